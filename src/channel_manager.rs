@@ -0,0 +1,211 @@
+use bitcoin::secp256k1::PublicKey;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::Watch;
+use lightning::ln::channelmanager::{
+	ChannelDetails, ChannelManager, InterceptId, PaymentId, Retry,
+};
+use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+use lightning::routing::router::Router;
+use lightning::sign::{EntropySource, NodeSigner, SignerProvider};
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
+use lightning::util::logger::Logger;
+use lightning_invoice::payment::{pay_invoice_with_id, PaymentError};
+use lightning_invoice::Bolt11Invoice;
+use std::ops::Deref;
+
+/// The subset of [`ChannelManager`] functionality the LSPS protocols rely on.
+///
+/// This is implemented for any [`ChannelManager`] and usually doesn't need to be implemented
+/// manually.
+pub trait ChannelManagerInterface {
+	/// Generates a payment hash and secret for an inbound payment, or returns `None` if the
+	/// parameters are invalid.
+	///
+	/// See [`ChannelManager::create_inbound_payment`].
+	fn create_inbound_payment(
+		&self, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32,
+		min_final_cltv_expiry_delta: Option<u16>,
+	) -> Option<(PaymentHash, PaymentSecret)>;
+
+	/// Gets the preimage of an inbound payment created via
+	/// [`ChannelManagerInterface::create_inbound_payment`].
+	///
+	/// See [`ChannelManager::get_payment_preimage`].
+	fn get_payment_preimage(
+		&self, payment_hash: PaymentHash, payment_secret: PaymentSecret,
+	) -> Result<PaymentPreimage, APIError>;
+
+	/// Claims a claimable payment.
+	///
+	/// See [`ChannelManager::claim_funds`].
+	fn claim_funds(&self, payment_preimage: PaymentPreimage);
+
+	/// Gets a fake short channel id for use in receiving intercepted payments.
+	///
+	/// See [`ChannelManager::get_intercept_scid`].
+	fn get_intercept_scid(&self) -> u64;
+
+	/// Creates a new outbound channel to the given remote node.
+	///
+	/// See [`ChannelManager::create_channel`].
+	fn create_channel(
+		&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64,
+		user_channel_id: u128, override_config: Option<UserConfig>,
+	) -> Result<[u8; 32], APIError>;
+
+	/// Attempts to forward an intercepted HTLC over the given channel.
+	///
+	/// See [`ChannelManager::forward_intercepted_htlc`].
+	fn forward_intercepted_htlc(
+		&self, intercept_id: InterceptId, next_hop_channel_id: &[u8; 32], next_node_id: PublicKey,
+		amt_to_forward_msat: u64,
+	) -> Result<(), APIError>;
+
+	/// Fails the intercepted HTLC backwards.
+	///
+	/// See [`ChannelManager::fail_intercepted_htlc`].
+	fn fail_intercepted_htlc(&self, intercept_id: InterceptId) -> Result<(), APIError>;
+
+	/// Gets the list of open channels.
+	///
+	/// See [`ChannelManager::list_channels`].
+	fn list_channels(&self) -> Vec<ChannelDetails>;
+
+	/// Pays the given invoice, identifying the payment by the given id.
+	///
+	/// See [`pay_invoice_with_id`].
+	fn pay_invoice(
+		&self, invoice: &Bolt11Invoice, payment_id: PaymentId, retry_strategy: Retry,
+	) -> Result<(), PaymentError>;
+
+	/// Fails back the HTLCs of a claimable payment instead of claiming it.
+	///
+	/// See [`ChannelManager::fail_htlc_backwards`].
+	fn fail_htlc_backwards(&self, payment_hash: &PaymentHash);
+
+	/// Accepts a request to open a channel.
+	///
+	/// See [`ChannelManager::accept_inbound_channel`].
+	fn accept_inbound_channel(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<(), APIError>;
+
+	/// Accepts a request to open a channel as zero-conf.
+	///
+	/// See [`ChannelManager::accept_inbound_channel_from_trusted_peer_0conf`].
+	fn accept_inbound_channel_from_trusted_peer_0conf(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<(), APIError>;
+}
+
+impl<M: Deref, T: Deref, ES: Deref, NS: Deref, SP: Deref, F: Deref, R: Deref, L: Deref>
+	ChannelManagerInterface for ChannelManager<M, T, ES, NS, SP, F, R, L>
+where
+	M::Target: Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	ES::Target: EntropySource,
+	NS::Target: NodeSigner,
+	SP::Target: SignerProvider,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	L::Target: Logger,
+{
+	fn create_inbound_payment(
+		&self, min_value_msat: Option<u64>, invoice_expiry_delta_secs: u32,
+		min_final_cltv_expiry_delta: Option<u16>,
+	) -> Option<(PaymentHash, PaymentSecret)> {
+		ChannelManager::create_inbound_payment(
+			self,
+			min_value_msat,
+			invoice_expiry_delta_secs,
+			min_final_cltv_expiry_delta,
+		)
+		.ok()
+	}
+
+	fn get_payment_preimage(
+		&self, payment_hash: PaymentHash, payment_secret: PaymentSecret,
+	) -> Result<PaymentPreimage, APIError> {
+		ChannelManager::get_payment_preimage(self, payment_hash, payment_secret)
+	}
+
+	fn claim_funds(&self, payment_preimage: PaymentPreimage) {
+		ChannelManager::claim_funds(self, payment_preimage)
+	}
+
+	fn get_intercept_scid(&self) -> u64 {
+		ChannelManager::get_intercept_scid(self)
+	}
+
+	fn create_channel(
+		&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64,
+		user_channel_id: u128, override_config: Option<UserConfig>,
+	) -> Result<[u8; 32], APIError> {
+		ChannelManager::create_channel(
+			self,
+			their_network_key,
+			channel_value_satoshis,
+			push_msat,
+			user_channel_id,
+			override_config,
+		)
+	}
+
+	fn forward_intercepted_htlc(
+		&self, intercept_id: InterceptId, next_hop_channel_id: &[u8; 32], next_node_id: PublicKey,
+		amt_to_forward_msat: u64,
+	) -> Result<(), APIError> {
+		ChannelManager::forward_intercepted_htlc(
+			self,
+			intercept_id,
+			next_hop_channel_id,
+			next_node_id,
+			amt_to_forward_msat,
+		)
+	}
+
+	fn fail_intercepted_htlc(&self, intercept_id: InterceptId) -> Result<(), APIError> {
+		ChannelManager::fail_intercepted_htlc(self, intercept_id)
+	}
+
+	fn list_channels(&self) -> Vec<ChannelDetails> {
+		ChannelManager::list_channels(self)
+	}
+
+	fn pay_invoice(
+		&self, invoice: &Bolt11Invoice, payment_id: PaymentId, retry_strategy: Retry,
+	) -> Result<(), PaymentError> {
+		pay_invoice_with_id(invoice, payment_id, retry_strategy, self)
+	}
+
+	fn fail_htlc_backwards(&self, payment_hash: &PaymentHash) {
+		ChannelManager::fail_htlc_backwards(self, payment_hash)
+	}
+
+	fn accept_inbound_channel(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<(), APIError> {
+		ChannelManager::accept_inbound_channel(
+			self,
+			temporary_channel_id,
+			counterparty_node_id,
+			user_channel_id,
+		)
+	}
+
+	fn accept_inbound_channel_from_trusted_peer_0conf(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<(), APIError> {
+		ChannelManager::accept_inbound_channel_from_trusted_peer_0conf(
+			self,
+			temporary_channel_id,
+			counterparty_node_id,
+			user_channel_id,
+		)
+	}
+}
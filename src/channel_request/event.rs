@@ -0,0 +1,215 @@
+use crate::channel_request::msgs::{OptionsSupported, OrderId, OrderState, PaymentState};
+use crate::channel_request::protocol::InboundChannelOrder;
+
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, OutPoint};
+use lightning::ln::PaymentHash;
+
+/// The reason we failed to order a channel from an LSP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrderCreationFailureReason {
+	/// The LSP doesn't support any of the LSPS1 versions we do.
+	NoCommonVersion,
+	/// The LSP rejected a parameter of the order as not matching its options.
+	OptionMismatch {
+		/// The name of the order parameter the LSP rejected.
+		property: String,
+	},
+	/// The LSP responded with an error.
+	ErrorResponse {
+		/// The error code the LSP responded with.
+		code: i32,
+		/// The error message the LSP responded with.
+		message: String,
+	},
+	/// The order the LSP created differs from the one we requested, or its total doesn't add up.
+	InvalidOrder,
+	/// The LSP didn't respond in time, or we didn't create an order from its options in time.
+	Timeout,
+}
+
+/// An event which an LSPS1 client or LSP should take some action in response to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Event {
+	/// The LSP we're ordering a channel from via
+	/// [`LiquidityManager::channel_order_request_options`] told us the options it supports.
+	///
+	/// The order has to be placed via [`LiquidityManager::channel_order_create`] with parameters
+	/// matching these options.
+	///
+	/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+	/// [`LiquidityManager::channel_order_create`]: crate::LiquidityManager::channel_order_create
+	SupportedOptionsReady {
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The website of the LSP.
+		website: String,
+		/// The options the LSP supports.
+		options: OptionsSupported,
+	},
+	/// The LSP created the order we placed via [`LiquidityManager::channel_order_create`].
+	///
+	/// The order has to be paid for before it expires, e.g., via
+	/// [`LiquidityManager::channel_order_pay`].
+	///
+	/// [`LiquidityManager::channel_order_create`]: crate::LiquidityManager::channel_order_create
+	/// [`LiquidityManager::channel_order_pay`]: crate::LiquidityManager::channel_order_pay
+	OrderCreated {
+		/// The order as created by the LSP.
+		order: Box<InboundChannelOrder>,
+	},
+	/// Ordering a channel via [`LiquidityManager::channel_order_request_options`] failed.
+	///
+	/// No order was created, but another attempt may be made.
+	///
+	/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+	OrderCreationFailed {
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP we tried to order the channel from.
+		counterparty_node_id: PublicKey,
+		/// Why ordering the channel failed.
+		reason: OrderCreationFailureReason,
+	},
+	/// The payment for an order made via [`LiquidityManager::channel_order_pay`] succeeded.
+	///
+	/// The LSP opens the channel once it received the payment, which can be followed via
+	/// [`LiquidityManager::channel_order_check_status`].
+	///
+	/// [`LiquidityManager::channel_order_pay`]: crate::LiquidityManager::channel_order_pay
+	/// [`LiquidityManager::channel_order_check_status`]: crate::LiquidityManager::channel_order_check_status
+	OrderPaymentSent {
+		/// The identifier the LSP assigned to the order.
+		order_id: OrderId,
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP the order was placed with.
+		counterparty_node_id: PublicKey,
+		/// The payment hash of the order's invoice.
+		payment_hash: PaymentHash,
+	},
+	/// The payment for an order made via [`LiquidityManager::channel_order_pay`] failed.
+	///
+	/// The payment may be retried as long as the order's invoice didn't expire.
+	///
+	/// [`LiquidityManager::channel_order_pay`]: crate::LiquidityManager::channel_order_pay
+	OrderPaymentFailed {
+		/// The identifier the LSP assigned to the order.
+		order_id: OrderId,
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP the order was placed with.
+		counterparty_node_id: PublicKey,
+		/// The payment hash of the order's invoice.
+		payment_hash: PaymentHash,
+	},
+	/// An order or its payment moved to a new state.
+	///
+	/// Emitted for the orders we placed as the LSP reports their progress, or once they expired
+	/// unpaid. When acting as an LSP, emitted for the orders clients placed with us as we receive
+	/// their payments, open their channels, or they expire unpaid.
+	OrderStateChanged {
+		/// The identifier assigned to the order.
+		order_id: OrderId,
+		/// The `user_channel_id` of the order's channel.
+		///
+		/// For orders we placed, this is the identifier passed into
+		/// [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP, or of the client for orders placed with us.
+		counterparty_node_id: PublicKey,
+		/// The new state of the order.
+		order_state: OrderState,
+		/// The new state of the order's payment.
+		payment_state: PaymentState,
+	},
+	/// The lease of a channel we sold to a client expired, so we may close it.
+	///
+	/// The lease started once the channel's funding transaction confirmed, and lasted the order's
+	/// `channel_expiry_blocks`.
+	ChannelLeaseExpired {
+		/// The identifier we assigned to the order.
+		order_id: OrderId,
+		/// The `user_channel_id` of the order's channel.
+		user_channel_id: u128,
+		/// The node id of the client that placed the order.
+		counterparty_node_id: PublicKey,
+		/// The block height at which the lease expired.
+		channel_expiry_height: u32,
+	},
+	/// The lease of a channel we bought from an LSP expires soon, after which the LSP may close it.
+	///
+	/// Emitted [`ChannelRequestsClientConfig::channel_expiry_warning_blocks`] ahead of the expiry,
+	/// leaving time to renew the lease, e.g., by ordering a new channel.
+	///
+	/// [`ChannelRequestsClientConfig::channel_expiry_warning_blocks`]: crate::ChannelRequestsClientConfig::channel_expiry_warning_blocks
+	ChannelLeaseExpiring {
+		/// The identifier the LSP assigned to the order.
+		order_id: OrderId,
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP the order was placed with.
+		counterparty_node_id: PublicKey,
+		/// The block height at which the lease expires.
+		channel_expiry_height: u32,
+	},
+	/// An LSP reported to have opened the channel for an order, but no matching channel with it
+	/// exists.
+	///
+	/// The channel is missing, is not with the LSP, or doesn't have the ordered capacity, inbound
+	/// balance or visibility, so the order may have to be disputed with the LSP.
+	ChannelDeliveryDisputed {
+		/// The identifier the LSP assigned to the order.
+		order_id: OrderId,
+		/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+		///
+		/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+		user_channel_id: u128,
+		/// The node id of the LSP the order was placed with.
+		counterparty_node_id: PublicKey,
+		/// The funding outpoint of the channel as reported by the LSP, if any.
+		funding_outpoint: Option<OutPoint>,
+	},
+	/// An order a client paid for on-chain failed, so the payment has to be refunded.
+	///
+	/// Once the refund was sent, its transaction has to be passed to
+	/// [`LiquidityManager::channel_order_refunded`].
+	///
+	/// [`LiquidityManager::channel_order_refunded`]: crate::LiquidityManager::channel_order_refunded
+	RefundRequired {
+		/// The identifier we assigned to the order.
+		order_id: OrderId,
+		/// The `user_channel_id` of the order's channel.
+		user_channel_id: u128,
+		/// The node id of the client that placed the order.
+		counterparty_node_id: PublicKey,
+		/// The amount to refund, i.e., the on-chain payment we received for the order.
+		amount_sat: u64,
+		/// The address the client asked to be refunded to.
+		///
+		/// If `None`, the refund has to be arranged with the client some other way.
+		refund_onchain_address: Option<Address>,
+	},
+	/// The LSP told us the current status of an order, as requested via
+	/// [`LiquidityManager::channel_order_check_status`].
+	///
+	/// [`LiquidityManager::channel_order_check_status`]: crate::LiquidityManager::channel_order_check_status
+	OrderStatusReady {
+		/// The order as last reported by the LSP.
+		order: Box<InboundChannelOrder>,
+	},
+}
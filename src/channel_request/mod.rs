@@ -8,3 +8,10 @@
 // licenses.
 
 //! Types and primitives that implement the LSPS1: Channel Request specification.
+
+pub(crate) mod event;
+pub mod msgs;
+pub(crate) mod pricing;
+pub(crate) mod protocol;
+pub(crate) mod signer;
+pub(crate) mod wallet;
@@ -0,0 +1,779 @@
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1};
+use bitcoin::OutPoint;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+
+use crate::transport::msgs::{string_amount, string_amount_option};
+use crate::transport::msgs::{LSPSDateTime, LSPSMessage, RequestId, ResponseError};
+use crate::utils;
+
+pub(crate) const LSPS1_GET_INFO_METHOD_NAME: &str = "lsps1.get_info";
+pub(crate) const LSPS1_CREATE_ORDER_METHOD_NAME: &str = "lsps1.create_order";
+pub(crate) const LSPS1_GET_ORDER_METHOD_NAME: &str = "lsps1.get_order";
+
+pub(crate) const LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
+pub(crate) const LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE: i32 = 100;
+pub(crate) const LSPS1_CREATE_ORDER_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE: i32 = 102;
+
+pub(crate) const LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE: i32 = 101;
+
+/// The tag of the hashes signed for order receipts, which keeps their signatures from being taken
+/// for those of any other message signed with the LSP's node key.
+const ORDER_RECEIPT_TAG: &str = "lsps1receipt";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct GetInfoRequest {}
+
+/// The options an LSP supports for the channels it sells.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OptionsSupported {
+	/// The number of confirmations the LSP waits for before the channel is usable, zero if it
+	/// sells zero-conf channels.
+	pub minimum_channel_confirmations: u8,
+	/// The number of confirmations an on-chain payment needs, or `None` if the LSP doesn't accept
+	/// on-chain payments.
+	pub minimum_onchain_payment_confirmations: Option<u8>,
+	/// Whether the LSP supports channels without a reserve on the client's side.
+	pub supports_zero_channel_reserve: bool,
+	/// The minimum amount of an on-chain payment, or `None` if the LSP doesn't accept on-chain
+	/// payments.
+	#[serde(with = "string_amount_option")]
+	pub min_onchain_payment_size_sat: Option<u64>,
+	/// The maximum number of blocks the LSP keeps a channel open for.
+	pub max_channel_expiry_blocks: u32,
+	/// The minimum balance the client can buy on its side of the channel.
+	#[serde(with = "string_amount")]
+	pub min_initial_client_balance_sat: u64,
+	/// The maximum balance the client can buy on its side of the channel.
+	#[serde(with = "string_amount")]
+	pub max_initial_client_balance_sat: u64,
+	/// The minimum balance the LSP puts on its side of the channel.
+	#[serde(with = "string_amount")]
+	pub min_initial_lsp_balance_sat: u64,
+	/// The maximum balance the LSP puts on its side of the channel.
+	#[serde(with = "string_amount")]
+	pub max_initial_lsp_balance_sat: u64,
+	/// The minimum size of the channel, i.e., the sum of both balances.
+	#[serde(with = "string_amount")]
+	pub min_channel_balance_sat: u64,
+	/// The maximum size of the channel, i.e., the sum of both balances.
+	#[serde(with = "string_amount")]
+	pub max_channel_balance_sat: u64,
+}
+
+impl OptionsSupported {
+	/// Returns the name of the first order parameter that doesn't match these options, if any.
+	pub(crate) fn mismatched_property(&self, order: &OrderParams) -> Option<&'static str> {
+		let channel_balance_sat = order.lsp_balance_sat.saturating_add(order.client_balance_sat);
+		if order.lsp_balance_sat < self.min_initial_lsp_balance_sat
+			|| order.lsp_balance_sat > self.max_initial_lsp_balance_sat
+		{
+			Some("lsp_balance_sat")
+		} else if order.client_balance_sat < self.min_initial_client_balance_sat
+			|| order.client_balance_sat > self.max_initial_client_balance_sat
+		{
+			Some("client_balance_sat")
+		} else if channel_balance_sat < self.min_channel_balance_sat
+			|| channel_balance_sat > self.max_channel_balance_sat
+		{
+			// The channel size is no order parameter, the LSP balance is what the client adjusts.
+			Some("lsp_balance_sat")
+		} else if order.channel_expiry_blocks > self.max_channel_expiry_blocks {
+			Some("channel_expiry_blocks")
+		} else {
+			None
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GetInfoResponse {
+	pub supported_versions: Vec<u16>,
+	pub website: String,
+	pub options: OptionsSupported,
+}
+
+/// The parameters of a channel order, as chosen by the client.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OrderParams {
+	/// The balance the LSP puts on its side of the channel.
+	#[serde(with = "string_amount")]
+	pub lsp_balance_sat: u64,
+	/// The balance the client buys on its side of the channel, pushed to it by the LSP.
+	#[serde(with = "string_amount")]
+	pub client_balance_sat: u64,
+	/// The number of blocks within which the client wants the funding transaction to confirm.
+	pub confirms_within_blocks: u32,
+	/// The number of blocks after which the LSP may close the channel.
+	pub channel_expiry_blocks: u32,
+	/// Whether the channel should be announced to the network.
+	pub announce_channel: bool,
+}
+
+impl_writeable_tlv_based!(OrderParams, {
+	(0, lsp_balance_sat, required),
+	(2, client_balance_sat, required),
+	(4, confirms_within_blocks, required),
+	(6, channel_expiry_blocks, required),
+	(8, announce_channel, required),
+});
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CreateOrderRequest {
+	pub api_version: u16,
+	#[serde(flatten)]
+	pub order: OrderParams,
+	/// A token the LSP may e.g. grant a discount for, such as a coupon code.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub token: Option<String>,
+	/// The address the LSP refunds an on-chain payment to if the order fails after it was paid.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub refund_onchain_address: Option<String>,
+}
+
+/// The identifier the LSP assigned to a channel order.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct OrderId(pub String);
+
+impl Writeable for OrderId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for OrderId {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self(Readable::read(reader)?))
+	}
+}
+
+/// The state of a channel order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderState {
+	/// The order was created and awaits payment or the channel open.
+	Created,
+	/// The channel was opened.
+	Completed,
+	/// The order failed, e.g., because it expired unpaid or the channel open failed.
+	Failed,
+}
+
+impl Writeable for OrderState {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let state: u8 = match self {
+			OrderState::Created => 0,
+			OrderState::Completed => 1,
+			OrderState::Failed => 2,
+		};
+		state.write(writer)
+	}
+}
+
+impl Readable for OrderState {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let state: u8 = Readable::read(reader)?;
+		match state {
+			0 => Ok(OrderState::Created),
+			1 => Ok(OrderState::Completed),
+			2 => Ok(OrderState::Failed),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+/// The state of the payment for a channel order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PaymentState {
+	/// The order awaits payment.
+	ExpectPayment,
+	/// The payment arrived and is held until the channel is opened.
+	Hold,
+	/// The payment was claimed.
+	Paid,
+	/// The payment was returned to the client.
+	Refunded,
+}
+
+impl Writeable for PaymentState {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let state: u8 = match self {
+			PaymentState::ExpectPayment => 0,
+			PaymentState::Hold => 1,
+			PaymentState::Paid => 2,
+			PaymentState::Refunded => 3,
+		};
+		state.write(writer)
+	}
+}
+
+impl Readable for PaymentState {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let state: u8 = Readable::read(reader)?;
+		match state {
+			0 => Ok(PaymentState::ExpectPayment),
+			1 => Ok(PaymentState::Hold),
+			2 => Ok(PaymentState::Paid),
+			3 => Ok(PaymentState::Refunded),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+/// How a channel order is paid for.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PaymentInfo {
+	/// The state of the payment.
+	pub state: PaymentState,
+	/// The fee the LSP charges for the channel.
+	#[serde(with = "string_amount")]
+	pub fee_total_sat: u64,
+	/// The total amount to pay, i.e., the fee plus the client balance.
+	#[serde(with = "string_amount")]
+	pub order_total_sat: u64,
+	/// The bolt11 invoice paying the order total.
+	pub bolt11_invoice: String,
+	/// The address to pay the order total to on-chain, if the LSP accepts on-chain payments for
+	/// the order.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub onchain_address: Option<String>,
+	/// The number of confirmations an on-chain payment needs before the LSP opens the channel.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub min_onchain_payment_confirmations: Option<u8>,
+	/// The feerate in sat/vbyte at which the LSP accepts an on-chain payment before it confirmed.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub min_fee_for_0conf: Option<u8>,
+	/// The on-chain payment the LSP received for the order, if any.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub onchain_payment: Option<OnchainPayment>,
+}
+
+impl_writeable_tlv_based!(PaymentInfo, {
+	(0, state, required),
+	(2, fee_total_sat, required),
+	(4, order_total_sat, required),
+	(6, bolt11_invoice, required),
+	(8, onchain_address, option),
+	(10, min_onchain_payment_confirmations, option),
+	(12, min_fee_for_0conf, option),
+	(14, onchain_payment, option),
+});
+
+/// An on-chain payment for a channel order.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OnchainPayment {
+	/// The output paying the order.
+	#[serde(with = "outpoint_string")]
+	pub outpoint: OutPoint,
+	/// The amount paid.
+	#[serde(with = "string_amount")]
+	pub sat: u64,
+	/// Whether the payment has the confirmations the LSP requires.
+	pub confirmed: bool,
+}
+
+impl Writeable for OnchainPayment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.outpoint.txid.write(writer)?;
+		self.outpoint.vout.write(writer)?;
+		self.sat.write(writer)?;
+		self.confirmed.write(writer)
+	}
+}
+
+impl Readable for OnchainPayment {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let txid = Readable::read(reader)?;
+		let vout = Readable::read(reader)?;
+		let sat = Readable::read(reader)?;
+		let confirmed = Readable::read(reader)?;
+		Ok(Self { outpoint: OutPoint { txid, vout }, sat, confirmed })
+	}
+}
+
+/// The channel opened for a channel order.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChannelInfo {
+	/// The time the funding transaction was published.
+	pub funded_at: LSPSDateTime,
+	/// The outpoint of the funding transaction.
+	#[serde(with = "outpoint_string")]
+	pub funding_outpoint: OutPoint,
+	/// The earliest time the LSP may close the channel.
+	pub expires_at: LSPSDateTime,
+}
+
+impl Writeable for ChannelInfo {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.funded_at.write(writer)?;
+		self.funding_outpoint.txid.write(writer)?;
+		self.funding_outpoint.vout.write(writer)?;
+		self.expires_at.write(writer)
+	}
+}
+
+impl Readable for ChannelInfo {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let funded_at = Readable::read(reader)?;
+		let txid = Readable::read(reader)?;
+		let vout = Readable::read(reader)?;
+		let expires_at = Readable::read(reader)?;
+		Ok(Self { funded_at, funding_outpoint: OutPoint { txid, vout }, expires_at })
+	}
+}
+
+/// A receipt for a channel order, signed by the LSP with its node key.
+///
+/// It covers the terms the LSP accepted the order on, and serves as evidence of what the LSP
+/// promised the client that placed the order, e.g., if the channel is closed before its lease
+/// expired.
+///
+/// This is a non-standard extension of LSPS1, only exchanged between LSPs and clients built on
+/// this crate.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OrderReceipt {
+	/// The identifier the LSP assigned to the order.
+	pub order_id: OrderId,
+	/// The parameters of the ordered channel, including the terms of its lease.
+	#[serde(flatten)]
+	pub order: OrderParams,
+	/// The fees the LSP charges for the order.
+	#[serde(with = "string_amount")]
+	pub fee_total_sat: u64,
+	/// The total the client pays for the order.
+	#[serde(with = "string_amount")]
+	pub order_total_sat: u64,
+	/// The time the order expires if it isn't paid for.
+	pub expires_at: LSPSDateTime,
+	/// The LSP's signature, hex-encoded as a compact signature followed by its recovery id.
+	pub signature: String,
+}
+
+impl_writeable_tlv_based!(OrderReceipt, {
+	(0, order_id, required),
+	(2, order, required),
+	(4, fee_total_sat, required),
+	(6, order_total_sat, required),
+	(8, expires_at, required),
+	(10, signature, required),
+});
+
+impl OrderReceipt {
+	/// The message the LSP signs, which binds the receipt to the client that placed the order.
+	///
+	/// It is the tagged hash of the receipt's terms and the client's node id, as in BIP 340.
+	pub(crate) fn signed_message(&self, client_node_id: &PublicKey) -> Message {
+		let tag = sha256::Hash::hash(ORDER_RECEIPT_TAG.as_bytes());
+		let mut engine = sha256::Hash::engine();
+		engine.input(&tag[..]);
+		engine.input(&tag[..]);
+		engine.input(&self.order_id.encode());
+		engine.input(&client_node_id.encode());
+		engine.input(&self.order.encode());
+		engine.input(&self.fee_total_sat.encode());
+		engine.input(&self.order_total_sat.encode());
+		engine.input(&self.expires_at.encode());
+		Message::from_slice(&sha256::Hash::from_engine(engine)[..])
+			.expect("SHA-256 hashes are valid messages")
+	}
+
+	/// Whether the receipt was signed by the LSP with the given node id, for an order placed by
+	/// the client with the given node id.
+	pub fn verify(&self, lsp_node_id: &PublicKey, client_node_id: &PublicKey) -> bool {
+		let signature = match utils::to_vec(&self.signature) {
+			Some(signature) if signature.len() == 65 => signature,
+			_ => return false,
+		};
+		let signature = match RecoveryId::from_i32(signature[64].into()).and_then(|recovery_id| {
+			RecoverableSignature::from_compact(&signature[..64], recovery_id)
+		}) {
+			Ok(signature) => signature,
+			Err(_) => return false,
+		};
+		let message = self.signed_message(client_node_id);
+		Secp256k1::verification_only().recover_ecdsa(&message, &signature) == Ok(*lsp_node_id)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OrderResponse {
+	pub order_id: OrderId,
+	#[serde(flatten)]
+	pub order: OrderParams,
+	pub created_at: LSPSDateTime,
+	pub expires_at: LSPSDateTime,
+	pub order_state: OrderState,
+	pub payment: PaymentInfo,
+	pub channel: Option<ChannelInfo>,
+	/// The receipt the LSP signed for the order, if any.
+	///
+	/// Receipts are an extension of ours rather than part of LSPS1, so other LSPs don't send them
+	/// and other clients ignore them.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub receipt: Option<Box<OrderReceipt>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GetOrderRequest {
+	pub order_id: OrderId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Request {
+	GetInfo(GetInfoRequest),
+	CreateOrder(CreateOrderRequest),
+	GetOrder(GetOrderRequest),
+}
+
+impl LSPS1Request {
+	pub fn method(&self) -> &str {
+		match self {
+			LSPS1Request::GetInfo(_) => LSPS1_GET_INFO_METHOD_NAME,
+			LSPS1Request::CreateOrder(_) => LSPS1_CREATE_ORDER_METHOD_NAME,
+			LSPS1Request::GetOrder(_) => LSPS1_GET_ORDER_METHOD_NAME,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Response {
+	GetInfo(GetInfoResponse),
+	GetInfoError(ResponseError),
+	CreateOrder(OrderResponse),
+	CreateOrderError(ResponseError),
+	GetOrder(OrderResponse),
+	GetOrderError(ResponseError),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Message {
+	Request(RequestId, LSPS1Request),
+	Response(RequestId, LSPS1Response),
+}
+
+impl TryFrom<LSPSMessage> for LSPS1Message {
+	type Error = ();
+
+	fn try_from(message: LSPSMessage) -> Result<Self, Self::Error> {
+		match message {
+			LSPSMessage::LSPS1(message) => Ok(message),
+			_ => Err(()),
+		}
+	}
+}
+
+impl From<LSPS1Message> for LSPSMessage {
+	fn from(message: LSPS1Message) -> Self {
+		LSPSMessage::LSPS1(message)
+	}
+}
+
+/// Serializes outpoints as `txid:vout` strings.
+mod outpoint_string {
+	use bitcoin::OutPoint;
+	use serde::de::Unexpected;
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::str::FromStr;
+
+	pub(crate) fn serialize<S>(x: &OutPoint, s: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		s.serialize_str(&x.to_string())
+	}
+
+	pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<OutPoint, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let buf = String::deserialize(deserializer)?;
+
+		OutPoint::from_str(&buf).map_err(|_| {
+			serde::de::Error::invalid_value(Unexpected::Str(&buf), &"a txid:vout outpoint string")
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use std::str::FromStr;
+
+	fn order() -> OrderParams {
+		OrderParams {
+			lsp_balance_sat: 1_000_000,
+			client_balance_sat: 50_000,
+			confirms_within_blocks: 6,
+			channel_expiry_blocks: 4_320,
+			announce_channel: false,
+		}
+	}
+
+	const BOLT11_INVOICE: &str = "lnbc580u1pjq9k3spp5hlgazn4vtzwffxngqhs9sdgquasmayffsq0da9ylkwpztyd9lxqsdq8f3f5z4qcqzzsxqrrs0fppqw508d6qejxtdg4y5r3zarvary0c5xw7ksp5txqd8dpuq5u4qlqy4hzlu5dr7p0l4jvqkfe00xlkrg8hn2j2njds9qyyssqy4lgd8tj637qcjp05rdpxxykjenthxftej7a2zzmwrmrl70fyj9hvj0rewhzj7jfyuwkwcg9g2jpwtk3wkjtwnkdks84hsnu8xps5vsq4gj5hs";
+
+	const ONCHAIN_ADDRESS: &str = "bc1p5uvtaxzkjwvey2tfy49k5vtqfpjmrgm09cvs88ezyy8h2zv7jhas9tu4yr";
+
+	fn order_response() -> OrderResponse {
+		OrderResponse {
+			order_id: OrderId("bb4b5d0a-8334-49d8-9463-90a6d413af7c".to_string()),
+			order: order(),
+			created_at: LSPSDateTime::from_rfc3339("2023-02-23T08:47:30.511Z").unwrap(),
+			expires_at: LSPSDateTime::from_rfc3339("2023-02-23T09:47:30.511Z").unwrap(),
+			order_state: OrderState::Completed,
+			payment: PaymentInfo {
+				state: PaymentState::Paid,
+				fee_total_sat: 8_000,
+				order_total_sat: 58_000,
+				bolt11_invoice: BOLT11_INVOICE.to_string(),
+				onchain_address: Some(ONCHAIN_ADDRESS.to_string()),
+				min_onchain_payment_confirmations: Some(1),
+				min_fee_for_0conf: Some(253),
+				onchain_payment: Some(OnchainPayment {
+					outpoint: OutPoint::from_str(
+						"0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:1",
+					)
+					.unwrap(),
+					sat: 58_000,
+					confirmed: true,
+				}),
+			},
+			channel: Some(ChannelInfo {
+				funded_at: LSPSDateTime::from_rfc3339("2023-02-23T08:50:30.511Z").unwrap(),
+				funding_outpoint: OutPoint::from_str(
+					"0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:0",
+				)
+				.unwrap(),
+				expires_at: LSPSDateTime::from_rfc3339("2023-05-23T08:50:30.511Z").unwrap(),
+			}),
+			receipt: None,
+		}
+	}
+
+	fn round_trip(message: LSPSMessage, request_id_to_method: &mut HashMap<String, String>) {
+		if let Some((request_id, method)) = message.get_request_id_and_method() {
+			request_id_to_method.insert(request_id, method);
+		}
+		let json = serde_json::to_string(&message).unwrap();
+		let parsed = LSPSMessage::from_str_with_id_map(&json, request_id_to_method).unwrap();
+		assert_eq!(parsed, message);
+	}
+
+	#[test]
+	fn serializes_create_order_request() {
+		let request = LSPSMessage::LSPS1(LSPS1Message::Request(
+			RequestId("request:id:xyz123".to_string()),
+			LSPS1Request::CreateOrder(CreateOrderRequest {
+				api_version: 1,
+				order: order(),
+				token: None,
+				refund_onchain_address: None,
+			}),
+		));
+		let json = serde_json::to_string(&request).unwrap();
+		assert_eq!(
+			json,
+			r#"{"jsonrpc":"2.0","method":"lsps1.create_order","id":"request:id:xyz123","params":{"api_version":1,"lsp_balance_sat":"1000000","client_balance_sat":"50000","confirms_within_blocks":6,"channel_expiry_blocks":4320,"announce_channel":false}}"#
+		);
+	}
+
+	#[test]
+	fn deserializes_order_response() {
+		let json = r#"{
+			"jsonrpc": "2.0",
+			"id": "request:id:xyz123",
+			"result": {
+				"order_id": "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+				"lsp_balance_sat": "1000000",
+				"client_balance_sat": "50000",
+				"confirms_within_blocks": 6,
+				"channel_expiry_blocks": 4320,
+				"announce_channel": false,
+				"created_at": "2023-02-23T08:47:30.511Z",
+				"expires_at": "2023-02-23T09:47:30.511Z",
+				"order_state": "COMPLETED",
+				"payment": {
+					"state": "PAID",
+					"fee_total_sat": "8000",
+					"order_total_sat": "58000",
+					"bolt11_invoice": "lnbc580u1pjq9k3spp5hlgazn4vtzwffxngqhs9sdgquasmayffsq0da9ylkwpztyd9lxqsdq8f3f5z4qcqzzsxqrrs0fppqw508d6qejxtdg4y5r3zarvary0c5xw7ksp5txqd8dpuq5u4qlqy4hzlu5dr7p0l4jvqkfe00xlkrg8hn2j2njds9qyyssqy4lgd8tj637qcjp05rdpxxykjenthxftej7a2zzmwrmrl70fyj9hvj0rewhzj7jfyuwkwcg9g2jpwtk3wkjtwnkdks84hsnu8xps5vsq4gj5hs",
+					"onchain_address": "bc1p5uvtaxzkjwvey2tfy49k5vtqfpjmrgm09cvs88ezyy8h2zv7jhas9tu4yr",
+					"min_onchain_payment_confirmations": 1,
+					"min_fee_for_0conf": 253,
+					"onchain_payment": {
+						"outpoint": "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:1",
+						"sat": "58000",
+						"confirmed": true
+					}
+				},
+				"channel": {
+					"funded_at": "2023-02-23T08:50:30.511Z",
+					"funding_outpoint": "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:0",
+					"expires_at": "2023-05-23T08:50:30.511Z"
+				}
+			}
+		}"#;
+		let mut request_id_to_method = HashMap::new();
+		request_id_to_method
+			.insert("request:id:xyz123".to_string(), LSPS1_GET_ORDER_METHOD_NAME.to_string());
+
+		let msg = LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method).unwrap();
+		assert_eq!(
+			msg,
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				RequestId("request:id:xyz123".to_string()),
+				LSPS1Response::GetOrder(order_response())
+			))
+		);
+	}
+
+	#[test]
+	fn requests_round_trip() {
+		let mut request_id_to_method = HashMap::new();
+
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Request(
+				RequestId("get_info".to_string()),
+				LSPS1Request::GetInfo(GetInfoRequest {}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Request(
+				RequestId("create_order".to_string()),
+				LSPS1Request::CreateOrder(CreateOrderRequest {
+					api_version: 1,
+					order: order(),
+					token: Some("coupon".to_string()),
+					refund_onchain_address: Some(ONCHAIN_ADDRESS.to_string()),
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Request(
+				RequestId("get_order".to_string()),
+				LSPS1Request::GetOrder(GetOrderRequest {
+					order_id: OrderId("bb4b5d0a-8334-49d8-9463-90a6d413af7c".to_string()),
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+	}
+
+	#[test]
+	fn responses_round_trip() {
+		let mut request_id_to_method = HashMap::new();
+		request_id_to_method.insert("get_info".to_string(), LSPS1_GET_INFO_METHOD_NAME.to_string());
+		request_id_to_method
+			.insert("create_order".to_string(), LSPS1_CREATE_ORDER_METHOD_NAME.to_string());
+		request_id_to_method
+			.insert("get_order".to_string(), LSPS1_GET_ORDER_METHOD_NAME.to_string());
+
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				RequestId("get_info".to_string()),
+				LSPS1Response::GetInfo(GetInfoResponse {
+					supported_versions: vec![1],
+					website: "https://example.com".to_string(),
+					options: OptionsSupported {
+						minimum_channel_confirmations: 0,
+						minimum_onchain_payment_confirmations: None,
+						supports_zero_channel_reserve: false,
+						min_onchain_payment_size_sat: None,
+						max_channel_expiry_blocks: 20_000,
+						min_initial_client_balance_sat: 0,
+						max_initial_client_balance_sat: 100_000,
+						min_initial_lsp_balance_sat: 10_000,
+						max_initial_lsp_balance_sat: 10_000_000,
+						min_channel_balance_sat: 50_000,
+						max_channel_balance_sat: 10_000_000,
+					},
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		let mut response = order_response();
+		response.receipt = Some(Box::new(OrderReceipt {
+			order_id: response.order_id.clone(),
+			order: order(),
+			fee_total_sat: 8_000,
+			order_total_sat: 58_000,
+			expires_at: response.expires_at,
+			signature: "00".repeat(65),
+		}));
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				RequestId("create_order".to_string()),
+				LSPS1Response::CreateOrder(response),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				RequestId("create_order".to_string()),
+				LSPS1Response::CreateOrderError(ResponseError {
+					code: LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+					message: "option_mismatch".to_string(),
+					data: Some(serde_json::json!({ "property": "lsp_balance_sat" })),
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				RequestId("get_order".to_string()),
+				LSPS1Response::GetOrderError(ResponseError {
+					code: LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE,
+					message: "not_found".to_string(),
+					data: None,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+	}
+
+	#[test]
+	fn names_mismatched_property() {
+		let options = OptionsSupported {
+			minimum_channel_confirmations: 0,
+			minimum_onchain_payment_confirmations: None,
+			supports_zero_channel_reserve: false,
+			min_onchain_payment_size_sat: None,
+			max_channel_expiry_blocks: 4_320,
+			min_initial_client_balance_sat: 0,
+			max_initial_client_balance_sat: 100_000,
+			min_initial_lsp_balance_sat: 10_000,
+			max_initial_lsp_balance_sat: 1_000_000,
+			min_channel_balance_sat: 50_000,
+			max_channel_balance_sat: 1_050_000,
+		};
+		assert_eq!(options.mismatched_property(&order()), None);
+
+		let mut mismatched = order();
+		mismatched.lsp_balance_sat = 1_000_001;
+		assert_eq!(options.mismatched_property(&mismatched), Some("lsp_balance_sat"));
+
+		let mut mismatched = order();
+		mismatched.client_balance_sat = 100_001;
+		assert_eq!(options.mismatched_property(&mismatched), Some("client_balance_sat"));
+
+		let mut mismatched = order();
+		mismatched.lsp_balance_sat = 20_000;
+		mismatched.client_balance_sat = 20_000;
+		assert_eq!(options.mismatched_property(&mismatched), Some("lsp_balance_sat"));
+
+		let mut mismatched = order();
+		mismatched.channel_expiry_blocks = 4_321;
+		assert_eq!(options.mismatched_property(&mismatched), Some("channel_expiry_blocks"));
+	}
+}
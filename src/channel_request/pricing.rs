@@ -0,0 +1,149 @@
+use crate::channel_request::msgs::OrderParams;
+
+use bitcoin::secp256k1::PublicKey;
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use std::convert::TryFrom;
+use std::ops::Deref;
+
+/// The weight of a funding transaction spending a single P2WPKH input to the P2WSH funding
+/// output and a P2WPKH change output.
+const ESTIMATED_FUNDING_TRANSACTION_WEIGHT: u64 = 610;
+
+/// Orders asking for confirmation within this many blocks are funded at a high priority feerate.
+const HIGH_PRIORITY_CONFIRMATION_BLOCKS: u32 = 6;
+
+/// Prices the channels we sell as an LSP.
+pub trait ChannelOrderPricing {
+	/// Returns the fee the client pays for a channel with the given order parameters.
+	fn fee_total_sat(&self, order: &OrderParams) -> u64;
+
+	/// Returns the fee the client pays for an order it placed with a token, e.g., a coupon code.
+	///
+	/// Called with the client's node id and the fee it pays without a token, which the token may
+	/// adjust, e.g., to grant a discount. Returning `None` rejects the order because the token is
+	/// unrecognized or stale, which is what happens to all orders with a token by default.
+	fn fee_total_sat_with_token(
+		&self, _counterparty_node_id: &PublicKey, _order: &OrderParams, _token: &str,
+		_fee_total_sat: u64,
+	) -> Option<u64> {
+		None
+	}
+}
+
+/// Configuration options for [`DefaultChannelOrderPricing`].
+#[derive(Clone, Debug)]
+pub struct ChannelOrderPricingConfig {
+	/// A fixed fee charged for every channel.
+	pub base_fee_sat: u64,
+	/// A fee proportional to the balance we put on our side of the channel, in parts per million.
+	pub lsp_balance_fee_ppm: u32,
+	/// The fee charged for every block we promise to keep the channel open.
+	pub lease_fee_msat_per_block: u64,
+}
+
+/// A [`ChannelOrderPricing`] charging the fees of a [`ChannelOrderPricingConfig`], plus the
+/// on-chain fee of the funding transaction at the current feerate.
+///
+/// Orders asking for the funding transaction to confirm within a few blocks pay the
+/// [`ConfirmationTarget::HighPriority`] feerate, all others the [`ConfirmationTarget::Normal`]
+/// one.
+pub struct DefaultChannelOrderPricing<F: Deref>
+where
+	F::Target: FeeEstimator,
+{
+	config: ChannelOrderPricingConfig,
+	fee_estimator: F,
+}
+
+impl<F: Deref> DefaultChannelOrderPricing<F>
+where
+	F::Target: FeeEstimator,
+{
+	/// Constructs a pricing charging the fees of the given config.
+	pub fn new(config: ChannelOrderPricingConfig, fee_estimator: F) -> Self {
+		Self { config, fee_estimator }
+	}
+}
+
+impl<F: Deref> ChannelOrderPricing for DefaultChannelOrderPricing<F>
+where
+	F::Target: FeeEstimator,
+{
+	fn fee_total_sat(&self, order: &OrderParams) -> u64 {
+		let lsp_balance_fee_sat = u128::from(order.lsp_balance_sat)
+			* u128::from(self.config.lsp_balance_fee_ppm)
+			/ 1_000_000;
+		let lease_fee_msat = u128::from(order.channel_expiry_blocks)
+			* u128::from(self.config.lease_fee_msat_per_block);
+		let lease_fee_sat = (lease_fee_msat + 999) / 1000;
+
+		let confirmation_target =
+			if order.confirms_within_blocks <= HIGH_PRIORITY_CONFIRMATION_BLOCKS {
+				ConfirmationTarget::HighPriority
+			} else {
+				ConfirmationTarget::Normal
+			};
+		let feerate_sat_per_1000_weight =
+			self.fee_estimator.get_est_sat_per_1000_weight(confirmation_target);
+		let onchain_fee_sat = (u128::from(feerate_sat_per_1000_weight)
+			* u128::from(ESTIMATED_FUNDING_TRANSACTION_WEIGHT)
+			+ 999) / 1000;
+
+		let fee_total_sat = u128::from(self.config.base_fee_sat)
+			+ lsp_balance_fee_sat
+			+ lease_fee_sat
+			+ onchain_fee_sat;
+		u64::try_from(fee_total_sat).unwrap_or(u64::MAX)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct TestFeeEstimator;
+
+	impl FeeEstimator for TestFeeEstimator {
+		fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32 {
+			match confirmation_target {
+				ConfirmationTarget::HighPriority => 5_000,
+				_ => 1_000,
+			}
+		}
+	}
+
+	fn order(confirms_within_blocks: u32) -> OrderParams {
+		OrderParams {
+			lsp_balance_sat: 1_000_000,
+			client_balance_sat: 50_000,
+			confirms_within_blocks,
+			channel_expiry_blocks: 4_320,
+			announce_channel: false,
+		}
+	}
+
+	#[test]
+	fn prices_orders_from_config_and_feerate() {
+		let config = ChannelOrderPricingConfig {
+			base_fee_sat: 1_000,
+			lsp_balance_fee_ppm: 2_000,
+			lease_fee_msat_per_block: 500,
+		};
+		let pricing = DefaultChannelOrderPricing::new(config, &TestFeeEstimator);
+
+		// 1000 base + 2000 for the balance + 2160 for the lease + 610 on-chain.
+		assert_eq!(pricing.fee_total_sat(&order(12)), 5_770);
+
+		// Fast confirmation pays the high priority feerate.
+		assert_eq!(pricing.fee_total_sat(&order(6)), 5_160 + 3_050);
+
+		let free = ChannelOrderPricingConfig {
+			base_fee_sat: 0,
+			lsp_balance_fee_ppm: 0,
+			lease_fee_msat_per_block: 1,
+		};
+		let pricing = DefaultChannelOrderPricing::new(free, &TestFeeEstimator);
+		// Sub-satoshi fees are rounded up.
+		assert_eq!(pricing.fee_total_sat(&order(12)), 5 + 610);
+	}
+}
@@ -0,0 +1,4263 @@
+use bitcoin::bech32::ToBase32;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid};
+use lightning::chain::TransactionData;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channelmanager::{ChannelDetails, PaymentId, Retry};
+use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError};
+use lightning::ln::{PaymentHash, PaymentSecret};
+use lightning::sign::{EntropySource, NodeSigner, Recipient};
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
+use lightning::util::logger::Level;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::ops::{Deref, Range};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::channel_manager::ChannelManagerInterface;
+use crate::channel_request::event::{LSPS1Event, OrderCreationFailureReason};
+use crate::channel_request::msgs::{
+	ChannelInfo, CreateOrderRequest, GetInfoRequest, GetInfoResponse, GetOrderRequest,
+	LSPS1Message, LSPS1Request, LSPS1Response, OnchainPayment, OptionsSupported, OrderId,
+	OrderParams, OrderReceipt, OrderResponse, OrderState, PaymentInfo, PaymentState,
+	LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE,
+	LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+	LSPS1_CREATE_ORDER_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+	LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE,
+};
+use crate::channel_request::pricing::ChannelOrderPricing;
+use crate::channel_request::signer::OrderReceiptSigner;
+use crate::channel_request::wallet::ChannelOrderWallet;
+use crate::events::{Event, EventQueue};
+use crate::transport::message_handler::ProtocolMessageHandler;
+use crate::transport::msgs::{
+	LSPSDateTime, LSPSMessage, RequestId, ResponseError, JSONRPC_INTERNAL_ERROR_ERROR_CODE,
+};
+use crate::utils;
+
+const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
+
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// The number of timer ticks after which we give up waiting for an LSP to respond to a request
+/// made while ordering a channel.
+const REQUEST_TIMEOUT_TICKS: u8 = 5;
+
+/// The final CLTV expiry delta of the invoices we issue for orders.
+///
+/// Payments are held until the ordered channel is open, so they must not time out while its
+/// funding transaction confirms.
+const ORDER_INVOICE_MIN_FINAL_CLTV_EXPIRY_DELTA: u16 = 144;
+
+/// The number of blocks after which we fail back a held order payment whose channel isn't ready
+/// yet, leaving enough blocks before the payment's HTLCs time out for them to be failed back.
+const HELD_PAYMENT_TIMEOUT_BLOCKS: u32 = ORDER_INVOICE_MIN_FINAL_CLTV_EXPIRY_DELTA as u32 - 36;
+
+/// The time we expect it takes to mine a block, used to turn an order's channel expiry into a
+/// point in time.
+const EXPECTED_BLOCK_INTERVAL_SECS: u64 = 600;
+
+/// The number of blocks ahead of its expiry we warn about the lease of a channel we bought, if not
+/// configured otherwise, i.e., about a week.
+const DEFAULT_CHANNEL_EXPIRY_WARNING_BLOCKS: u32 = 1008;
+
+/// Configuration options for selling channels to clients that order them.
+#[derive(Clone, Debug)]
+pub struct ChannelRequestsConfig<CP: Deref, OW: Deref, RS: Deref>
+where
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	/// The website we advertise to clients.
+	pub website: String,
+	/// The options we advertise to clients in response to `lsps1.get_info`.
+	///
+	/// Orders that don't match them are rejected.
+	pub options: OptionsSupported,
+	/// Prices the channels clients order, and validates the tokens they order them with.
+	pub pricing: CP,
+	/// How long an order stays valid if it isn't paid for.
+	pub order_expiry_secs: u32,
+	/// How long we keep orders once they are finished, i.e., once they failed without a refund
+	/// being due, or the lease of their channel expired.
+	///
+	/// Afterwards they are forgotten, and neither clients nor
+	/// [`LiquidityManager::channel_order_by_id`] can look them up anymore.
+	///
+	/// [`LiquidityManager::channel_order_by_id`]: crate::LiquidityManager::channel_order_by_id
+	pub order_retention_secs: u32,
+	/// The number of orders a client may have awaiting payment at once.
+	///
+	/// Further orders of the client are rejected until one of them was paid for or expired.
+	pub max_unpaid_orders_per_client: usize,
+	/// The configuration of the channels we open for orders.
+	///
+	/// Whether a channel is announced follows its order. The number of confirmations a channel
+	/// requires is up to the client accepting it, so our
+	/// [`OptionsSupported::minimum_channel_confirmations`] is advertised but not enforced.
+	///
+	/// The funding transactions of these channels have to be built in response to
+	/// [`Event::FundingGenerationReady`] at a feerate that gets them confirmed within the
+	/// [`OrderParams::confirms_within_blocks`] the client paid for. Its `user_channel_id` is the
+	/// [`OutboundChannelOrder::user_channel_id`] of the order.
+	///
+	/// [`Event::FundingGenerationReady`]: lightning::events::Event::FundingGenerationReady
+	pub channel_config: UserConfig,
+	/// Provides the addresses orders are paid to on-chain.
+	///
+	/// Orders are only offered to be paid on-chain if a wallet is given and our `options` set
+	/// [`OptionsSupported::minimum_onchain_payment_confirmations`]. Their total must also reach
+	/// [`OptionsSupported::min_onchain_payment_size_sat`], if set.
+	///
+	/// The payments are found by scanning the full blocks passed to the [`LiquidityManager`] as a
+	/// [`Listen`], or all transactions of a block passed to it as a [`Confirm`]. Order addresses
+	/// can't be registered with a [`Filter`], so chain sources that only sync registered outputs
+	/// and transactions, e.g., Electrum or Esplora based ones, don't see these payments. No wallet
+	/// should be given when using such a source.
+	///
+	/// [`LiquidityManager`]: crate::LiquidityManager
+	/// [`Listen`]: lightning::chain::Listen
+	/// [`Confirm`]: lightning::chain::Confirm
+	/// [`Filter`]: lightning::chain::Filter
+	pub wallet: Option<OW>,
+	/// The feerate in sat/vbyte from which we accept on-chain payments before they confirmed.
+	///
+	/// Such payments have to be passed to [`LiquidityManager::unconfirmed_transaction_seen`].
+	///
+	/// [`LiquidityManager::unconfirmed_transaction_seen`]: crate::LiquidityManager::unconfirmed_transaction_seen
+	pub min_fee_for_0conf: u8,
+	/// Signs the receipts of the orders we accept with our node key.
+	///
+	/// Orders are accepted without a receipt if no signer is given.
+	pub receipt_signer: Option<RS>,
+}
+
+/// Configuration options for ordering channels from LSPs as a client.
+#[derive(Clone, Debug)]
+pub struct ChannelRequestsClientConfig {
+	/// The number of blocks ahead of its expiry we are warned about the lease of a channel we
+	/// bought, via an [`LSPS1Event::ChannelLeaseExpiring`] event.
+	pub channel_expiry_warning_blocks: u32,
+}
+
+/// A channel order a client placed with us.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboundChannelOrder {
+	/// The identifier we assigned to the order.
+	pub order_id: OrderId,
+	/// The node id of the client that placed the order.
+	pub counterparty_node_id: PublicKey,
+	/// The parameters of the ordered channel.
+	pub order: OrderParams,
+	/// The time the order was created.
+	pub created_at: LSPSDateTime,
+	/// The time the order expires if it isn't paid for.
+	pub expires_at: LSPSDateTime,
+	/// The state of the order.
+	pub order_state: OrderState,
+	/// How the order is paid for.
+	pub payment: PaymentInfo,
+	/// The channel we opened for the order, if any.
+	pub channel: Option<ChannelInfo>,
+	/// The `user_channel_id` of the channel we open for the order.
+	pub user_channel_id: u128,
+	/// The payment hash of the order's invoice.
+	pub payment_hash: PaymentHash,
+	/// The token the client placed the order with, if any.
+	pub token: Option<String>,
+	/// The address the client asked to be refunded to if the order fails after it was paid for
+	/// on-chain.
+	pub refund_onchain_address: Option<String>,
+	/// The transaction refunding the order's on-chain payment, once it was refunded.
+	pub refund_txid: Option<Txid>,
+	/// The block height at which the lease of the order's channel expires, i.e., the height its
+	/// funding transaction confirmed at plus the order's `channel_expiry_blocks`.
+	///
+	/// `None` until the funding transaction confirmed.
+	pub channel_expiry_height: Option<u32>,
+	/// The receipt we signed for the order, covering the terms we accepted it on.
+	///
+	/// `None` for orders created before we signed receipts.
+	pub receipt: Option<OrderReceipt>,
+	payment_secret: PaymentSecret,
+	onchain_payment_height: Option<u32>,
+	onchain_payment_block_hash: Option<BlockHash>,
+	channel_expiry_reported: bool,
+	finished_at: Option<LSPSDateTime>,
+	hold_height: Option<u32>,
+}
+
+impl_writeable_tlv_based!(OutboundChannelOrder, {
+	(0, order_id, required),
+	(2, counterparty_node_id, required),
+	(4, order, required),
+	(6, created_at, required),
+	(8, expires_at, required),
+	(10, order_state, required),
+	(12, payment, required),
+	(14, channel, option),
+	(16, user_channel_id, required),
+	(18, payment_hash, required),
+	(20, payment_secret, required),
+	(22, onchain_payment_height, option),
+	(24, onchain_payment_block_hash, option),
+	(26, refund_onchain_address, option),
+	(28, refund_txid, option),
+	(30, token, option),
+	(32, channel_expiry_height, option),
+	(34, channel_expiry_reported, required),
+	(36, receipt, option),
+	(38, finished_at, option),
+	(40, hold_height, option),
+});
+
+impl OutboundChannelOrder {
+	/// Moves the order to the given states, returning the event reporting the transition.
+	fn transition(
+		&mut self, order_state: OrderState, payment_state: PaymentState,
+	) -> Result<LSPS1Event, APIError> {
+		if !is_valid_transition(self.order_state, self.payment.state, order_state, payment_state) {
+			return Err(invalid_transition_error(
+				&self.order_id,
+				self.order_state,
+				self.payment.state,
+				order_state,
+				payment_state,
+			));
+		}
+		self.order_state = order_state;
+		self.payment.state = payment_state;
+		Ok(LSPS1Event::OrderStateChanged {
+			order_id: self.order_id.clone(),
+			user_channel_id: self.user_channel_id,
+			counterparty_node_id: self.counterparty_node_id,
+			order_state,
+			payment_state,
+		})
+	}
+
+	/// Whether the order awaits to be paid for, which it no longer does once paid on-chain.
+	fn awaits_payment(&self) -> bool {
+		self.order_state == OrderState::Created
+			&& self.payment.state == PaymentState::ExpectPayment
+			&& self.payment.onchain_payment.is_none()
+	}
+
+	/// Whether we are done with the order, because it failed without a refund being due, or the
+	/// lease of its channel expired.
+	fn is_finished(&self) -> bool {
+		match self.order_state {
+			OrderState::Failed => self.refund_required_event().is_none(),
+			OrderState::Completed => self.channel_expiry_reported,
+			OrderState::Created => false,
+		}
+	}
+
+	/// Whether the order was paid for and awaits its channel to be opened.
+	fn awaits_channel(&self) -> bool {
+		self.order_state == OrderState::Created
+			&& matches!(self.payment.state, PaymentState::Hold | PaymentState::Paid)
+	}
+
+	/// Records the given output as the on-chain payment for the order, confirmed in the given
+	/// block if any, returning whether it is a payment the order awaits.
+	///
+	/// Only the first output paying the order total is recorded, which may be seen before and
+	/// once more after it confirmed.
+	fn record_onchain_payment(
+		&mut self, tx: &Transaction, vout: usize, block: Option<(u32, BlockHash)>,
+	) -> bool {
+		if self.order_state != OrderState::Created
+			|| self.payment.state != PaymentState::ExpectPayment
+		{
+			return false;
+		}
+
+		let outpoint = OutPoint { txid: tx.txid(), vout: vout as u32 };
+		match self.payment.onchain_payment.as_ref() {
+			Some(payment) if payment.outpoint != outpoint => return false,
+			Some(_) => {}
+			None if self.expires_at.is_past() => return false,
+			None => {}
+		}
+		let sat = tx.output[vout].value;
+		if sat < self.payment.order_total_sat {
+			return false;
+		}
+
+		self.payment.onchain_payment = Some(OnchainPayment { outpoint, sat, confirmed: false });
+		if let Some((height, block_hash)) = block {
+			self.onchain_payment_height = Some(height);
+			self.onchain_payment_block_hash = Some(block_hash);
+		}
+		true
+	}
+
+	fn accept_onchain_payment(&mut self) -> Result<LSPS1Event, APIError> {
+		let event = self.transition(OrderState::Created, PaymentState::Paid)?;
+		if let Some(payment) = self.payment.onchain_payment.as_mut() {
+			payment.confirmed = true;
+		}
+		Ok(event)
+	}
+
+	/// The event asking for the order's on-chain payment to be refunded, if it failed after it
+	/// was paid for on-chain and wasn't refunded yet.
+	fn refund_required_event(&self) -> Option<LSPS1Event> {
+		if self.order_state != OrderState::Failed
+			|| self.payment.state != PaymentState::Paid
+			|| self.refund_txid.is_some()
+		{
+			return None;
+		}
+		let payment = self.payment.onchain_payment.as_ref()?;
+		Some(LSPS1Event::RefundRequired {
+			order_id: self.order_id.clone(),
+			user_channel_id: self.user_channel_id,
+			counterparty_node_id: self.counterparty_node_id,
+			amount_sat: payment.sat,
+			refund_onchain_address: self
+				.refund_onchain_address
+				.as_ref()
+				.and_then(|address| Address::from_str(address).ok()),
+		})
+	}
+
+	fn to_response(&self) -> OrderResponse {
+		OrderResponse {
+			order_id: self.order_id.clone(),
+			order: self.order.clone(),
+			created_at: self.created_at,
+			expires_at: self.expires_at,
+			order_state: self.order_state,
+			payment: self.payment.clone(),
+			channel: self.channel.clone(),
+			receipt: self.receipt.clone().map(Box::new),
+		}
+	}
+}
+
+/// Whether an order may move from one pair of order and payment states to another.
+///
+/// Orders move from `CREATED` to `COMPLETED` or `FAILED`, and their payments from
+/// `EXPECT_PAYMENT` via `HOLD` to `PAID` or `REFUNDED`. Payments made on-chain are only refunded
+/// once they were `PAID`. Only paid orders complete, and only failed orders are refunded.
+///
+/// As clients only learn the states an LSP reports when asked, states in between may be skipped,
+/// though a payment is only refunded once it was received, i.e., `HOLD` or `PAID`.
+fn is_valid_transition(
+	order_state: OrderState, payment_state: PaymentState, next_order_state: OrderState,
+	next_payment_state: PaymentState,
+) -> bool {
+	let is_valid_order_transition = order_state == next_order_state
+		|| (order_state == OrderState::Created && next_order_state != OrderState::Created);
+	let is_valid_payment_transition = match (payment_state, next_payment_state) {
+		(PaymentState::ExpectPayment, next_state) => next_state != PaymentState::Refunded,
+		(PaymentState::Hold, PaymentState::Paid)
+		| (PaymentState::Hold, PaymentState::Refunded)
+		| (PaymentState::Paid, PaymentState::Refunded) => true,
+		(state, next_state) => state == next_state,
+	};
+	let is_consistent = (next_order_state != OrderState::Completed
+		|| next_payment_state == PaymentState::Paid)
+		&& (next_payment_state != PaymentState::Refunded || next_order_state == OrderState::Failed);
+	is_valid_order_transition && is_valid_payment_transition && is_consistent
+}
+
+/// Signs a receipt for the given order of the client with the given node id.
+fn create_order_receipt<S: OrderReceiptSigner + ?Sized>(
+	receipt_signer: &S, counterparty_node_id: &PublicKey, response: &OrderResponse,
+) -> Option<OrderReceipt> {
+	let mut receipt = OrderReceipt {
+		order_id: response.order_id.clone(),
+		order: response.order.clone(),
+		fee_total_sat: response.payment.fee_total_sat,
+		order_total_sat: response.payment.order_total_sat,
+		expires_at: response.expires_at,
+		signature: String::new(),
+	};
+	let signature =
+		receipt_signer.sign_order_receipt(&receipt.signed_message(counterparty_node_id))?;
+	let (recovery_id, signature) = signature.serialize_compact();
+	let mut signature = signature.to_vec();
+	signature.push(recovery_id.to_i32() as u8);
+	receipt.signature = utils::hex_str(&signature);
+	Some(receipt)
+}
+
+/// The parts of the [`ChannelDetails`] of a channel we check the channels opened for orders
+/// against.
+struct OrderChannel {
+	funding_outpoint: OutPoint,
+	counterparty_node_id: PublicKey,
+	channel_value_sat: u64,
+	/// The balance on the counterparty's side of the channel.
+	inbound_balance_msat: u64,
+	is_public: bool,
+	confirmations: Option<u32>,
+}
+
+/// The given channels as far as they have a funding outpoint.
+fn order_channels(channels: &[ChannelDetails]) -> Vec<OrderChannel> {
+	channels
+		.iter()
+		.filter_map(|channel| {
+			Some(OrderChannel {
+				funding_outpoint: channel.funding_txo?.into_bitcoin_outpoint(),
+				counterparty_node_id: channel.counterparty.node_id,
+				channel_value_sat: channel.channel_value_satoshis,
+				inbound_balance_msat: channel
+					.channel_value_satoshis
+					.saturating_mul(1000)
+					.saturating_sub(channel.balance_msat),
+				is_public: channel.is_public,
+				confirmations: channel.confirmations,
+			})
+		})
+		.collect()
+}
+
+/// The block height at which the lease of an order's channel expires, if its funding transaction
+/// confirmed according to the given channels at the given best block height.
+fn channel_expiry_height(
+	channels: &[OrderChannel], channel: Option<&ChannelInfo>, order: &OrderParams, height: u32,
+) -> Option<u32> {
+	let funding_outpoint = channel?.funding_outpoint;
+	let confirmations = channels
+		.iter()
+		.find(|channel| channel.funding_outpoint == funding_outpoint)?
+		.confirmations
+		.filter(|confirmations| *confirmations > 0)?;
+	let confirmation_height = height.saturating_add(1).checked_sub(confirmations)?;
+	Some(confirmation_height.saturating_add(order.channel_expiry_blocks))
+}
+
+fn invalid_transition_error(
+	order_id: &OrderId, order_state: OrderState, payment_state: PaymentState,
+	next_order_state: OrderState, next_payment_state: PaymentState,
+) -> APIError {
+	APIError::APIMisuseError {
+		err: format!(
+			"Order {:?} can't move from {:?}/{:?} to {:?}/{:?}",
+			order_id, order_state, payment_state, next_order_state, next_payment_state
+		),
+	}
+}
+
+/// A channel order we placed with an LSP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InboundChannelOrder {
+	/// The identifier the LSP assigned to the order.
+	pub order_id: OrderId,
+	/// The identifier passed into [`LiquidityManager::channel_order_request_options`].
+	///
+	/// [`LiquidityManager::channel_order_request_options`]: crate::LiquidityManager::channel_order_request_options
+	pub user_channel_id: u128,
+	/// The node id of the LSP the order was placed with.
+	pub counterparty_node_id: PublicKey,
+	/// The parameters of the ordered channel.
+	pub order: OrderParams,
+	/// The time the order was created.
+	pub created_at: LSPSDateTime,
+	/// The time the order expires if it isn't paid for.
+	pub expires_at: LSPSDateTime,
+	/// The state of the order.
+	pub order_state: OrderState,
+	/// How the order is paid for.
+	pub payment: PaymentInfo,
+	/// The channel the LSP opened for the order, if any.
+	pub channel: Option<ChannelInfo>,
+	/// The payment hash of the order's invoice.
+	pub payment_hash: PaymentHash,
+	/// The address we asked the LSP to refund an on-chain payment to if the order fails.
+	pub refund_onchain_address: Option<String>,
+	/// The block height at which the lease of the order's channel expires, i.e., the height its
+	/// funding transaction confirmed at plus the order's `channel_expiry_blocks`.
+	///
+	/// `None` until the funding transaction confirmed.
+	pub channel_expiry_height: Option<u32>,
+	/// The receipt the LSP signed for the order, covering the terms it accepted it on.
+	///
+	/// `None` if the LSP doesn't sign receipts. If it did, the receipt was checked to match the
+	/// order and to be signed by the LSP for us, and may be verified again via
+	/// [`OrderReceipt::verify`].
+	pub receipt: Option<OrderReceipt>,
+	channel_expiry_warned: bool,
+}
+
+impl_writeable_tlv_based!(InboundChannelOrder, {
+	(0, order_id, required),
+	(2, user_channel_id, required),
+	(4, counterparty_node_id, required),
+	(6, order, required),
+	(8, created_at, required),
+	(10, expires_at, required),
+	(12, order_state, required),
+	(14, payment, required),
+	(16, channel, option),
+	(18, payment_hash, required),
+	(20, refund_onchain_address, option),
+	(22, channel_expiry_height, option),
+	(24, channel_expiry_warned, required),
+	(26, receipt, option),
+});
+
+impl InboundChannelOrder {
+	fn new(
+		counterparty_node_id: PublicKey, user_channel_id: u128, response: OrderResponse,
+		payment_hash: PaymentHash, refund_onchain_address: Option<String>,
+	) -> Self {
+		Self {
+			order_id: response.order_id,
+			user_channel_id,
+			counterparty_node_id,
+			order: response.order,
+			created_at: response.created_at,
+			expires_at: response.expires_at,
+			order_state: response.order_state,
+			payment: response.payment,
+			channel: response.channel,
+			payment_hash,
+			refund_onchain_address,
+			channel_expiry_height: None,
+			receipt: response.receipt.map(|receipt| *receipt),
+			channel_expiry_warned: false,
+		}
+	}
+
+	/// Moves the order to the given states, returning the event reporting the transition.
+	fn transition(
+		&mut self, order_state: OrderState, payment_state: PaymentState,
+	) -> Result<LSPS1Event, APIError> {
+		if !is_valid_transition(self.order_state, self.payment.state, order_state, payment_state) {
+			return Err(invalid_transition_error(
+				&self.order_id,
+				self.order_state,
+				self.payment.state,
+				order_state,
+				payment_state,
+			));
+		}
+		self.order_state = order_state;
+		self.payment.state = payment_state;
+		Ok(LSPS1Event::OrderStateChanged {
+			order_id: self.order_id.clone(),
+			user_channel_id: self.user_channel_id,
+			counterparty_node_id: self.counterparty_node_id,
+			order_state,
+			payment_state,
+		})
+	}
+
+	/// The address to pay the order total to on-chain, if the LSP accepts on-chain payments for
+	/// the order.
+	///
+	/// The LSP opens the channel once the payment has the order's
+	/// [`PaymentInfo::min_onchain_payment_confirmations`], or right away if it pays at least the
+	/// [`PaymentInfo::min_fee_for_0conf`] feerate. The payment it received is reported as the
+	/// order's [`PaymentInfo::onchain_payment`].
+	pub fn onchain_payment_address(&self) -> Option<Address> {
+		self.payment.onchain_address.as_ref().and_then(|address| Address::from_str(address).ok())
+	}
+
+	/// Whether the channel the LSP reported to have opened for the order is among the given ones,
+	/// and matches the order.
+	///
+	/// The channel has to be with the LSP, have the ordered capacity and visibility, and give us at
+	/// least the ordered inbound balance.
+	fn is_delivered(&self, channels: &[OrderChannel]) -> bool {
+		let funding_outpoint = match self.channel.as_ref() {
+			Some(channel) => channel.funding_outpoint,
+			None => return false,
+		};
+		let channel_value_sat =
+			self.order.lsp_balance_sat.saturating_add(self.order.client_balance_sat);
+		channels.iter().any(|channel| {
+			channel.funding_outpoint == funding_outpoint
+				&& channel.counterparty_node_id == self.counterparty_node_id
+				&& channel.channel_value_sat == channel_value_sat
+				&& channel.inbound_balance_msat >= self.order.lsp_balance_sat.saturating_mul(1000)
+				&& channel.is_public == self.order.announce_channel
+		})
+	}
+
+	/// Whether the order can no longer be paid for as it or its invoice expired.
+	fn is_expired(&self) -> bool {
+		self.expires_at.is_past()
+			|| Bolt11Invoice::from_str(&self.payment.bolt11_invoice)
+				.map_or(true, |invoice| invoice.is_expired())
+	}
+
+	fn update(&mut self, response: OrderResponse) {
+		self.expires_at = response.expires_at;
+		self.order_state = response.order_state;
+		self.payment = response.payment;
+		self.channel = response.channel;
+	}
+}
+
+enum InboundOrderRequestState {
+	OptionsRequested,
+	OptionsReceived { version: u16, options: OptionsSupported },
+	OrderRequested { order: OrderParams, refund_onchain_address: Option<String> },
+	StatusRequested { order_id: OrderId },
+}
+
+struct InboundOrderRequest {
+	user_channel_id: u128,
+	state: InboundOrderRequestState,
+	/// The number of timer ticks since we sent the request, or since we received the options we
+	/// have to order from.
+	ticks_elapsed: u8,
+}
+
+struct PeerState {
+	inbound_requests_by_request_id: HashMap<RequestId, InboundOrderRequest>,
+}
+
+impl PeerState {
+	fn new() -> Self {
+		Self { inbound_requests_by_request_id: HashMap::new() }
+	}
+}
+
+#[derive(Default)]
+struct OutboundChannelOrders {
+	orders_by_id: HashMap<OrderId, OutboundChannelOrder>,
+	order_ids_by_payment_hash: HashMap<PaymentHash, OrderId>,
+	order_ids_by_user_channel_id: HashMap<u128, OrderId>,
+	order_ids_by_script_pubkey: HashMap<Script, OrderId>,
+}
+
+impl OutboundChannelOrders {
+	fn insert(&mut self, order: OutboundChannelOrder) {
+		self.order_ids_by_payment_hash.insert(order.payment_hash, order.order_id.clone());
+		self.order_ids_by_user_channel_id.insert(order.user_channel_id, order.order_id.clone());
+		let onchain_address = order.payment.onchain_address.as_ref();
+		if let Some(address) = onchain_address.and_then(|address| Address::from_str(address).ok()) {
+			self.order_ids_by_script_pubkey.insert(address.script_pubkey(), order.order_id.clone());
+		}
+		self.orders_by_id.insert(order.order_id.clone(), order);
+	}
+
+	fn remove(&mut self, order_id: &OrderId) -> Option<OutboundChannelOrder> {
+		let order = self.orders_by_id.remove(order_id)?;
+		self.order_ids_by_payment_hash.remove(&order.payment_hash);
+		self.order_ids_by_user_channel_id.remove(&order.user_channel_id);
+		let onchain_address = order.payment.onchain_address.as_ref();
+		if let Some(address) = onchain_address.and_then(|address| Address::from_str(address).ok()) {
+			self.order_ids_by_script_pubkey.remove(&address.script_pubkey());
+		}
+		Some(order)
+	}
+
+	fn get_mut_by_payment_hash(
+		&mut self, payment_hash: &PaymentHash,
+	) -> Option<&mut OutboundChannelOrder> {
+		let order_id = self.order_ids_by_payment_hash.get(payment_hash)?;
+		self.orders_by_id.get_mut(order_id)
+	}
+
+	fn get_mut_by_user_channel_id(
+		&mut self, user_channel_id: u128,
+	) -> Option<&mut OutboundChannelOrder> {
+		let order_id = self.order_ids_by_user_channel_id.get(&user_channel_id)?;
+		self.orders_by_id.get_mut(order_id)
+	}
+
+	fn get_mut_by_script_pubkey(
+		&mut self, script_pubkey: &Script,
+	) -> Option<&mut OutboundChannelOrder> {
+		let order_id = self.order_ids_by_script_pubkey.get(script_pubkey)?;
+		self.orders_by_id.get_mut(order_id)
+	}
+}
+
+struct ServiceState<CP: Deref, OW: Deref, RS: Deref>
+where
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	config: ChannelRequestsConfig<CP, OW, RS>,
+	outbound_orders: Mutex<OutboundChannelOrders>,
+	best_block_height: AtomicU32,
+}
+
+pub struct LSPS1MessageHandler<ES: Deref, CM: Deref, NS: Deref, CP: Deref, OW: Deref, RS: Deref>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	entropy_source: ES,
+	channel_manager: CM,
+	node_signer: NS,
+	network: Network,
+	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+	pending_events: Arc<EventQueue>,
+	per_peer_state: Mutex<HashMap<PublicKey, PeerState>>,
+	service_state: Option<ServiceState<CP, OW, RS>>,
+	inbound_orders: Mutex<HashMap<OrderId, InboundChannelOrder>>,
+	channel_expiry_warning_blocks: u32,
+	needs_persistence: AtomicBool,
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, CP: Deref, OW: Deref, RS: Deref>
+	LSPS1MessageHandler<ES, CM, NS, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(
+		entropy_source: ES, channel_manager: CM, node_signer: NS, network: Network,
+		config: Option<ChannelRequestsConfig<CP, OW, RS>>,
+		client_config: Option<ChannelRequestsClientConfig>,
+		pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		pending_events: Arc<EventQueue>,
+	) -> Self {
+		let service_state = config.map(|config| ServiceState {
+			config,
+			outbound_orders: Mutex::new(OutboundChannelOrders::default()),
+			best_block_height: AtomicU32::new(0),
+		});
+
+		Self {
+			entropy_source,
+			channel_manager,
+			node_signer,
+			network,
+			pending_messages,
+			pending_events,
+			per_peer_state: Mutex::new(HashMap::new()),
+			service_state,
+			inbound_orders: Mutex::new(HashMap::new()),
+			channel_expiry_warning_blocks: client_config
+				.map_or(DEFAULT_CHANNEL_EXPIRY_WARNING_BLOCKS, |client_config| {
+					client_config.channel_expiry_warning_blocks
+				}),
+			needs_persistence: AtomicBool::new(false),
+		}
+	}
+
+	pub fn request_options(&self, counterparty_node_id: PublicKey, user_channel_id: u128) {
+		let request = InboundOrderRequest {
+			user_channel_id,
+			state: InboundOrderRequestState::OptionsRequested,
+			ticks_elapsed: 0,
+		};
+		self.send_inbound_request(
+			&counterparty_node_id,
+			request,
+			LSPS1Request::GetInfo(GetInfoRequest {}),
+		);
+	}
+
+	pub fn create_order(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, order: OrderParams,
+		token: Option<String>, refund_onchain_address: Option<Address>,
+	) -> Result<(), APIError> {
+		if let Some(address) = refund_onchain_address.as_ref() {
+			if !address.is_valid_for_network(self.network) {
+				return Err(APIError::APIMisuseError {
+					err: format!("The refund address {} isn't valid on {}", address, self.network),
+				});
+			}
+		}
+		let refund_onchain_address = refund_onchain_address.map(|address| address.to_string());
+
+		let (mut request, version) = {
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let requests = per_peer_state
+				.get_mut(counterparty_node_id)
+				.map(|peer_state| &mut peer_state.inbound_requests_by_request_id);
+			let requests = match requests {
+				Some(requests) => requests,
+				None => return Err(no_options_error(counterparty_node_id, user_channel_id)),
+			};
+
+			let options = requests.iter().find_map(|(request_id, request)| match &request.state {
+				InboundOrderRequestState::OptionsReceived { version, options }
+					if request.user_channel_id == user_channel_id =>
+				{
+					Some((request_id.clone(), *version, options.mismatched_property(&order)))
+				}
+				_ => None,
+			});
+
+			match options {
+				Some((request_id, version, None)) => match requests.remove(&request_id) {
+					Some(request) => (request, version),
+					None => return Err(no_options_error(counterparty_node_id, user_channel_id)),
+				},
+				Some((_, _, Some(property))) => {
+					return Err(APIError::APIMisuseError {
+						err: format!(
+							"The order's {} doesn't match the options supported by {}",
+							property, counterparty_node_id
+						),
+					});
+				}
+				None => return Err(no_options_error(counterparty_node_id, user_channel_id)),
+			}
+		};
+
+		request.state = InboundOrderRequestState::OrderRequested {
+			order: order.clone(),
+			refund_onchain_address: refund_onchain_address.clone(),
+		};
+		request.ticks_elapsed = 0;
+		self.send_inbound_request(
+			counterparty_node_id,
+			request,
+			LSPS1Request::CreateOrder(CreateOrderRequest {
+				api_version: version,
+				order,
+				token,
+				refund_onchain_address,
+			}),
+		);
+		Ok(())
+	}
+
+	pub fn check_order_status(
+		&self, counterparty_node_id: &PublicKey, order_id: OrderId,
+	) -> Result<(), APIError> {
+		let user_channel_id = match self.inbound_orders.lock().unwrap().get(&order_id) {
+			Some(order) if order.counterparty_node_id == *counterparty_node_id => {
+				order.user_channel_id
+			}
+			_ => {
+				return Err(APIError::APIMisuseError {
+					err: format!(
+						"We didn't place an order {:?} with {}",
+						order_id, counterparty_node_id
+					),
+				});
+			}
+		};
+
+		let request = InboundOrderRequest {
+			user_channel_id,
+			state: InboundOrderRequestState::StatusRequested { order_id: order_id.clone() },
+			ticks_elapsed: 0,
+		};
+		self.send_inbound_request(
+			counterparty_node_id,
+			request,
+			LSPS1Request::GetOrder(GetOrderRequest { order_id }),
+		);
+		Ok(())
+	}
+
+	pub fn outbound_order_by_id(&self, order_id: &OrderId) -> Option<OutboundChannelOrder> {
+		self.service_state.as_ref().and_then(|service_state| {
+			service_state.outbound_orders.lock().unwrap().orders_by_id.get(order_id).cloned()
+		})
+	}
+
+	pub fn list_outbound_orders(
+		&self, order_state: Option<OrderState>, counterparty_node_id: Option<&PublicKey>,
+		created_at: Option<Range<LSPSDateTime>>,
+	) -> Vec<OutboundChannelOrder> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Vec::new(),
+		};
+
+		let outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let mut orders: Vec<OutboundChannelOrder> = outbound_orders
+			.orders_by_id
+			.values()
+			.filter(|order| order_state.map_or(true, |state| order.order_state == state))
+			.filter(|order| {
+				counterparty_node_id.map_or(true, |node_id| order.counterparty_node_id == *node_id)
+			})
+			.filter(|order| {
+				created_at.as_ref().map_or(true, |range| range.contains(&order.created_at))
+			})
+			.cloned()
+			.collect();
+		orders.sort_by_key(|order| order.created_at);
+		orders
+	}
+
+	pub fn cancel_outbound_order(&self, order_id: &OrderId) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => {
+				return Err(APIError::APIMisuseError {
+					err: "We don't sell channels to clients".to_string(),
+				});
+			}
+		};
+
+		// Once a payment arrived, even if it didn't confirm yet, the order has to be fulfilled or
+		// refunded instead. Payments to the invoice of a cancelled order are failed back.
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.orders_by_id.get_mut(order_id) {
+			Some(order)
+				if order.order_state == OrderState::Created
+					&& order.payment.state == PaymentState::ExpectPayment
+					&& order.payment.onchain_payment.is_none() =>
+			{
+				order
+			}
+			_ => {
+				return Err(APIError::APIMisuseError {
+					err: format!("Order {:?} isn't awaiting payment", order_id),
+				});
+			}
+		};
+		let event = order.transition(OrderState::Failed, PaymentState::ExpectPayment)?;
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.pending_events.enqueue(Event::LSPS1(event));
+		Ok(())
+	}
+
+	pub fn payment_claimable(&self, payment_hash: PaymentHash, amount_msat: u64) -> bool {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return true,
+		};
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.get_mut_by_payment_hash(&payment_hash) {
+			Some(order) => order,
+			None => return true,
+		};
+
+		// The payment is already held, e.g., as it is replayed after a restart. Orders paid
+		// on-chain aren't paid twice though.
+		if let PaymentState::Hold | PaymentState::Paid = order.payment.state {
+			if order.payment.onchain_payment.is_some() {
+				drop(outbound_orders);
+				self.channel_manager.fail_htlc_backwards(&payment_hash);
+			}
+			return false;
+		}
+
+		// Orders which expired since the last timer tick fail right away.
+		let expired_event =
+			if order.order_state == OrderState::Created && order.expires_at.is_past() {
+				order.transition(OrderState::Failed, PaymentState::ExpectPayment).ok()
+			} else {
+				None
+			};
+
+		// The payment is only claimed once the channel is ready, see `channel_ready`. Payments for
+		// orders that failed, as well as underpayments, are failed back.
+		let order_total_msat = order.payment.order_total_sat.saturating_mul(1000);
+		let hold_event = if amount_msat >= order_total_msat {
+			order.transition(OrderState::Created, PaymentState::Hold).ok()
+		} else {
+			None
+		};
+		let hold_event = match hold_event {
+			Some(hold_event) => {
+				let height = service_state.best_block_height.load(Ordering::Acquire);
+				order.hold_height = if height > 0 { Some(height) } else { None };
+				hold_event
+			}
+			None => {
+				drop(outbound_orders);
+				if let Some(expired_event) = expired_event {
+					self.needs_persistence.store(true, Ordering::Release);
+					self.pending_events.enqueue(Event::LSPS1(expired_event));
+				}
+				self.channel_manager.fail_htlc_backwards(&payment_hash);
+				return false;
+			}
+		};
+
+		let order = order.clone();
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+		self.pending_events.enqueue(Event::LSPS1(hold_event));
+
+		self.open_order_channel(service_state, &order);
+		false
+	}
+
+	pub fn unconfirmed_transaction_seen(&self, tx: &Transaction, fee_sat: u64) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		let vsize = (tx.weight() as u64 + 3) / 4;
+		let fee_rate_sat_per_vbyte = fee_sat / vsize.max(1);
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let mut events = Vec::new();
+		let mut paid_orders = Vec::new();
+		for (vout, output) in tx.output.iter().enumerate() {
+			let order = match outbound_orders.get_mut_by_script_pubkey(&output.script_pubkey) {
+				Some(order) => order,
+				None => continue,
+			};
+			if !order.record_onchain_payment(tx, vout, None) {
+				continue;
+			}
+			let pays_0conf_fee = order
+				.payment
+				.min_fee_for_0conf
+				.map_or(false, |min_fee| fee_rate_sat_per_vbyte >= u64::from(min_fee));
+			if pays_0conf_fee {
+				if let Ok(event) = order.accept_onchain_payment() {
+					events.push(event);
+					paid_orders.push(order.clone());
+				}
+			}
+		}
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		for event in events {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+		for order in paid_orders {
+			self.open_order_channel(service_state, &order);
+		}
+	}
+
+	pub fn transactions_confirmed(
+		&self, header: &BlockHeader, txdata: &TransactionData, height: u32,
+	) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let mut has_payments = false;
+		for (_, tx) in txdata.iter() {
+			for (vout, output) in tx.output.iter().enumerate() {
+				if let Some(order) = outbound_orders.get_mut_by_script_pubkey(&output.script_pubkey)
+				{
+					let block = (height, header.block_hash());
+					has_payments |= order.record_onchain_payment(tx, vout, Some(block));
+				}
+			}
+		}
+		drop(outbound_orders);
+
+		if has_payments {
+			self.needs_persistence.store(true, Ordering::Release);
+			self.best_block_updated(header, height);
+		}
+	}
+
+	pub fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.forget_unaccepted_onchain_payments(|order| {
+			order.payment.onchain_payment.as_ref().map(|payment| payment.outpoint.txid)
+				== Some(*txid)
+		});
+	}
+
+	pub fn best_block_updated(&self, _header: &BlockHeader, height: u32) {
+		self.update_channel_leases(height);
+
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+		service_state.best_block_height.store(height, Ordering::Release);
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let mut events = Vec::new();
+		let mut paid_orders = Vec::new();
+		for order in outbound_orders.orders_by_id.values_mut() {
+			let confirmation_height = match order.onchain_payment_height {
+				Some(confirmation_height) if confirmation_height <= height => confirmation_height,
+				_ => continue,
+			};
+			let confirmations = height - confirmation_height + 1;
+			let min_confirmations = order.payment.min_onchain_payment_confirmations.unwrap_or(0);
+			if order.payment.state != PaymentState::ExpectPayment
+				|| confirmations < u32::from(min_confirmations)
+			{
+				continue;
+			}
+			if let Ok(event) = order.accept_onchain_payment() {
+				events.push(event);
+				paid_orders.push(order.clone());
+			}
+		}
+
+		// Held payments whose channel didn't get ready in time are failed back before their
+		// HTLCs time out, failing the order.
+		let mut is_updated = false;
+		let mut timed_out_payment_hashes = Vec::new();
+		for order in outbound_orders.orders_by_id.values_mut() {
+			if !order.awaits_channel() || order.payment.state != PaymentState::Hold {
+				continue;
+			}
+			let hold_height = match order.hold_height {
+				Some(hold_height) => hold_height,
+				None => {
+					order.hold_height = Some(height);
+					is_updated = true;
+					continue;
+				}
+			};
+			if height < hold_height.saturating_add(HELD_PAYMENT_TIMEOUT_BLOCKS) {
+				continue;
+			}
+			if let Ok(event) = order.transition(OrderState::Failed, PaymentState::Refunded) {
+				events.push(event);
+				timed_out_payment_hashes.push(order.payment_hash);
+			}
+		}
+		drop(outbound_orders);
+
+		if is_updated || !events.is_empty() {
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+		for payment_hash in timed_out_payment_hashes {
+			self.channel_manager.fail_htlc_backwards(&payment_hash);
+		}
+		for event in events {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+		for order in paid_orders {
+			self.open_order_channel(service_state, &order);
+		}
+	}
+
+	pub fn get_relevant_txids(&self) -> Vec<(Txid, Option<BlockHash>)> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Vec::new(),
+		};
+
+		let outbound_orders = service_state.outbound_orders.lock().unwrap();
+		outbound_orders
+			.orders_by_id
+			.values()
+			.filter(|order| order.payment.state == PaymentState::ExpectPayment)
+			.filter_map(|order| {
+				let payment = order.payment.onchain_payment.as_ref()?;
+				order
+					.onchain_payment_block_hash
+					.map(|block_hash| (payment.outpoint.txid, Some(block_hash)))
+			})
+			.collect()
+	}
+
+	pub fn block_disconnected(&self, _header: &BlockHeader, height: u32) {
+		if let Some(service_state) = self.service_state.as_ref() {
+			service_state.best_block_height.store(height.saturating_sub(1), Ordering::Release);
+		}
+		self.forget_unaccepted_onchain_payments(|order| {
+			order
+				.onchain_payment_height
+				.map_or(false, |confirmation_height| confirmation_height >= height)
+		});
+	}
+
+	pub fn channel_pending(
+		&self, user_channel_id: u128, counterparty_node_id: &PublicKey, funding_txo: OutPoint,
+	) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Ok(()),
+		};
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.get_mut_by_user_channel_id(user_channel_id) {
+			Some(order) if order.awaits_channel() => order,
+			_ => return Ok(()),
+		};
+		if order.counterparty_node_id != *counterparty_node_id {
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"Channel {} is not the channel we opened to {} for an order",
+					user_channel_id, counterparty_node_id
+				),
+			});
+		}
+
+		let funded_at = LSPSDateTime::now();
+		let channel_expiry_secs =
+			u64::from(order.order.channel_expiry_blocks) * EXPECTED_BLOCK_INTERVAL_SECS;
+		order.channel = Some(ChannelInfo {
+			funded_at,
+			funding_outpoint: funding_txo,
+			expires_at: funded_at + Duration::from_secs(channel_expiry_secs),
+		});
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+		Ok(())
+	}
+
+	pub fn channel_ready(
+		&self, user_channel_id: u128, counterparty_node_id: &PublicKey,
+	) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Ok(()),
+		};
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.get_mut_by_user_channel_id(user_channel_id) {
+			Some(order) if order.awaits_channel() => order,
+			_ => return Ok(()),
+		};
+		if order.counterparty_node_id != *counterparty_node_id {
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"Channel {} is not the channel we opened to {} for an order",
+					user_channel_id, counterparty_node_id
+				),
+			});
+		}
+
+		// Orders paid on-chain have nothing left to claim.
+		let payment_preimage = if order.payment.state == PaymentState::Hold {
+			Some(
+				self.channel_manager
+					.get_payment_preimage(order.payment_hash, order.payment_secret)?,
+			)
+		} else {
+			None
+		};
+		let event = order.transition(OrderState::Completed, PaymentState::Paid)?;
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		if let Some(payment_preimage) = payment_preimage {
+			self.channel_manager.claim_funds(payment_preimage);
+		}
+		self.pending_events.enqueue(Event::LSPS1(event));
+		Ok(())
+	}
+
+	pub fn channel_closed(&self, user_channel_id: u128) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		// Once the channel was ready the order is fulfilled, otherwise the open failed and the
+		// client must not be charged.
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.get_mut_by_user_channel_id(user_channel_id) {
+			Some(order) if order.awaits_channel() => order,
+			_ => return,
+		};
+		// Held payments are failed back, while payments made on-chain have to be refunded.
+		let is_held = order.payment.state == PaymentState::Hold;
+		let payment_state = if is_held { PaymentState::Refunded } else { PaymentState::Paid };
+		let event = match order.transition(OrderState::Failed, payment_state) {
+			Ok(event) => event,
+			Err(_) => return,
+		};
+		let refund_required_event = order.refund_required_event();
+		let payment_hash = order.payment_hash;
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		if is_held {
+			self.channel_manager.fail_htlc_backwards(&payment_hash);
+		}
+		self.pending_events.enqueue(Event::LSPS1(event));
+		if let Some(event) = refund_required_event {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+	}
+
+	pub fn order_refunded(&self, order_id: &OrderId, refund_txid: Txid) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => {
+				return Err(APIError::APIMisuseError {
+					err: "We don't sell channels to clients".to_string(),
+				});
+			}
+		};
+
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		let order = match outbound_orders.orders_by_id.get_mut(order_id) {
+			Some(order) if order.refund_required_event().is_some() => order,
+			_ => {
+				return Err(APIError::APIMisuseError {
+					err: format!("Order {:?} doesn't await a refund", order_id),
+				});
+			}
+		};
+		let event = order.transition(OrderState::Failed, PaymentState::Refunded)?;
+		order.refund_txid = Some(refund_txid);
+		drop(outbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.pending_events.enqueue(Event::LSPS1(event));
+		Ok(())
+	}
+
+	pub fn pay_order(&self, order_id: &OrderId, retry_strategy: Retry) -> Result<(), APIError> {
+		let invoice = {
+			let inbound_orders = self.inbound_orders.lock().unwrap();
+			let order = inbound_orders.get(order_id).ok_or_else(|| APIError::APIMisuseError {
+				err: format!("We didn't place an order {:?}", order_id),
+			})?;
+
+			if order.order_state != OrderState::Created
+				|| order.payment.state != PaymentState::ExpectPayment
+			{
+				return Err(APIError::APIMisuseError {
+					err: format!("Order {:?} doesn't expect a payment", order_id),
+				});
+			}
+
+			match self.order_invoice(&order.counterparty_node_id, &order.payment) {
+				Some(invoice) if invoice.payment_hash().into_inner() == order.payment_hash.0 => {
+					invoice
+				}
+				_ => {
+					return Err(APIError::APIMisuseError {
+						err: format!("The invoice of order {:?} doesn't match it", order_id),
+					});
+				}
+			}
+		};
+
+		if invoice.is_expired() {
+			return Err(APIError::APIMisuseError {
+				err: format!("The invoice of order {:?} expired", order_id),
+			});
+		}
+
+		let payment_id = PaymentId(invoice.payment_hash().into_inner());
+		self.channel_manager.pay_invoice(&invoice, payment_id, retry_strategy).map_err(|e| {
+			APIError::APIMisuseError {
+				err: format!("Failed to pay the invoice of order {:?}: {:?}", order_id, e),
+			}
+		})
+	}
+
+	pub fn payment_sent(&self, payment_hash: PaymentHash) {
+		if let Some(order) = self.inbound_order_by_payment_hash(&payment_hash) {
+			self.pending_events.enqueue(Event::LSPS1(LSPS1Event::OrderPaymentSent {
+				order_id: order.order_id,
+				user_channel_id: order.user_channel_id,
+				counterparty_node_id: order.counterparty_node_id,
+				payment_hash,
+			}));
+		}
+	}
+
+	pub fn payment_failed(&self, payment_hash: PaymentHash) {
+		if let Some(order) = self.inbound_order_by_payment_hash(&payment_hash) {
+			self.pending_events.enqueue(Event::LSPS1(LSPS1Event::OrderPaymentFailed {
+				order_id: order.order_id,
+				user_channel_id: order.user_channel_id,
+				counterparty_node_id: order.counterparty_node_id,
+				payment_hash,
+			}));
+		}
+	}
+
+	pub fn timer_tick_occurred(&self) {
+		// Orders fail once they, or the invoices to pay them, expired unpaid.
+		let mut expired_events = Vec::new();
+		let mut is_updated = false;
+		if let Some(service_state) = self.service_state.as_ref() {
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			for order in outbound_orders.orders_by_id.values_mut() {
+				if order.awaits_payment() && order.expires_at.is_past() {
+					expired_events
+						.extend(order.transition(OrderState::Failed, PaymentState::ExpectPayment));
+				}
+			}
+
+			// Finished orders are forgotten once we retained them for long enough.
+			let retention = Duration::from_secs(service_state.config.order_retention_secs.into());
+			let mut forgotten_order_ids = Vec::new();
+			for order in outbound_orders.orders_by_id.values_mut() {
+				if !order.is_finished() {
+					continue;
+				}
+				match order.finished_at {
+					None => {
+						order.finished_at = Some(LSPSDateTime::now());
+						is_updated = true;
+					}
+					Some(finished_at) if (finished_at + retention).is_past() => {
+						forgotten_order_ids.push(order.order_id.clone());
+					}
+					Some(_) => {}
+				}
+			}
+			for order_id in forgotten_order_ids {
+				outbound_orders.remove(&order_id);
+				is_updated = true;
+			}
+		}
+		{
+			let mut inbound_orders = self.inbound_orders.lock().unwrap();
+			for order in inbound_orders.values_mut() {
+				if order.order_state == OrderState::Created
+					&& order.payment.state == PaymentState::ExpectPayment
+					&& order.payment.onchain_payment.is_none()
+					&& order.is_expired()
+				{
+					expired_events
+						.extend(order.transition(OrderState::Failed, PaymentState::ExpectPayment));
+				}
+			}
+		}
+		if is_updated || !expired_events.is_empty() {
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+		for event in expired_events {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+
+		let mut stale_requests = Vec::new();
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			for (counterparty_node_id, peer_state) in per_peer_state.iter_mut() {
+				let requests = &mut peer_state.inbound_requests_by_request_id;
+				let stale_request_ids: Vec<RequestId> = requests
+					.iter_mut()
+					.filter_map(|(request_id, request)| {
+						request.ticks_elapsed += 1;
+						if request.ticks_elapsed >= REQUEST_TIMEOUT_TICKS {
+							Some(request_id.clone())
+						} else {
+							None
+						}
+					})
+					.collect();
+				for request_id in stale_request_ids {
+					if let Some(request) = requests.remove(&request_id) {
+						stale_requests.push((*counterparty_node_id, request));
+					}
+				}
+			}
+			per_peer_state
+				.retain(|_, peer_state| !peer_state.inbound_requests_by_request_id.is_empty());
+		}
+
+		for (counterparty_node_id, request) in stale_requests {
+			// A status request can simply be repeated, so only failed orders are reported.
+			if let InboundOrderRequestState::StatusRequested { .. } = request.state {
+				continue;
+			}
+			self.fail_order_creation(
+				&counterparty_node_id,
+				request.user_channel_id,
+				OrderCreationFailureReason::Timeout,
+			);
+		}
+	}
+
+	pub fn get_and_clear_needs_persistence(&self) -> bool {
+		self.needs_persistence.swap(false, Ordering::AcqRel)
+	}
+
+	pub fn read_state<R: io::Read>(&self, reader: &mut R) -> Result<(), DecodeError> {
+		// Data persisted before channel orders were supported ends before our state.
+		let version: u8 = match Readable::read(reader) {
+			Ok(version) => version,
+			Err(DecodeError::ShortRead) => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		if version > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+
+		let num_outbound_orders: u64 = Readable::read(reader)?;
+		let mut outbound_orders = Vec::new();
+		for _ in 0..num_outbound_orders {
+			let order: OutboundChannelOrder = Readable::read(reader)?;
+			outbound_orders.push(order);
+		}
+
+		let num_inbound_orders: u64 = Readable::read(reader)?;
+		let mut inbound_orders = self.inbound_orders.lock().unwrap();
+		for _ in 0..num_inbound_orders {
+			let order: InboundChannelOrder = Readable::read(reader)?;
+			inbound_orders.insert(order.order_id.clone(), order);
+		}
+
+		// If we are no longer configured as a service we have no use for the orders we took.
+		if let Some(service_state) = self.service_state.as_ref() {
+			let mut orders = service_state.outbound_orders.lock().unwrap();
+			for order in outbound_orders {
+				orders.insert(order);
+			}
+		}
+		Ok(())
+	}
+
+	/// Opens the channel for an order that was paid for, failing the order if that's impossible.
+	fn open_order_channel(
+		&self, service_state: &ServiceState<CP, OW, RS>, order: &OutboundChannelOrder,
+	) {
+		let channel_value_sat = order.order.lsp_balance_sat + order.order.client_balance_sat;
+		let push_msat = order.order.client_balance_sat * 1000;
+		let mut channel_config = service_state.config.channel_config.clone();
+		channel_config.channel_handshake_config.announced_channel = order.order.announce_channel;
+
+		if self
+			.channel_manager
+			.create_channel(
+				order.counterparty_node_id,
+				channel_value_sat,
+				push_msat,
+				order.user_channel_id,
+				Some(channel_config),
+			)
+			.is_err()
+		{
+			self.channel_closed(order.user_channel_id);
+		}
+	}
+
+	/// Tracks the leases of the channels opened for orders against the given best block height.
+	///
+	/// A lease starts once the funding transaction of its channel confirmed, which we learn from
+	/// the [`ChannelManager`]. We learn when the leases of the channels we sold expired, and are
+	/// warned ahead of time about the leases of the channels we bought.
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	fn update_channel_leases(&self, height: u32) {
+		let mut channels = None;
+		let mut events = Vec::new();
+		let mut is_updated = false;
+
+		if let Some(service_state) = self.service_state.as_ref() {
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			for order in outbound_orders.orders_by_id.values_mut() {
+				if order.order_state != OrderState::Completed {
+					continue;
+				}
+				if order.channel_expiry_height.is_none() {
+					let channels = channels.get_or_insert_with(|| {
+						order_channels(&self.channel_manager.list_channels())
+					});
+					order.channel_expiry_height = channel_expiry_height(
+						channels,
+						order.channel.as_ref(),
+						&order.order,
+						height,
+					);
+					is_updated |= order.channel_expiry_height.is_some();
+				}
+				match order.channel_expiry_height {
+					Some(expiry_height)
+						if expiry_height <= height && !order.channel_expiry_reported =>
+					{
+						order.channel_expiry_reported = true;
+						is_updated = true;
+						events.push(LSPS1Event::ChannelLeaseExpired {
+							order_id: order.order_id.clone(),
+							user_channel_id: order.user_channel_id,
+							counterparty_node_id: order.counterparty_node_id,
+							channel_expiry_height: expiry_height,
+						});
+					}
+					_ => {}
+				}
+			}
+		}
+
+		{
+			let mut inbound_orders = self.inbound_orders.lock().unwrap();
+			for order in inbound_orders.values_mut() {
+				if order.order_state != OrderState::Completed {
+					continue;
+				}
+				if order.channel_expiry_height.is_none() {
+					let channels = channels.get_or_insert_with(|| {
+						order_channels(&self.channel_manager.list_channels())
+					});
+					order.channel_expiry_height = channel_expiry_height(
+						channels,
+						order.channel.as_ref(),
+						&order.order,
+						height,
+					);
+					is_updated |= order.channel_expiry_height.is_some();
+				}
+				match order.channel_expiry_height {
+					Some(expiry_height)
+						if expiry_height.saturating_sub(self.channel_expiry_warning_blocks)
+							<= height && !order.channel_expiry_warned =>
+					{
+						order.channel_expiry_warned = true;
+						is_updated = true;
+						events.push(LSPS1Event::ChannelLeaseExpiring {
+							order_id: order.order_id.clone(),
+							user_channel_id: order.user_channel_id,
+							counterparty_node_id: order.counterparty_node_id,
+							channel_expiry_height: expiry_height,
+						});
+					}
+					_ => {}
+				}
+			}
+		}
+
+		if is_updated {
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+		for event in events {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+	}
+
+	/// Forgets the on-chain payments we didn't accept yet whose transactions got reorged out.
+	fn forget_unaccepted_onchain_payments<F: Fn(&OutboundChannelOrder) -> bool>(
+		&self, is_reorged_out: F,
+	) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		// Payments we accepted are final, as the channel may be open already.
+		let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+		for order in outbound_orders.orders_by_id.values_mut() {
+			if order.payment.state == PaymentState::ExpectPayment && is_reorged_out(order) {
+				order.payment.onchain_payment = None;
+				order.onchain_payment_height = None;
+				order.onchain_payment_block_hash = None;
+				self.needs_persistence.store(true, Ordering::Release);
+			}
+		}
+	}
+
+	/// Returns a fresh address to pay an order with the given total to on-chain, if we accept
+	/// on-chain payments for it.
+	fn new_order_address(
+		&self, config: &ChannelRequestsConfig<CP, OW, RS>, order_id: &OrderId, order_total_sat: u64,
+	) -> Option<Address> {
+		let wallet = config.wallet.as_ref()?;
+		config.options.minimum_onchain_payment_confirmations?;
+		if config.options.min_onchain_payment_size_sat.map_or(false, |min| order_total_sat < min) {
+			return None;
+		}
+		wallet.new_order_address(order_id).filter(|address| address.network == self.network)
+	}
+
+	fn generate_order_id(&self) -> OrderId {
+		let bytes = self.entropy_source.get_secure_random_bytes();
+		OrderId(utils::hex_str(&bytes[0..16]))
+	}
+
+	fn generate_user_channel_id(&self) -> u128 {
+		let random_bytes = self.entropy_source.get_secure_random_bytes();
+		let mut user_channel_id = [0; 16];
+		user_channel_id.copy_from_slice(&random_bytes[..16]);
+		u128::from_be_bytes(user_channel_id)
+	}
+
+	fn create_order_invoice(
+		&self, order_id: &OrderId, order_total_sat: u64, expiry_secs: u32,
+	) -> Result<(Bolt11Invoice, PaymentSecret), String> {
+		let order_total_msat = order_total_sat.saturating_mul(1000);
+		let (payment_hash, payment_secret) = self
+			.channel_manager
+			.create_inbound_payment(
+				Some(order_total_msat),
+				expiry_secs,
+				Some(ORDER_INVOICE_MIN_FINAL_CLTV_EXPIRY_DELTA),
+			)
+			.ok_or_else(|| "failed to register inbound payment".to_string())?;
+
+		let payment_hash = sha256::Hash::from_slice(&payment_hash.0)
+			.map_err(|_| "invalid payment hash".to_string())?;
+
+		let raw_invoice = InvoiceBuilder::new(Currency::from(self.network))
+			.description(format!("Channel order {}", order_id.0))
+			.payment_hash(payment_hash)
+			.payment_secret(payment_secret)
+			.current_timestamp()
+			.min_final_cltv_expiry_delta(ORDER_INVOICE_MIN_FINAL_CLTV_EXPIRY_DELTA.into())
+			.expiry_time(Duration::from_secs(expiry_secs.into()))
+			.amount_milli_satoshis(order_total_msat)
+			.build_raw()
+			.map_err(|e| format!("{:?}", e))?;
+		let hrp_str = raw_invoice.hrp.to_string();
+		let hrp_bytes = hrp_str.as_bytes();
+		let data_without_signature = raw_invoice.data.to_base32();
+		let signed_raw_invoice = raw_invoice
+			.sign(|_| {
+				self.node_signer.sign_invoice(hrp_bytes, &data_without_signature, Recipient::Node)
+			})
+			.map_err(|()| "failed to sign invoice".to_string())?;
+
+		let invoice =
+			Bolt11Invoice::from_signed(signed_raw_invoice).map_err(|e| format!("{:?}", e))?;
+		Ok((invoice, payment_secret))
+	}
+
+	/// Whether the given receipt covers the terms of the given order, and was signed by the LSP
+	/// for us.
+	fn is_valid_receipt(
+		&self, receipt: &OrderReceipt, counterparty_node_id: &PublicKey, response: &OrderResponse,
+	) -> bool {
+		let node_id = match self.node_signer.get_node_id(Recipient::Node) {
+			Ok(node_id) => node_id,
+			Err(()) => return false,
+		};
+		receipt.order_id == response.order_id
+			&& receipt.order == response.order
+			&& receipt.fee_total_sat == response.payment.fee_total_sat
+			&& receipt.order_total_sat == response.payment.order_total_sat
+			&& receipt.expires_at == response.expires_at
+			&& receipt.verify(counterparty_node_id, &node_id)
+	}
+
+	fn inbound_order_by_payment_hash(
+		&self, payment_hash: &PaymentHash,
+	) -> Option<InboundChannelOrder> {
+		let inbound_orders = self.inbound_orders.lock().unwrap();
+		inbound_orders.values().find(|order| order.payment_hash == *payment_hash).cloned()
+	}
+
+	/// Decodes the invoice of an order placed with the given LSP, if it pays the order total to
+	/// the LSP on our network.
+	fn order_invoice(
+		&self, counterparty_node_id: &PublicKey, payment: &PaymentInfo,
+	) -> Option<Bolt11Invoice> {
+		let invoice = Bolt11Invoice::from_str(&payment.bolt11_invoice).ok()?;
+		let order_total_msat = payment.order_total_sat.checked_mul(1000)?;
+		if invoice.currency() != Currency::from(self.network)
+			|| invoice.amount_milli_satoshis() != Some(order_total_msat)
+			|| invoice.recover_payee_pub_key() != *counterparty_node_id
+		{
+			return None;
+		}
+		Some(invoice)
+	}
+
+	/// Whether the on-chain address of an order, if any, is an address on our network.
+	fn is_valid_onchain_address(&self, payment: &PaymentInfo) -> bool {
+		payment.onchain_address.as_ref().map_or(true, |address| self.is_valid_address(address))
+	}
+
+	fn is_valid_address(&self, address: &str) -> bool {
+		Address::from_str(address)
+			.map_or(false, |address| address.is_valid_for_network(self.network))
+	}
+
+	fn enqueue_request(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, request: LSPS1Request,
+	) {
+		let msg: LSPSMessage = LSPS1Message::Request(request_id, request).into();
+		self.pending_messages.lock().unwrap().push((counterparty_node_id, msg));
+	}
+
+	fn enqueue_response(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, response: LSPS1Response,
+	) {
+		let msg: LSPSMessage = LSPS1Message::Response(request_id, response).into();
+		self.pending_messages.lock().unwrap().push((counterparty_node_id, msg));
+	}
+
+	fn send_inbound_request(
+		&self, counterparty_node_id: &PublicKey, request: InboundOrderRequest,
+		message: LSPS1Request,
+	) {
+		let request_id = utils::generate_request_id(&self.entropy_source);
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let peer_state =
+				per_peer_state.entry(*counterparty_node_id).or_insert_with(PeerState::new);
+			peer_state.inbound_requests_by_request_id.insert(request_id.clone(), request);
+		}
+
+		self.enqueue_request(*counterparty_node_id, request_id, message);
+	}
+
+	fn remove_inbound_request(
+		&self, request_id: &RequestId, counterparty_node_id: &PublicKey,
+	) -> Result<InboundOrderRequest, LightningError> {
+		let mut per_peer_state = self.per_peer_state.lock().unwrap();
+		per_peer_state
+			.get_mut(counterparty_node_id)
+			.and_then(|peer_state| peer_state.inbound_requests_by_request_id.remove(request_id))
+			.ok_or_else(|| LightningError {
+				err: format!(
+					"Received LSPS1 response from {} for unknown request {:?}",
+					counterparty_node_id, request_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			})
+	}
+
+	fn fail_order_creation(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+		reason: OrderCreationFailureReason,
+	) {
+		self.pending_events.enqueue(Event::LSPS1(LSPS1Event::OrderCreationFailed {
+			user_channel_id,
+			counterparty_node_id: *counterparty_node_id,
+			reason,
+		}));
+	}
+
+	fn handle_get_info_request(
+		&self, service_state: &ServiceState<CP, OW, RS>, request_id: RequestId,
+		counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS1Response::GetInfo(GetInfoResponse {
+				supported_versions: SUPPORTED_SPEC_VERSIONS.to_vec(),
+				website: service_state.config.website.clone(),
+				options: service_state.config.options.clone(),
+			}),
+		);
+		Ok(())
+	}
+
+	fn handle_create_order_request(
+		&self, service_state: &ServiceState<CP, OW, RS>, request_id: RequestId,
+		counterparty_node_id: &PublicKey, params: CreateOrderRequest,
+	) -> Result<(), LightningError> {
+		if !SUPPORTED_SPEC_VERSIONS.contains(&params.api_version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS1Response::CreateOrderError(ResponseError {
+					code: LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE,
+					message: "unsupported_version".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("client requested unsupported version {}", params.api_version),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let mismatched_property =
+			service_state.config.options.mismatched_property(&params.order).or_else(|| {
+				params
+					.refund_onchain_address
+					.as_ref()
+					.filter(|address| !self.is_valid_address(address))
+					.map(|_| "refund_onchain_address")
+			});
+		if let Some(property) = mismatched_property {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS1Response::CreateOrderError(ResponseError {
+					code: LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+					message: "option_mismatch".to_string(),
+					data: Some(json!({ "property": property })),
+				}),
+			);
+			return Err(LightningError {
+				err: format!(
+					"client {} ordered a channel with an unsupported {}",
+					counterparty_node_id, property
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let unpaid_orders = {
+			let outbound_orders = service_state.outbound_orders.lock().unwrap();
+			outbound_orders
+				.orders_by_id
+				.values()
+				.filter(|order| order.counterparty_node_id == *counterparty_node_id)
+				.filter(|order| order.awaits_payment())
+				.count()
+		};
+		if unpaid_orders >= service_state.config.max_unpaid_orders_per_client {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS1Response::CreateOrderError(ResponseError {
+					code: JSONRPC_INTERNAL_ERROR_ERROR_CODE,
+					message: "too_many_unpaid_orders".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!(
+					"client {} already has {} unpaid orders",
+					counterparty_node_id, unpaid_orders
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let pricing = &service_state.config.pricing;
+		let fee_total_sat = pricing.fee_total_sat(&params.order);
+		let fee_total_sat = match params.token.as_deref() {
+			Some(token) => pricing.fee_total_sat_with_token(
+				counterparty_node_id,
+				&params.order,
+				token,
+				fee_total_sat,
+			),
+			None => Some(fee_total_sat),
+		};
+		let fee_total_sat = match fee_total_sat {
+			Some(fee_total_sat) => fee_total_sat,
+			None => {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS1Response::CreateOrderError(ResponseError {
+						code: LSPS1_CREATE_ORDER_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+						message: "unrecognized_or_stale_token".to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: format!(
+						"client {} provided an unrecognized or stale token",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let order_id = self.generate_order_id();
+		let order_total_sat = fee_total_sat.saturating_add(params.order.client_balance_sat);
+		let order_expiry_secs = service_state.config.order_expiry_secs;
+		let (invoice, payment_secret) =
+			match self.create_order_invoice(&order_id, order_total_sat, order_expiry_secs) {
+				Ok(invoice) => invoice,
+				Err(e) => {
+					self.enqueue_response(
+						*counterparty_node_id,
+						request_id,
+						LSPS1Response::CreateOrderError(ResponseError {
+							code: JSONRPC_INTERNAL_ERROR_ERROR_CODE,
+							message: "internal_error".to_string(),
+							data: None,
+						}),
+					);
+					return Err(LightningError {
+						err: format!(
+							"failed to create an invoice for an order of {}: {}",
+							counterparty_node_id, e
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Error),
+					});
+				}
+			};
+
+		let config = &service_state.config;
+		let onchain_address = self.new_order_address(config, &order_id, order_total_sat);
+		let payment = PaymentInfo {
+			state: PaymentState::ExpectPayment,
+			fee_total_sat,
+			order_total_sat,
+			bolt11_invoice: invoice.to_string(),
+			min_onchain_payment_confirmations: onchain_address
+				.as_ref()
+				.and(config.options.minimum_onchain_payment_confirmations),
+			min_fee_for_0conf: onchain_address.as_ref().map(|_| config.min_fee_for_0conf),
+			onchain_address: onchain_address.map(|address| address.to_string()),
+			onchain_payment: None,
+		};
+		let created_at = LSPSDateTime::now();
+		let mut order = OutboundChannelOrder {
+			order_id,
+			counterparty_node_id: *counterparty_node_id,
+			order: params.order,
+			created_at,
+			expires_at: created_at + Duration::from_secs(order_expiry_secs.into()),
+			order_state: OrderState::Created,
+			payment,
+			channel: None,
+			user_channel_id: self.generate_user_channel_id(),
+			payment_hash: PaymentHash(invoice.payment_hash().into_inner()),
+			token: params.token,
+			refund_onchain_address: params.refund_onchain_address,
+			refund_txid: None,
+			channel_expiry_height: None,
+			receipt: None,
+			payment_secret,
+			onchain_payment_height: None,
+			onchain_payment_block_hash: None,
+			channel_expiry_reported: false,
+			finished_at: None,
+			hold_height: None,
+		};
+		if let Some(receipt_signer) = service_state.config.receipt_signer.as_ref() {
+			let response = order.to_response();
+			let receipt = create_order_receipt(&**receipt_signer, counterparty_node_id, &response);
+			if receipt.is_none() {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS1Response::CreateOrderError(ResponseError {
+						code: JSONRPC_INTERNAL_ERROR_ERROR_CODE,
+						message: "internal_error".to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: format!(
+						"failed to sign the receipt of an order of {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Error),
+				});
+			}
+			order.receipt = receipt;
+		}
+		let response = order.to_response();
+
+		service_state.outbound_orders.lock().unwrap().insert(order);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS1Response::CreateOrder(response),
+		);
+		Ok(())
+	}
+
+	fn handle_get_order_request(
+		&self, service_state: &ServiceState<CP, OW, RS>, request_id: RequestId,
+		counterparty_node_id: &PublicKey, params: GetOrderRequest,
+	) -> Result<(), LightningError> {
+		let response = service_state
+			.outbound_orders
+			.lock()
+			.unwrap()
+			.orders_by_id
+			.get(&params.order_id)
+			.filter(|order| order.counterparty_node_id == *counterparty_node_id)
+			.map(|order| order.to_response());
+
+		match response {
+			Some(response) => {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS1Response::GetOrder(response),
+				);
+				Ok(())
+			}
+			None => {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS1Response::GetOrderError(ResponseError {
+						code: LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE,
+						message: "not_found".to_string(),
+						data: None,
+					}),
+				);
+				Err(LightningError {
+					err: format!(
+						"client {} requested unknown order {:?}",
+						counterparty_node_id, params.order_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				})
+			}
+		}
+	}
+
+	fn handle_get_info_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, response: GetInfoResponse,
+	) -> Result<(), LightningError> {
+		let mut request = self.remove_inbound_request(&request_id, counterparty_node_id)?;
+
+		match request.state {
+			InboundOrderRequestState::OptionsRequested => {}
+			_ => {
+				return Err(LightningError {
+					err: format!(
+						"Received unexpected lsps1.get_info response from {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		}
+
+		let version = match response
+			.supported_versions
+			.iter()
+			.filter(|version| SUPPORTED_SPEC_VERSIONS.contains(version))
+			.max()
+		{
+			Some(version) => *version,
+			None => {
+				self.fail_order_creation(
+					counterparty_node_id,
+					request.user_channel_id,
+					OrderCreationFailureReason::NoCommonVersion,
+				);
+				return Err(LightningError {
+					err: format!(
+						"LSP {} does not support any of our LSPS1 versions: {:?}",
+						counterparty_node_id, response.supported_versions
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let event = LSPS1Event::SupportedOptionsReady {
+			user_channel_id: request.user_channel_id,
+			counterparty_node_id: *counterparty_node_id,
+			website: response.website,
+			options: response.options.clone(),
+		};
+
+		// Nothing is requested until the user placed an order, so we keep the request under the
+		// request id of the get_info request.
+		request.state =
+			InboundOrderRequestState::OptionsReceived { version, options: response.options };
+		request.ticks_elapsed = 0;
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let peer_state =
+				per_peer_state.entry(*counterparty_node_id).or_insert_with(PeerState::new);
+			peer_state.inbound_requests_by_request_id.insert(request_id, request);
+		}
+
+		self.pending_events.enqueue(Event::LSPS1(event));
+		Ok(())
+	}
+
+	fn handle_create_order_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, response: OrderResponse,
+	) -> Result<(), LightningError> {
+		let request = self.remove_inbound_request(&request_id, counterparty_node_id)?;
+
+		let (order, refund_onchain_address) = match request.state {
+			InboundOrderRequestState::OrderRequested { order, refund_onchain_address } => {
+				(order, refund_onchain_address)
+			}
+			_ => {
+				return Err(LightningError {
+					err: format!(
+						"Received unexpected lsps1.create_order response from {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let invoice = self.order_invoice(counterparty_node_id, &response.payment);
+		let mut inbound_orders = self.inbound_orders.lock().unwrap();
+		let is_valid = response.order == order
+			&& response.payment.order_total_sat
+				== response.payment.fee_total_sat.saturating_add(order.client_balance_sat)
+			&& invoice.is_some()
+			&& self.is_valid_onchain_address(&response.payment)
+			&& response.receipt.as_ref().map_or(true, |receipt| {
+				self.is_valid_receipt(receipt, counterparty_node_id, &response)
+			}) && !inbound_orders.contains_key(&response.order_id);
+		let payment_hash = match invoice {
+			Some(invoice) if is_valid => PaymentHash(invoice.payment_hash().into_inner()),
+			_ => {
+				drop(inbound_orders);
+				self.fail_order_creation(
+					counterparty_node_id,
+					request.user_channel_id,
+					OrderCreationFailureReason::InvalidOrder,
+				);
+				return Err(LightningError {
+					err: format!(
+						"LSP {} created an order {:?} that doesn't match the one we placed",
+						counterparty_node_id, response.order_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let order = InboundChannelOrder::new(
+			*counterparty_node_id,
+			request.user_channel_id,
+			response,
+			payment_hash,
+			refund_onchain_address,
+		);
+		inbound_orders.insert(order.order_id.clone(), order.clone());
+		drop(inbound_orders);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.pending_events
+			.enqueue(Event::LSPS1(LSPS1Event::OrderCreated { order: Box::new(order) }));
+		Ok(())
+	}
+
+	fn handle_get_order_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, response: OrderResponse,
+	) -> Result<(), LightningError> {
+		let request = self.remove_inbound_request(&request_id, counterparty_node_id)?;
+
+		let order_id = match request.state {
+			InboundOrderRequestState::StatusRequested { order_id }
+				if order_id == response.order_id =>
+			{
+				order_id
+			}
+			_ => {
+				return Err(LightningError {
+					err: format!(
+						"Received unexpected lsps1.get_order response from {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let (order, state_changed_event, is_completed) = {
+			let mut inbound_orders = self.inbound_orders.lock().unwrap();
+			let order = match inbound_orders.get_mut(&order_id) {
+				Some(order) => order,
+				None => return Ok(()),
+			};
+
+			// The LSP must not change what we pay for, nor the invoice we might have paid.
+			let invoice = self.order_invoice(counterparty_node_id, &response.payment);
+			let is_unchanged = response.order == order.order
+				&& response.payment.order_total_sat == order.payment.order_total_sat
+				&& response.payment.onchain_address == order.payment.onchain_address
+				&& invoice.map_or(false, |invoice| {
+					invoice.payment_hash().into_inner() == order.payment_hash.0
+				});
+			if !is_unchanged {
+				return Err(LightningError {
+					err: format!(
+						"LSP {} changed the terms of order {:?}",
+						counterparty_node_id, order_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+
+			let is_completed = response.order_state == OrderState::Completed
+				&& order.order_state != OrderState::Completed;
+			let state_changed_event = if response.order_state != order.order_state
+				|| response.payment.state != order.payment.state
+			{
+				let event = order
+					.transition(response.order_state, response.payment.state)
+					.map_err(|e| LightningError {
+						err: format!(
+							"LSP {} reported an invalid update: {:?}",
+							counterparty_node_id, e
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+				Some(event)
+			} else {
+				None
+			};
+
+			order.update(response);
+			(order.clone(), state_changed_event, is_completed)
+		};
+		self.needs_persistence.store(true, Ordering::Release);
+
+		if let Some(event) = state_changed_event {
+			self.pending_events.enqueue(Event::LSPS1(event));
+		}
+		// Once the LSP reports to have opened the channel, it has to be among ours.
+		if is_completed
+			&& !order.is_delivered(&order_channels(&self.channel_manager.list_channels()))
+		{
+			self.pending_events.enqueue(Event::LSPS1(LSPS1Event::ChannelDeliveryDisputed {
+				order_id: order.order_id.clone(),
+				user_channel_id: order.user_channel_id,
+				counterparty_node_id: order.counterparty_node_id,
+				funding_outpoint: order.channel.as_ref().map(|channel| channel.funding_outpoint),
+			}));
+		}
+		self.pending_events
+			.enqueue(Event::LSPS1(LSPS1Event::OrderStatusReady { order: Box::new(order) }));
+		Ok(())
+	}
+
+	fn handle_error_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, method: &str,
+		error: ResponseError,
+	) -> Result<(), LightningError> {
+		let request = self.remove_inbound_request(&request_id, counterparty_node_id)?;
+
+		if let InboundOrderRequestState::OptionsRequested
+		| InboundOrderRequestState::OrderRequested { .. } = request.state
+		{
+			let property = error
+				.data
+				.as_ref()
+				.and_then(|data| data.get("property"))
+				.and_then(|property| property.as_str());
+			let reason = match property {
+				Some(property)
+					if error.code == LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE =>
+				{
+					OrderCreationFailureReason::OptionMismatch { property: property.to_string() }
+				}
+				_ => OrderCreationFailureReason::ErrorResponse {
+					code: error.code,
+					message: error.message.clone(),
+				},
+			};
+			self.fail_order_creation(counterparty_node_id, request.user_channel_id, reason);
+		}
+
+		Err(LightningError {
+			err: format!(
+				"{} error received from {}. code = {}, message = {}, data = {:?}",
+				method, counterparty_node_id, error.code, error.message, error.data
+			),
+			action: ErrorAction::IgnoreAndLog(Level::Info),
+		})
+	}
+}
+
+fn no_options_error(counterparty_node_id: &PublicKey, user_channel_id: u128) -> APIError {
+	APIError::APIMisuseError {
+		err: format!(
+			"No options from {} are awaiting an order for channel {}",
+			counterparty_node_id, user_channel_id
+		),
+	}
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, CP: Deref, OW: Deref, RS: Deref> Writeable
+	for LSPS1MessageHandler<ES, CM, NS, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let outbound_orders: Vec<OutboundChannelOrder> = match self.service_state.as_ref() {
+			Some(service_state) => {
+				let outbound_orders = service_state.outbound_orders.lock().unwrap();
+				outbound_orders.orders_by_id.values().cloned().collect()
+			}
+			None => Vec::new(),
+		};
+
+		SERIALIZATION_VERSION.write(writer)?;
+		(outbound_orders.len() as u64).write(writer)?;
+		for order in outbound_orders {
+			order.write(writer)?;
+		}
+
+		let inbound_orders = self.inbound_orders.lock().unwrap();
+		(inbound_orders.len() as u64).write(writer)?;
+		for order in inbound_orders.values() {
+			order.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, CP: Deref, OW: Deref, RS: Deref> ProtocolMessageHandler
+	for LSPS1MessageHandler<ES, CM, NS, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	type ProtocolMessage = LSPS1Message;
+	const PROTOCOL_NUMBER: Option<u16> = Some(1);
+
+	fn handle_message(
+		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		match message {
+			LSPS1Message::Request(request_id, request) => {
+				let service_state = self.service_state.as_ref().ok_or_else(|| LightningError {
+					err: format!(
+						"Received LSPS1 request from {} but we are not configured as an LSPS1 service",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				})?;
+
+				match request {
+					LSPS1Request::GetInfo(_) => self.handle_get_info_request(
+						service_state,
+						request_id,
+						counterparty_node_id,
+					),
+					LSPS1Request::CreateOrder(params) => self.handle_create_order_request(
+						service_state,
+						request_id,
+						counterparty_node_id,
+						params,
+					),
+					LSPS1Request::GetOrder(params) => self.handle_get_order_request(
+						service_state,
+						request_id,
+						counterparty_node_id,
+						params,
+					),
+				}
+			}
+			LSPS1Message::Response(request_id, response) => match response {
+				LSPS1Response::GetInfo(response) => {
+					self.handle_get_info_response(request_id, counterparty_node_id, response)
+				}
+				LSPS1Response::CreateOrder(response) => {
+					self.handle_create_order_response(request_id, counterparty_node_id, response)
+				}
+				LSPS1Response::GetOrder(response) => {
+					self.handle_get_order_response(request_id, counterparty_node_id, response)
+				}
+				LSPS1Response::GetInfoError(error) => {
+					self.handle_error_response(request_id, counterparty_node_id, "GetInfo", error)
+				}
+				LSPS1Response::CreateOrderError(error) => self.handle_error_response(
+					request_id,
+					counterparty_node_id,
+					"CreateOrder",
+					error,
+				),
+				LSPS1Response::GetOrderError(error) => {
+					self.handle_error_response(request_id, counterparty_node_id, "GetOrder", error)
+				}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils;
+	use bitcoin::secp256k1::ecdsa::RecoverableSignature;
+	use bitcoin::secp256k1::{Message, Secp256k1};
+	use bitcoin::{PackedLockTime, TxMerkleNode, TxOut, Txid};
+	use lightning::ln::channelmanager::InterceptId;
+	use lightning::ln::PaymentPreimage;
+	use lightning::sign::KeysManager;
+	use lightning_invoice::payment::PaymentError;
+
+	#[derive(Default)]
+	struct TestChannelManager {
+		created_channels: Mutex<Vec<(PublicKey, u64, u64, u128)>>,
+		claimed_payments: Mutex<Vec<PaymentPreimage>>,
+		failed_payments: Mutex<Vec<PaymentHash>>,
+		channel_configs: Mutex<Vec<UserConfig>>,
+		paid_invoices: Mutex<Vec<(Bolt11Invoice, PaymentId)>>,
+	}
+
+	impl ChannelManagerInterface for TestChannelManager {
+		fn create_inbound_payment(
+			&self, _min_value_msat: Option<u64>, _invoice_expiry_delta_secs: u32,
+			_min_final_cltv_expiry_delta: Option<u16>,
+		) -> Option<(PaymentHash, PaymentSecret)> {
+			Some((PaymentHash([1; 32]), PaymentSecret([2; 32])))
+		}
+
+		fn get_payment_preimage(
+			&self, payment_hash: PaymentHash, payment_secret: PaymentSecret,
+		) -> Result<PaymentPreimage, APIError> {
+			assert_eq!(payment_hash, PaymentHash([1; 32]));
+			assert_eq!(payment_secret, PaymentSecret([2; 32]));
+			Ok(PaymentPreimage([3; 32]))
+		}
+
+		fn claim_funds(&self, payment_preimage: PaymentPreimage) {
+			self.claimed_payments.lock().unwrap().push(payment_preimage);
+		}
+
+		fn get_intercept_scid(&self) -> u64 {
+			unimplemented!()
+		}
+
+		fn create_channel(
+			&self, their_network_key: PublicKey, channel_value_satoshis: u64, push_msat: u64,
+			user_channel_id: u128, override_config: Option<UserConfig>,
+		) -> Result<[u8; 32], APIError> {
+			self.channel_configs.lock().unwrap().push(override_config.unwrap());
+			self.created_channels.lock().unwrap().push((
+				their_network_key,
+				channel_value_satoshis,
+				push_msat,
+				user_channel_id,
+			));
+			Ok([4; 32])
+		}
+
+		fn forward_intercepted_htlc(
+			&self, _intercept_id: InterceptId, _next_hop_channel_id: &[u8; 32],
+			_next_node_id: PublicKey, _amt_to_forward_msat: u64,
+		) -> Result<(), APIError> {
+			unimplemented!()
+		}
+
+		fn fail_intercepted_htlc(&self, _intercept_id: InterceptId) -> Result<(), APIError> {
+			unimplemented!()
+		}
+
+		fn list_channels(&self) -> Vec<ChannelDetails> {
+			Vec::new()
+		}
+
+		fn pay_invoice(
+			&self, invoice: &Bolt11Invoice, payment_id: PaymentId, _retry_strategy: Retry,
+		) -> Result<(), PaymentError> {
+			self.paid_invoices.lock().unwrap().push((invoice.clone(), payment_id));
+			Ok(())
+		}
+
+		fn fail_htlc_backwards(&self, payment_hash: &PaymentHash) {
+			self.failed_payments.lock().unwrap().push(*payment_hash);
+		}
+
+		fn accept_inbound_channel(
+			&self, _temporary_channel_id: &[u8; 32], _counterparty_node_id: &PublicKey,
+			_user_channel_id: u128,
+		) -> Result<(), APIError> {
+			unimplemented!()
+		}
+
+		fn accept_inbound_channel_from_trusted_peer_0conf(
+			&self, _temporary_channel_id: &[u8; 32], _counterparty_node_id: &PublicKey,
+			_user_channel_id: u128,
+		) -> Result<(), APIError> {
+			unimplemented!()
+		}
+	}
+
+	#[derive(Debug)]
+	struct TestPricing;
+
+	impl ChannelOrderPricing for TestPricing {
+		fn fee_total_sat(&self, order: &OrderParams) -> u64 {
+			1_000 + order.lsp_balance_sat / 100
+		}
+
+		fn fee_total_sat_with_token(
+			&self, _counterparty_node_id: &PublicKey, _order: &OrderParams, token: &str,
+			fee_total_sat: u64,
+		) -> Option<u64> {
+			match token {
+				"half" => Some(fee_total_sat / 2),
+				_ => None,
+			}
+		}
+	}
+
+	struct TestWallet;
+
+	impl ChannelOrderWallet for TestWallet {
+		fn new_order_address(&self, _order_id: &OrderId) -> Option<Address> {
+			Some(order_address())
+		}
+	}
+
+	struct TestReceiptSigner;
+
+	impl OrderReceiptSigner for TestReceiptSigner {
+		fn sign_order_receipt(&self, message: &Message) -> Option<RecoverableSignature> {
+			let node_secret = keys_manager().get_node_secret_key();
+			Some(Secp256k1::new().sign_ecdsa_recoverable(message, &node_secret))
+		}
+	}
+
+	fn order_address() -> Address {
+		let pubkey = bitcoin::PublicKey::new(lsp_node_id());
+		Address::p2wpkh(&pubkey, Network::Regtest).unwrap()
+	}
+
+	type TestHandler = LSPS1MessageHandler<
+		Arc<KeysManager>,
+		Arc<TestChannelManager>,
+		Arc<KeysManager>,
+		Arc<TestPricing>,
+		Arc<TestWallet>,
+		Arc<TestReceiptSigner>,
+	>;
+
+	fn keys_manager() -> Arc<KeysManager> {
+		Arc::new(KeysManager::new(&[42; 32], 42, 42))
+	}
+
+	fn counterparty_node_id() -> PublicKey {
+		utils::parse_pubkey("027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190")
+			.unwrap()
+	}
+
+	fn lsp_node_id() -> PublicKey {
+		keys_manager().get_node_id(Recipient::Node).unwrap()
+	}
+
+	fn options() -> OptionsSupported {
+		OptionsSupported {
+			minimum_channel_confirmations: 0,
+			minimum_onchain_payment_confirmations: None,
+			supports_zero_channel_reserve: false,
+			min_onchain_payment_size_sat: None,
+			max_channel_expiry_blocks: 4_320,
+			min_initial_client_balance_sat: 0,
+			max_initial_client_balance_sat: 100_000,
+			min_initial_lsp_balance_sat: 10_000,
+			max_initial_lsp_balance_sat: 1_000_000,
+			min_channel_balance_sat: 50_000,
+			max_channel_balance_sat: 1_050_000,
+		}
+	}
+
+	fn config() -> ChannelRequestsConfig<Arc<TestPricing>, Arc<TestWallet>, Arc<TestReceiptSigner>>
+	{
+		let mut channel_config = UserConfig::default();
+		channel_config.channel_handshake_config.announced_channel = true;
+		ChannelRequestsConfig {
+			website: "https://lsp.example.com".to_string(),
+			options: options(),
+			pricing: Arc::new(TestPricing),
+			order_expiry_secs: 3600,
+			max_unpaid_orders_per_client: 3,
+			order_retention_secs: 86_400,
+			channel_config,
+			wallet: Some(Arc::new(TestWallet)),
+			min_fee_for_0conf: 10,
+			receipt_signer: Some(Arc::new(TestReceiptSigner)),
+		}
+	}
+
+	fn onchain_config(
+	) -> ChannelRequestsConfig<Arc<TestPricing>, Arc<TestWallet>, Arc<TestReceiptSigner>> {
+		let mut config = config();
+		config.options.minimum_onchain_payment_confirmations = Some(2);
+		config.options.min_onchain_payment_size_sat = Some(10_000);
+		config
+	}
+
+	fn order() -> OrderParams {
+		OrderParams {
+			lsp_balance_sat: 500_000,
+			client_balance_sat: 20_000,
+			confirms_within_blocks: 6,
+			channel_expiry_blocks: 4_320,
+			announce_channel: false,
+		}
+	}
+
+	fn test_handler(
+		config: Option<
+			ChannelRequestsConfig<Arc<TestPricing>, Arc<TestWallet>, Arc<TestReceiptSigner>>,
+		>,
+		pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		pending_events: &Arc<EventQueue>,
+	) -> TestHandler {
+		LSPS1MessageHandler::new(
+			keys_manager(),
+			Arc::new(TestChannelManager::default()),
+			keys_manager(),
+			Network::Regtest,
+			config,
+			Some(ChannelRequestsClientConfig { channel_expiry_warning_blocks: 144 }),
+			Arc::clone(pending_messages),
+			Arc::clone(pending_events),
+		)
+	}
+
+	fn outbound_orders(handler: &TestHandler) -> HashMap<OrderId, OutboundChannelOrder> {
+		let service_state = handler.service_state.as_ref().unwrap();
+		service_state.outbound_orders.lock().unwrap().orders_by_id.clone()
+	}
+
+	fn pop_message(
+		pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+	) -> (RequestId, LSPS1Message) {
+		let mut pending_messages = pending_messages.lock().unwrap();
+		assert_eq!(pending_messages.len(), 1);
+		match pending_messages.pop().unwrap() {
+			(_, LSPSMessage::LSPS1(message)) => match &message {
+				LSPS1Message::Request(request_id, _) | LSPS1Message::Response(request_id, _) => {
+					(request_id.clone(), message)
+				}
+			},
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn pop_response(pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>) -> LSPS1Response {
+		match pop_message(pending_messages) {
+			(_, LSPS1Message::Response(_, response)) => response,
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn create_order(handler: &TestHandler, order: OrderParams, api_version: u16) {
+		let request = LSPS1Message::Request(
+			RequestId("create_order".to_string()),
+			LSPS1Request::CreateOrder(CreateOrderRequest {
+				api_version,
+				order,
+				token: None,
+				refund_onchain_address: None,
+			}),
+		);
+		let _ = handler.handle_message(request, &counterparty_node_id());
+	}
+
+	fn order_invoice(order_total_sat: u64) -> String {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let lsp = test_handler(Some(config()), &pending_messages, &pending_events);
+		let order_id = OrderId("order".to_string());
+		lsp.create_order_invoice(&order_id, order_total_sat, 3600).unwrap().0.to_string()
+	}
+
+	/// A channel with the LSP matching [`order`].
+	fn order_channel(funding_outpoint: OutPoint, confirmations: Option<u32>) -> OrderChannel {
+		OrderChannel {
+			funding_outpoint,
+			counterparty_node_id: lsp_node_id(),
+			channel_value_sat: 520_000,
+			inbound_balance_msat: 500_000_000,
+			is_public: false,
+			confirmations,
+		}
+	}
+
+	fn order_response(order: OrderParams, fee_total_sat: u64) -> OrderResponse {
+		let created_at = LSPSDateTime::now();
+		OrderResponse {
+			order_id: OrderId("order".to_string()),
+			order: order.clone(),
+			created_at,
+			expires_at: created_at + Duration::from_secs(3600),
+			order_state: OrderState::Created,
+			payment: PaymentInfo {
+				state: PaymentState::ExpectPayment,
+				fee_total_sat,
+				order_total_sat: fee_total_sat + order.client_balance_sat,
+				bolt11_invoice: order_invoice(fee_total_sat + order.client_balance_sat),
+				onchain_address: None,
+				min_onchain_payment_confirmations: None,
+				min_fee_for_0conf: None,
+				onchain_payment: None,
+			},
+			channel: None,
+			receipt: None,
+		}
+	}
+
+	fn client_request_options(
+		handler: &TestHandler, pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		pending_events: &Arc<EventQueue>,
+	) {
+		let lsp_node_id = lsp_node_id();
+		handler.request_options(lsp_node_id, 42);
+
+		let (request_id, _) = pop_message(pending_messages);
+		let response = LSPS1Message::Response(
+			request_id,
+			LSPS1Response::GetInfo(GetInfoResponse {
+				supported_versions: vec![1, 2],
+				website: "https://lsp.example.com".to_string(),
+				options: options(),
+			}),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::SupportedOptionsReady {
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id,
+				website: "https://lsp.example.com".to_string(),
+				options: options(),
+			})]
+		);
+	}
+
+	fn order_state_changed(
+		order: &OutboundChannelOrder, order_state: OrderState, payment_state: PaymentState,
+	) -> Event {
+		Event::LSPS1(LSPS1Event::OrderStateChanged {
+			order_id: order.order_id.clone(),
+			user_channel_id: order.user_channel_id,
+			counterparty_node_id: order.counterparty_node_id,
+			order_state,
+			payment_state,
+		})
+	}
+
+	fn block_header(nonce: u32) -> BlockHeader {
+		BlockHeader {
+			version: 1,
+			prev_blockhash: BlockHash::all_zeros(),
+			merkle_root: TxMerkleNode::all_zeros(),
+			time: 0,
+			bits: 0,
+			nonce,
+		}
+	}
+
+	fn payment_transaction(value: u64) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut { value, script_pubkey: order_address().script_pubkey() }],
+		}
+	}
+
+	fn order_creation_failed(reason: OrderCreationFailureReason) -> Vec<Event> {
+		vec![Event::LSPS1(LSPS1Event::OrderCreationFailed {
+			user_channel_id: 42,
+			counterparty_node_id: lsp_node_id(),
+			reason,
+		})]
+	}
+
+	#[test]
+	fn answers_get_info_from_config() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		let request = LSPS1Message::Request(
+			RequestId("get_info".to_string()),
+			LSPS1Request::GetInfo(GetInfoRequest {}),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+		assert_eq!(
+			pop_response(&pending_messages),
+			LSPS1Response::GetInfo(GetInfoResponse {
+				supported_versions: vec![1],
+				website: "https://lsp.example.com".to_string(),
+				options: options(),
+			})
+		);
+
+		let client = test_handler(None, &pending_messages, &pending_events);
+		let request = LSPS1Message::Request(
+			RequestId("get_info".to_string()),
+			LSPS1Request::GetInfo(GetInfoRequest {}),
+		);
+		assert!(client.handle_message(request, &counterparty_node_id()).is_err());
+		assert!(pending_messages.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn creates_priced_orders() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		let response = match pop_response(&pending_messages) {
+			LSPS1Response::CreateOrder(response) => response,
+			_ => panic!("Unexpected response"),
+		};
+		assert_eq!(response.order, order());
+		assert_eq!(response.order_state, OrderState::Created);
+		assert_eq!(response.payment.state, PaymentState::ExpectPayment);
+		assert_eq!(response.payment.fee_total_sat, 6_000);
+		assert_eq!(response.payment.order_total_sat, 26_000);
+		assert_eq!(response.order_id.0.len(), 32);
+		assert_eq!(response.expires_at, response.created_at + Duration::from_secs(3600));
+		assert_eq!(response.channel, None);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// The order's receipt covers its terms and is signed for the client placing it.
+		let receipt = response.receipt.clone().unwrap();
+		assert_eq!(receipt.order_id, response.order_id);
+		assert_eq!(receipt.order, order());
+		assert_eq!(receipt.fee_total_sat, 6_000);
+		assert_eq!(receipt.order_total_sat, 26_000);
+		assert_eq!(receipt.expires_at, response.expires_at);
+		assert!(receipt.verify(&lsp_node_id(), &counterparty_node_id()));
+		assert!(!receipt.verify(&counterparty_node_id(), &counterparty_node_id()));
+		assert!(!receipt.verify(&lsp_node_id(), &lsp_node_id()));
+		let mut tampered = receipt;
+		tampered.fee_total_sat = 5_000;
+		assert!(!tampered.verify(&lsp_node_id(), &counterparty_node_id()));
+
+		let invoice = Bolt11Invoice::from_str(&response.payment.bolt11_invoice).unwrap();
+		assert_eq!(invoice.amount_milli_satoshis(), Some(26_000_000));
+		assert_eq!(invoice.payment_hash().into_inner(), [1; 32]);
+		assert_eq!(invoice.currency(), Currency::Regtest);
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(3600));
+		assert_eq!(
+			invoice.min_final_cltv_expiry_delta(),
+			u64::from(ORDER_INVOICE_MIN_FINAL_CLTV_EXPIRY_DELTA)
+		);
+		assert_eq!(
+			invoice.recover_payee_pub_key(),
+			keys_manager().get_node_id(Recipient::Node).unwrap()
+		);
+
+		let stored = outbound_orders(&handler).remove(&response.order_id).unwrap();
+		assert_eq!(stored.counterparty_node_id, counterparty_node_id());
+		assert_eq!(stored.payment_hash, PaymentHash([1; 32]));
+		assert_eq!(stored.to_response(), response);
+	}
+
+	#[test]
+	fn prices_orders_placed_with_tokens() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		let create_order_with_token = |token: &str| {
+			let request = LSPS1Message::Request(
+				RequestId("create_order".to_string()),
+				LSPS1Request::CreateOrder(CreateOrderRequest {
+					api_version: 1,
+					order: order(),
+					token: Some(token.to_string()),
+					refund_onchain_address: None,
+				}),
+			);
+			let _ = handler.handle_message(request, &counterparty_node_id());
+			pop_response(&pending_messages)
+		};
+
+		match create_order_with_token("half") {
+			LSPS1Response::CreateOrder(response) => {
+				assert_eq!(response.payment.fee_total_sat, 3_000);
+				assert_eq!(response.payment.order_total_sat, 23_000);
+				let stored = outbound_orders(&handler).remove(&response.order_id).unwrap();
+				assert_eq!(stored.token, Some("half".to_string()));
+			}
+			_ => panic!("Unexpected response"),
+		}
+
+		match create_order_with_token("stale") {
+			LSPS1Response::CreateOrderError(error) => {
+				assert_eq!(
+					error.code,
+					LSPS1_CREATE_ORDER_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE
+				);
+			}
+			_ => panic!("Unexpected response"),
+		}
+		assert_eq!(outbound_orders(&handler).len(), 1);
+	}
+
+	#[test]
+	fn fails_held_payments_back_before_they_time_out() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+		handler.best_block_updated(&block_header(0), 100);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		let payment_hash = PaymentHash([1; 32]);
+		assert!(!handler.payment_claimable(payment_hash, 26_000_000));
+		pending_events.get_and_clear_pending_events();
+
+		handler.best_block_updated(&block_header(1), 100 + HELD_PAYMENT_TIMEOUT_BLOCKS - 1);
+		assert_eq!(outbound_orders(&handler)[&order_id].payment.state, PaymentState::Hold);
+		assert!(handler.channel_manager.failed_payments.lock().unwrap().is_empty());
+
+		handler.best_block_updated(&block_header(2), 100 + HELD_PAYMENT_TIMEOUT_BLOCKS);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.order_state, OrderState::Failed);
+		assert_eq!(order.payment.state, PaymentState::Refunded);
+		assert_eq!(*handler.channel_manager.failed_payments.lock().unwrap(), vec![payment_hash]);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Failed, PaymentState::Refunded)]
+		);
+
+		// The channel getting ready late doesn't claim the failed payment anymore.
+		handler.channel_ready(order.user_channel_id, &counterparty_node_id()).unwrap();
+		assert!(handler.channel_manager.claimed_payments.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn holds_order_payments_until_the_channel_is_ready() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		// Payments unrelated to orders are claimable as usual.
+		assert!(handler.payment_claimable(PaymentHash([9; 32]), 1_000));
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		let payment_hash = PaymentHash([1; 32]);
+
+		// Underpayments are failed back.
+		assert!(!handler.payment_claimable(payment_hash, 25_999_999));
+		assert_eq!(*handler.channel_manager.failed_payments.lock().unwrap(), vec![payment_hash]);
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+
+		assert!(!handler.payment_claimable(payment_hash, 26_000_000));
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Hold);
+		assert_eq!(order.order_state, OrderState::Created);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Created, PaymentState::Hold)]
+		);
+		assert_eq!(
+			*handler.channel_manager.created_channels.lock().unwrap(),
+			vec![(counterparty_node_id(), 520_000, 20_000_000, order.user_channel_id)]
+		);
+		{
+			let channel_configs = handler.channel_manager.channel_configs.lock().unwrap();
+			assert!(!channel_configs[0].channel_handshake_config.announced_channel);
+		}
+
+		// A replayed claimable payment neither opens another channel nor is claimed.
+		assert!(!handler.payment_claimable(payment_hash, 26_000_000));
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+		assert!(handler.channel_manager.claimed_payments.lock().unwrap().is_empty());
+
+		let other_node_id = utils::parse_pubkey(
+			"03fd0c8f2e8b0d3e6f07b3ee5bdcb07e8fe7d1a93cb6e6a6b36a9b8fc9f4a6c2fa",
+		)
+		.unwrap();
+		let funding_txo = OutPoint { txid: Txid::from_inner([5; 32]), vout: 1 };
+		assert!(handler
+			.channel_pending(order.user_channel_id, &other_node_id, funding_txo)
+			.is_err());
+		handler
+			.channel_pending(order.user_channel_id, &counterparty_node_id(), funding_txo)
+			.unwrap();
+		let channel = outbound_orders(&handler).remove(&order_id).unwrap().channel.unwrap();
+		assert_eq!(channel.funding_outpoint, funding_txo);
+		assert_eq!(channel.expires_at, channel.funded_at + Duration::from_secs(4_320 * 600));
+		assert!(handler.get_and_clear_needs_persistence());
+
+		assert!(handler.channel_ready(order.user_channel_id, &other_node_id).is_err());
+		handler.channel_ready(order.user_channel_id, &counterparty_node_id()).unwrap();
+		assert_eq!(
+			*handler.channel_manager.claimed_payments.lock().unwrap(),
+			vec![PaymentPreimage([3; 32])]
+		);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Paid);
+		assert_eq!(order.order_state, OrderState::Completed);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Completed, PaymentState::Paid)]
+		);
+
+		// Closing the channel later doesn't refund the client.
+		handler.channel_closed(order.user_channel_id);
+		assert_eq!(outbound_orders(&handler).remove(&order_id), Some(order));
+		assert_eq!(handler.channel_manager.failed_payments.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn fails_back_order_payments_if_the_channel_open_fails() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let payment_hash = PaymentHash([1; 32]);
+		assert!(!handler.payment_claimable(payment_hash, 26_000_000));
+		let order = outbound_orders(&handler).into_iter().next().unwrap().1;
+
+		handler.channel_closed(order.user_channel_id);
+		assert_eq!(*handler.channel_manager.failed_payments.lock().unwrap(), vec![payment_hash]);
+		assert!(handler.channel_manager.claimed_payments.lock().unwrap().is_empty());
+		let order = outbound_orders(&handler).remove(&order.order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Refunded);
+		assert_eq!(order.order_state, OrderState::Failed);
+
+		// Neither a late channel_ready nor another payment gets claimed.
+		handler.channel_ready(order.user_channel_id, &counterparty_node_id()).unwrap();
+		assert!(!handler.payment_claimable(payment_hash, 26_000_000));
+		assert!(handler.channel_manager.claimed_payments.lock().unwrap().is_empty());
+		assert_eq!(handler.channel_manager.failed_payments.lock().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn fails_orders_expiring_unpaid() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		handler.get_and_clear_needs_persistence();
+
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+		assert!(!handler.get_and_clear_needs_persistence());
+
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			let order = outbound_orders.orders_by_id.get_mut(&order_id).unwrap();
+			order.expires_at = LSPSDateTime::now() - Duration::from_secs(1);
+		}
+		handler.timer_tick_occurred();
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.order_state, OrderState::Failed);
+		assert_eq!(order.payment.state, PaymentState::ExpectPayment);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Failed, PaymentState::ExpectPayment)]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// Late payments are failed back, and the order is only failed once.
+		assert!(!handler.payment_claimable(PaymentHash([1; 32]), 26_000_000));
+		assert_eq!(
+			*handler.channel_manager.failed_payments.lock().unwrap(),
+			vec![PaymentHash([1; 32])]
+		);
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		// Neither failed nor completed orders move on, and only paid orders complete.
+		let mut order = order;
+		assert!(order.transition(OrderState::Created, PaymentState::Hold).is_err());
+		order.order_state = OrderState::Created;
+		assert!(order.transition(OrderState::Completed, PaymentState::ExpectPayment).is_err());
+		order.transition(OrderState::Created, PaymentState::Hold).unwrap();
+		order.transition(OrderState::Completed, PaymentState::Paid).unwrap();
+		assert!(order.transition(OrderState::Failed, PaymentState::Refunded).is_err());
+	}
+
+	#[test]
+	fn opens_channels_for_orders_paid_onchain_once_confirmed() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(onchain_config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		let payment = match pop_response(&pending_messages) {
+			LSPS1Response::CreateOrder(response) => response.payment,
+			_ => panic!("Unexpected response"),
+		};
+		assert_eq!(payment.onchain_address, Some(order_address().to_string()));
+		assert_eq!(payment.min_onchain_payment_confirmations, Some(2));
+		assert_eq!(payment.min_fee_for_0conf, Some(10));
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+
+		// Underpayments are ignored.
+		let underpayment = payment_transaction(25_999);
+		handler.transactions_confirmed(&block_header(0), &[(0, &underpayment)], 100);
+		assert!(outbound_orders(&handler)[&order_id].payment.onchain_payment.is_none());
+
+		let tx = payment_transaction(26_000);
+		let header = block_header(1);
+		handler.transactions_confirmed(&header, &[(0, &tx)], 100);
+		handler.best_block_updated(&header, 100);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(
+			order.payment.onchain_payment,
+			Some(OnchainPayment {
+				outpoint: OutPoint { txid: tx.txid(), vout: 0 },
+				sat: 26_000,
+				confirmed: false
+			})
+		);
+		assert_eq!(order.payment.state, PaymentState::ExpectPayment);
+		assert_eq!(handler.get_relevant_txids(), vec![(tx.txid(), Some(header.block_hash()))]);
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+		pending_events.get_and_clear_pending_events();
+
+		// Unpaid orders don't expire while their payment confirms.
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			let order = outbound_orders.orders_by_id.get_mut(&order_id).unwrap();
+			order.expires_at = LSPSDateTime::now() - Duration::from_secs(1);
+		}
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.best_block_updated(&block_header(2), 101);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Paid);
+		assert!(order.payment.onchain_payment.as_ref().unwrap().confirmed);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Created, PaymentState::Paid)]
+		);
+		assert_eq!(
+			*handler.channel_manager.created_channels.lock().unwrap(),
+			vec![(counterparty_node_id(), 520_000, 20_000_000, order.user_channel_id)]
+		);
+		assert!(handler.get_relevant_txids().is_empty());
+
+		// The order isn't paid twice.
+		assert!(!handler.payment_claimable(PaymentHash([1; 32]), 26_000_000));
+		assert_eq!(
+			*handler.channel_manager.failed_payments.lock().unwrap(),
+			vec![PaymentHash([1; 32])]
+		);
+
+		handler.channel_ready(order.user_channel_id, &counterparty_node_id()).unwrap();
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.order_state, OrderState::Completed);
+		assert!(handler.channel_manager.claimed_payments.lock().unwrap().is_empty());
+
+		// Accepted payments remain across restarts.
+		let encoded = handler.encode();
+		let restored = test_handler(Some(onchain_config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &encoded[..]).unwrap();
+		assert_eq!(outbound_orders(&restored).remove(&order_id), Some(order));
+	}
+
+	#[test]
+	fn accepts_unconfirmed_onchain_payments_paying_the_0conf_fee() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(onchain_config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+
+		// The payment pays 100 sat for its 41 vbytes, less than the 10 sat/vbyte required.
+		let tx = payment_transaction(26_000);
+		handler.unconfirmed_transaction_seen(&tx, 100);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::ExpectPayment);
+		assert!(order.payment.onchain_payment.is_some());
+
+		// Payments reorged out before we accepted them are forgotten.
+		let header = block_header(1);
+		handler.transactions_confirmed(&header, &[(0, &tx)], 100);
+		handler.block_disconnected(&header, 100);
+		assert!(outbound_orders(&handler)[&order_id].payment.onchain_payment.is_none());
+		handler.transactions_confirmed(&header, &[(0, &tx)], 100);
+		handler.transaction_unconfirmed(&tx.txid());
+		assert!(outbound_orders(&handler)[&order_id].payment.onchain_payment.is_none());
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+
+		handler.unconfirmed_transaction_seen(&tx, 410);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Paid);
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+
+		// If the channel can't be opened the payment remains to be refunded.
+		handler.channel_closed(order.user_channel_id);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.order_state, OrderState::Failed);
+		assert_eq!(order.payment.state, PaymentState::Paid);
+		assert!(handler.channel_manager.failed_payments.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn requests_refunds_for_failed_orders_paid_onchain() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(onchain_config()), &pending_messages, &pending_events);
+
+		let refund_address =
+			Address::p2wpkh(&bitcoin::PublicKey::new(counterparty_node_id()), Network::Regtest)
+				.unwrap();
+		let mainnet_address =
+			Address::p2wpkh(&bitcoin::PublicKey::new(counterparty_node_id()), Network::Bitcoin)
+				.unwrap();
+		for address in vec![mainnet_address, refund_address.clone()] {
+			let request = LSPS1Message::Request(
+				RequestId("create_order".to_string()),
+				LSPS1Request::CreateOrder(CreateOrderRequest {
+					api_version: 1,
+					order: order(),
+					token: None,
+					refund_onchain_address: Some(address.to_string()),
+				}),
+			);
+			let _ = handler.handle_message(request, &counterparty_node_id());
+		}
+		match pending_messages.lock().unwrap().remove(0).1 {
+			LSPSMessage::LSPS1(LSPS1Message::Response(
+				_,
+				LSPS1Response::CreateOrderError(error),
+			)) => {
+				assert_eq!(error.code, LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE);
+				assert_eq!(error.data, Some(json!({ "property": "refund_onchain_address" })));
+			}
+			_ => panic!("Unexpected message"),
+		}
+		pop_response(&pending_messages);
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+
+		let tx = payment_transaction(26_000);
+		handler.unconfirmed_transaction_seen(&tx, 410);
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.refund_onchain_address, Some(refund_address.to_string()));
+		pending_events.get_and_clear_pending_events();
+
+		// Orders paid on-chain can't be refunded before they failed.
+		assert!(handler.order_refunded(&order_id, Txid::from_inner([9; 32])).is_err());
+
+		handler.channel_closed(order.user_channel_id);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![
+				order_state_changed(&order, OrderState::Failed, PaymentState::Paid),
+				Event::LSPS1(LSPS1Event::RefundRequired {
+					order_id: order_id.clone(),
+					user_channel_id: order.user_channel_id,
+					counterparty_node_id: counterparty_node_id(),
+					amount_sat: 26_000,
+					refund_onchain_address: Some(refund_address),
+				}),
+			]
+		);
+
+		handler.order_refunded(&order_id, Txid::from_inner([9; 32])).unwrap();
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+		assert_eq!(order.payment.state, PaymentState::Refunded);
+		assert_eq!(order.refund_txid, Some(Txid::from_inner([9; 32])));
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&order, OrderState::Failed, PaymentState::Refunded)]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// Orders are only refunded once.
+		assert!(handler.order_refunded(&order_id, Txid::from_inner([9; 32])).is_err());
+	}
+
+	#[test]
+	fn computes_channel_expiry_heights() {
+		let funding_outpoint = OutPoint { txid: Txid::from_inner([6; 32]), vout: 1 };
+		let funded_at = LSPSDateTime::now();
+		let channel = ChannelInfo {
+			funded_at,
+			funding_outpoint,
+			expires_at: funded_at + Duration::from_secs(1),
+		};
+
+		// Confirmed three blocks ago, i.e., at height 98.
+		let channels = vec![order_channel(funding_outpoint, Some(3))];
+		assert_eq!(
+			channel_expiry_height(&channels, Some(&channel), &order(), 100),
+			Some(98 + 4_320)
+		);
+
+		let other_outpoint = OutPoint { txid: funding_outpoint.txid, vout: 0 };
+		assert_eq!(
+			channel_expiry_height(
+				&[order_channel(other_outpoint, Some(3))],
+				Some(&channel),
+				&order(),
+				100
+			),
+			None
+		);
+		assert_eq!(
+			channel_expiry_height(
+				&[order_channel(funding_outpoint, Some(0))],
+				Some(&channel),
+				&order(),
+				100
+			),
+			None
+		);
+		assert_eq!(channel_expiry_height(&channels, None, &order(), 100), None);
+	}
+
+	#[test]
+	fn client_checks_channels_delivered_for_orders() {
+		let mut order = InboundChannelOrder::new(
+			lsp_node_id(),
+			42,
+			order_response(order(), 6_000),
+			PaymentHash([1; 32]),
+			None,
+		);
+		let funding_outpoint = OutPoint { txid: Txid::from_inner([6; 32]), vout: 0 };
+		let delivered = || order_channel(funding_outpoint, None);
+		assert!(!order.is_delivered(&[delivered()]));
+
+		let funded_at = LSPSDateTime::now();
+		order.channel = Some(ChannelInfo { funded_at, funding_outpoint, expires_at: funded_at });
+		assert!(order.is_delivered(&[delivered()]));
+		assert!(!order.is_delivered(&[]));
+
+		let mut mismatched = vec![delivered(), delivered(), delivered(), delivered(), delivered()];
+		mismatched[0].funding_outpoint.vout = 1;
+		mismatched[1].counterparty_node_id = counterparty_node_id();
+		mismatched[2].channel_value_sat = 519_999;
+		mismatched[3].inbound_balance_msat = 499_999_999;
+		mismatched[4].is_public = true;
+		for channel in mismatched {
+			assert!(!order.is_delivered(&[channel]));
+		}
+	}
+
+	#[test]
+	fn reports_expired_channel_leases() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			let order = outbound_orders.orders_by_id.get_mut(&order_id).unwrap();
+			order.order_state = OrderState::Completed;
+			order.payment.state = PaymentState::Paid;
+			order.channel_expiry_height = Some(200);
+		}
+		let order = outbound_orders(&handler).remove(&order_id).unwrap();
+
+		handler.best_block_updated(&block_header(0), 199);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.best_block_updated(&block_header(1), 200);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::ChannelLeaseExpired {
+				order_id: order_id.clone(),
+				user_channel_id: order.user_channel_id,
+				counterparty_node_id: counterparty_node_id(),
+				channel_expiry_height: 200,
+			})]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// Expired leases are only reported once, also across restarts.
+		handler.best_block_updated(&block_header(2), 201);
+		let encoded = handler.encode();
+		let restored = test_handler(Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &encoded[..]).unwrap();
+		restored.best_block_updated(&block_header(2), 201);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn client_warned_about_expiring_channel_leases() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+
+		let response = order_response(order(), 6_000);
+		let mut order =
+			InboundChannelOrder::new(lsp_node_id(), 42, response, PaymentHash([1; 32]), None);
+		order.order_state = OrderState::Completed;
+		order.payment.state = PaymentState::Paid;
+		order.channel_expiry_height = Some(1_000);
+		handler.inbound_orders.lock().unwrap().insert(order.order_id.clone(), order.clone());
+
+		// We are warned 144 blocks ahead of the expiry.
+		handler.best_block_updated(&block_header(0), 855);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.best_block_updated(&block_header(1), 856);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::ChannelLeaseExpiring {
+				order_id: order.order_id.clone(),
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id(),
+				channel_expiry_height: 1_000,
+			})]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		handler.best_block_updated(&block_header(2), 857);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn only_offers_onchain_payments_if_supported() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+
+		let mut small_order = order();
+		small_order.lsp_balance_sat = 50_000;
+		small_order.client_balance_sat = 0;
+		let mut configs = vec![(config(), order()), (onchain_config(), small_order)];
+		let mut without_wallet = onchain_config();
+		without_wallet.wallet = None;
+		configs.push((without_wallet, order()));
+
+		for (config, order) in configs {
+			let handler = test_handler(Some(config), &pending_messages, &pending_events);
+			create_order(&handler, order, 1);
+			match pop_response(&pending_messages) {
+				LSPS1Response::CreateOrder(response) => {
+					assert_eq!(response.payment.onchain_address, None);
+					assert_eq!(response.payment.min_onchain_payment_confirmations, None);
+					assert_eq!(response.payment.min_fee_for_0conf, None);
+				}
+				_ => panic!("Unexpected response"),
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_mismatched_orders_and_unsupported_versions() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		let mut mismatched = order();
+		mismatched.client_balance_sat = 100_001;
+		create_order(&handler, mismatched, 1);
+		assert_eq!(
+			pop_response(&pending_messages),
+			LSPS1Response::CreateOrderError(ResponseError {
+				code: LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+				message: "option_mismatch".to_string(),
+				data: Some(json!({ "property": "client_balance_sat" })),
+			})
+		);
+
+		create_order(&handler, order(), 2);
+		assert_eq!(
+			pop_response(&pending_messages),
+			LSPS1Response::CreateOrderError(ResponseError {
+				code: LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE,
+				message: "unsupported_version".to_string(),
+				data: None,
+			})
+		);
+
+		assert!(outbound_orders(&handler).is_empty());
+		assert!(!handler.get_and_clear_needs_persistence());
+	}
+
+	#[test]
+	fn validates_state_transitions() {
+		use OrderState::*;
+		use PaymentState::*;
+
+		assert!(is_valid_transition(Created, ExpectPayment, Created, ExpectPayment));
+		assert!(is_valid_transition(Created, ExpectPayment, Completed, Paid));
+		assert!(is_valid_transition(Created, ExpectPayment, Failed, ExpectPayment));
+		assert!(is_valid_transition(Created, Hold, Failed, Refunded));
+		assert!(is_valid_transition(Failed, Paid, Failed, Refunded));
+
+		// Payments are only refunded once they were received.
+		assert!(!is_valid_transition(Created, ExpectPayment, Failed, Refunded));
+		assert!(!is_valid_transition(Created, Paid, Completed, Refunded));
+		assert!(!is_valid_transition(Completed, Paid, Created, Paid));
+		assert!(!is_valid_transition(Created, Hold, Completed, Hold));
+	}
+
+	#[test]
+	fn limits_unpaid_orders_per_client() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let created = outbound_orders(&handler).values().next().unwrap().clone();
+		for i in 1..3 {
+			let mut unpaid = created.clone();
+			unpaid.order_id = OrderId(format!("unpaid{}", i));
+			unpaid.payment_hash = PaymentHash([i; 32]);
+			unpaid.user_channel_id += u128::from(i);
+			let service_state = handler.service_state.as_ref().unwrap();
+			service_state.outbound_orders.lock().unwrap().insert(unpaid);
+		}
+
+		create_order(&handler, order(), 1);
+		assert_eq!(
+			pop_response(&pending_messages),
+			LSPS1Response::CreateOrderError(ResponseError {
+				code: JSONRPC_INTERNAL_ERROR_ERROR_CODE,
+				message: "too_many_unpaid_orders".to_string(),
+				data: None,
+			})
+		);
+		assert_eq!(outbound_orders(&handler).len(), 3);
+
+		// Once one of them expired, the client may order again.
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_orders = service_state.outbound_orders.lock().unwrap();
+			let order_id = OrderId("unpaid1".to_string());
+			let order = outbound_orders.orders_by_id.get_mut(&order_id).unwrap();
+			order.expires_at = LSPSDateTime::now() - Duration::from_secs(1);
+		}
+		handler.timer_tick_occurred();
+		create_order(&handler, order(), 1);
+		match pop_response(&pending_messages) {
+			LSPS1Response::CreateOrder(_) => {}
+			_ => panic!("Unexpected response"),
+		}
+	}
+
+	#[test]
+	fn forgets_finished_orders_after_retention() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		let service_state = handler.service_state.as_ref().unwrap();
+		service_state
+			.outbound_orders
+			.lock()
+			.unwrap()
+			.orders_by_id
+			.get_mut(&order_id)
+			.unwrap()
+			.expires_at = LSPSDateTime::now() - Duration::from_secs(1);
+		handler.get_and_clear_needs_persistence();
+
+		// The order fails on the first tick, and is finished from the next on.
+		handler.timer_tick_occurred();
+		assert_eq!(outbound_orders(&handler)[&order_id].order_state, OrderState::Failed);
+		assert!(outbound_orders(&handler)[&order_id].finished_at.is_some());
+		assert!(handler.get_and_clear_needs_persistence());
+
+		handler.timer_tick_occurred();
+		assert!(handler.outbound_order_by_id(&order_id).is_some());
+		assert!(!handler.get_and_clear_needs_persistence());
+
+		service_state
+			.outbound_orders
+			.lock()
+			.unwrap()
+			.orders_by_id
+			.get_mut(&order_id)
+			.unwrap()
+			.finished_at = Some(LSPSDateTime::now() - Duration::from_secs(86_401));
+		handler.timer_tick_occurred();
+		assert!(handler.outbound_order_by_id(&order_id).is_none());
+		assert!(handler.get_and_clear_needs_persistence());
+		let outbound_orders = service_state.outbound_orders.lock().unwrap();
+		assert!(outbound_orders.order_ids_by_payment_hash.is_empty());
+		assert!(outbound_orders.order_ids_by_user_channel_id.is_empty());
+	}
+
+	#[test]
+	fn returns_orders_to_the_client_that_placed_them() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		let created = match pop_response(&pending_messages) {
+			LSPS1Response::CreateOrder(response) => response,
+			_ => panic!("Unexpected response"),
+		};
+
+		let get_order = |counterparty_node_id: &PublicKey| {
+			let request = LSPS1Message::Request(
+				RequestId("get_order".to_string()),
+				LSPS1Request::GetOrder(GetOrderRequest { order_id: created.order_id.clone() }),
+			);
+			let _ = handler.handle_message(request, counterparty_node_id);
+			pop_response(&pending_messages)
+		};
+
+		assert_eq!(get_order(&counterparty_node_id()), LSPS1Response::GetOrder(created.clone()));
+
+		let other_node_id = utils::parse_pubkey(
+			"03fd0c8f2e8b0d3e6f07b3ee5bdcb07e8fe7d1a93cb6e6a6b36a9b8fc9f4a6c2fa",
+		)
+		.unwrap();
+		match get_order(&other_node_id) {
+			LSPS1Response::GetOrderError(error) => {
+				assert_eq!(error.code, LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE)
+			}
+			_ => panic!("Unexpected response"),
+		}
+	}
+
+	#[test]
+	fn lists_and_cancels_orders() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let order_id = outbound_orders(&handler).keys().next().unwrap().clone();
+		let created = handler.outbound_order_by_id(&order_id).unwrap();
+
+		let other_node_id = utils::parse_pubkey(
+			"03fd0c8f2e8b0d3e6f07b3ee5bdcb07e8fe7d1a93cb6e6a6b36a9b8fc9f4a6c2fa",
+		)
+		.unwrap();
+		let mut older = created.clone();
+		older.order_id = OrderId("older".to_string());
+		older.counterparty_node_id = other_node_id;
+		older.created_at = created.created_at - Duration::from_secs(3600);
+		older.payment_hash = PaymentHash([2; 32]);
+		older.user_channel_id += 1;
+		handler
+			.service_state
+			.as_ref()
+			.unwrap()
+			.outbound_orders
+			.lock()
+			.unwrap()
+			.insert(older.clone());
+
+		assert_eq!(handler.list_outbound_orders(None, None, None), vec![older, created.clone()]);
+		assert_eq!(
+			handler.list_outbound_orders(None, Some(&counterparty_node_id()), None),
+			vec![created.clone()]
+		);
+		let recently = created.created_at - Duration::from_secs(60)
+			..created.created_at + Duration::from_secs(1);
+		assert_eq!(handler.list_outbound_orders(None, None, Some(recently)), vec![created.clone()]);
+		assert!(handler.list_outbound_orders(Some(OrderState::Failed), None, None).is_empty());
+		assert!(handler.outbound_order_by_id(&OrderId("unknown".to_string())).is_none());
+
+		handler.get_and_clear_needs_persistence();
+		handler.cancel_outbound_order(&order_id).unwrap();
+		let cancelled = handler.outbound_order_by_id(&order_id).unwrap();
+		assert_eq!(cancelled.order_state, OrderState::Failed);
+		assert_eq!(
+			handler.list_outbound_orders(Some(OrderState::Failed), None, None),
+			vec![cancelled.clone()]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![order_state_changed(&cancelled, OrderState::Failed, PaymentState::ExpectPayment)]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(handler.cancel_outbound_order(&order_id).is_err());
+
+		// Its invoice can't be paid anymore.
+		assert!(!handler.payment_claimable(cancelled.payment_hash, 26_000_000));
+		assert_eq!(
+			*handler.channel_manager.failed_payments.lock().unwrap(),
+			vec![cancelled.payment_hash]
+		);
+
+		// The client learns the order failed.
+		let request = LSPS1Message::Request(
+			RequestId("get_order".to_string()),
+			LSPS1Request::GetOrder(GetOrderRequest { order_id }),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+		match pop_response(&pending_messages) {
+			LSPS1Response::GetOrder(response) => {
+				assert_eq!(response.order_state, OrderState::Failed)
+			}
+			_ => panic!("Unexpected response"),
+		}
+	}
+
+	#[test]
+	fn client_orders_channel_and_checks_its_status() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+
+		let mut mismatched = order();
+		mismatched.channel_expiry_blocks = 5_000;
+		match handler.create_order(&lsp_node_id, 42, mismatched, None, None) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("channel_expiry_blocks")),
+			_ => panic!("Unexpected result"),
+		}
+		assert!(pending_messages.lock().unwrap().is_empty());
+		assert!(handler.create_order(&lsp_node_id, 43, order(), None, None).is_err());
+		let mainnet_address =
+			Address::p2wpkh(&bitcoin::PublicKey::new(lsp_node_id), Network::Bitcoin).unwrap();
+		match handler.create_order(&lsp_node_id, 42, order(), None, Some(mainnet_address)) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("refund address")),
+			_ => panic!("Unexpected result"),
+		}
+
+		let token = Some("coupon".to_string());
+		handler.create_order(&lsp_node_id, 42, order(), token, Some(order_address())).unwrap();
+		let (request_id, message) = pop_message(&pending_messages);
+		assert_eq!(
+			message,
+			LSPS1Message::Request(
+				request_id.clone(),
+				LSPS1Request::CreateOrder(CreateOrderRequest {
+					api_version: 1,
+					order: order(),
+					token: Some("coupon".to_string()),
+					refund_onchain_address: Some(order_address().to_string()),
+				})
+			)
+		);
+		// The options were used up by the order.
+		assert!(handler.create_order(&lsp_node_id, 42, order(), None, None).is_err());
+
+		let response = order_response(order(), 6_000);
+		handler
+			.handle_message(
+				LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response.clone())),
+				&lsp_node_id,
+			)
+			.unwrap();
+		let created = InboundChannelOrder::new(
+			lsp_node_id,
+			42,
+			response.clone(),
+			PaymentHash([1; 32]),
+			Some(order_address().to_string()),
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::OrderCreated { order: Box::new(created.clone()) })]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		handler.check_order_status(&lsp_node_id, created.order_id.clone()).unwrap();
+		let (request_id, message) = pop_message(&pending_messages);
+		assert_eq!(
+			message,
+			LSPS1Message::Request(
+				request_id.clone(),
+				LSPS1Request::GetOrder(GetOrderRequest { order_id: created.order_id.clone() })
+			)
+		);
+
+		let mut completed = response;
+		completed.order_state = OrderState::Completed;
+		completed.payment.state = PaymentState::Paid;
+		let funding_outpoint = OutPoint { txid: Txid::from_inner([6; 32]), vout: 0 };
+		let funded_at = LSPSDateTime::now();
+		completed.channel =
+			Some(ChannelInfo { funded_at, funding_outpoint, expires_at: funded_at });
+		handler
+			.handle_message(
+				LSPS1Message::Response(request_id, LSPS1Response::GetOrder(completed.clone())),
+				&lsp_node_id,
+			)
+			.unwrap();
+		let mut expected = created;
+		expected.update(completed.clone());
+		// The LSP reports to have opened the channel, which we don't have.
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![
+				Event::LSPS1(LSPS1Event::OrderStateChanged {
+					order_id: expected.order_id.clone(),
+					user_channel_id: 42,
+					counterparty_node_id: lsp_node_id,
+					order_state: OrderState::Completed,
+					payment_state: PaymentState::Paid,
+				}),
+				Event::LSPS1(LSPS1Event::ChannelDeliveryDisputed {
+					order_id: expected.order_id.clone(),
+					user_channel_id: 42,
+					counterparty_node_id: lsp_node_id,
+					funding_outpoint: Some(funding_outpoint),
+				}),
+				Event::LSPS1(LSPS1Event::OrderStatusReady { order: Box::new(expected.clone()) }),
+			]
+		);
+
+		// Completed orders can't fail anymore.
+		handler.check_order_status(&lsp_node_id, expected.order_id.clone()).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut failed = completed;
+		failed.order_state = OrderState::Failed;
+		failed.payment.state = PaymentState::Refunded;
+		let message = LSPS1Message::Response(request_id, LSPS1Response::GetOrder(failed));
+		assert!(handler.handle_message(message, &lsp_node_id).is_err());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		assert!(handler.check_order_status(&lsp_node_id, OrderId("unknown".to_string())).is_err());
+
+		let encoded = handler.encode();
+		let restored = test_handler(None, &pending_messages, &pending_events);
+		restored.read_state(&mut &encoded[..]).unwrap();
+		assert_eq!(
+			restored.inbound_orders.lock().unwrap().get(&expected.order_id),
+			Some(&expected)
+		);
+	}
+
+	#[test]
+	fn client_rejects_orders_differing_from_the_requested_one() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+
+		let mut response = order_response(order(), 6_000);
+		response.payment.order_total_sat += 1;
+		let message = LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response));
+		assert!(handler.handle_message(message, &lsp_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::InvalidOrder)
+		);
+		assert!(handler.inbound_orders.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn client_rejects_orders_whose_invoice_does_not_pay_the_order_total() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+
+		let mut response = order_response(order(), 6_000);
+		response.payment.bolt11_invoice = order_invoice(25_000);
+		let message = LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response));
+		assert!(handler.handle_message(message, &lsp_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::InvalidOrder)
+		);
+		assert!(handler.inbound_orders.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn client_verifies_and_stores_order_receipts() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		// We share the LSP's node key in tests, so we can sign the LSP's receipts ourselves.
+		let response = order_response(order(), 6_000);
+		let receipt = create_order_receipt(&TestReceiptSigner, &lsp_node_id, &response).unwrap();
+		let mut tampered = receipt.clone();
+		tampered.fee_total_sat = 5_000;
+		let mut mismatched = receipt.clone();
+		mismatched.order_id = OrderId("other".to_string());
+		let mut malformed = receipt.clone();
+		malformed.signature.truncate(64);
+		for receipt in vec![tampered, mismatched, malformed] {
+			client_request_options(&handler, &pending_messages, &pending_events);
+			handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+			let (request_id, _) = pop_message(&pending_messages);
+			let mut response = response.clone();
+			response.receipt = Some(Box::new(receipt));
+			let message = LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response));
+			assert!(handler.handle_message(message, &lsp_node_id).is_err());
+			assert_eq!(
+				pending_events.get_and_clear_pending_events(),
+				order_creation_failed(OrderCreationFailureReason::InvalidOrder)
+			);
+		}
+		assert!(handler.inbound_orders.lock().unwrap().is_empty());
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut response = response;
+		response.receipt = Some(Box::new(receipt.clone()));
+		let message = LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response));
+		handler.handle_message(message, &lsp_node_id).unwrap();
+		let order = handler.inbound_orders.lock().unwrap()[&OrderId("order".to_string())].clone();
+		assert_eq!(order.receipt, Some(receipt));
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::OrderCreated { order: Box::new(order) })]
+		);
+	}
+
+	#[test]
+	fn client_checks_onchain_payment_addresses() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		let mainnet_address =
+			Address::p2wpkh(&bitcoin::PublicKey::new(lsp_node_id), Network::Bitcoin).unwrap();
+		for address in vec![mainnet_address.to_string(), "invalid".to_string()] {
+			client_request_options(&handler, &pending_messages, &pending_events);
+			handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+			let (request_id, _) = pop_message(&pending_messages);
+			let mut response = order_response(order(), 6_000);
+			response.payment.onchain_address = Some(address);
+			let message = LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response));
+			assert!(handler.handle_message(message, &lsp_node_id).is_err());
+			assert_eq!(
+				pending_events.get_and_clear_pending_events(),
+				order_creation_failed(OrderCreationFailureReason::InvalidOrder)
+			);
+		}
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut response = order_response(order(), 6_000);
+		response.payment.onchain_address = Some(order_address().to_string());
+		response.payment.min_onchain_payment_confirmations = Some(2);
+		response.payment.min_fee_for_0conf = Some(10);
+		let message =
+			LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response.clone()));
+		handler.handle_message(message, &lsp_node_id).unwrap();
+		let order = handler.inbound_orders.lock().unwrap()[&response.order_id].clone();
+		assert_eq!(order.onchain_payment_address(), Some(order_address()));
+		pending_events.get_and_clear_pending_events();
+
+		// The LSP reports the payment it received, but can't change the address.
+		let outpoint = OutPoint { txid: Txid::from_inner([5; 32]), vout: 0 };
+		let mut paid = response.clone();
+		paid.payment.state = PaymentState::Paid;
+		paid.payment.onchain_payment =
+			Some(OnchainPayment { outpoint, sat: 26_000, confirmed: true });
+		let mut moved = paid.clone();
+		moved.payment.onchain_address = Some(mainnet_address.to_string());
+		for (update, is_valid) in vec![(moved, false), (paid.clone(), true)] {
+			handler.check_order_status(&lsp_node_id, response.order_id.clone()).unwrap();
+			let (request_id, _) = pop_message(&pending_messages);
+			let message = LSPS1Message::Response(request_id, LSPS1Response::GetOrder(update));
+			assert_eq!(handler.handle_message(message, &lsp_node_id).is_ok(), is_valid);
+		}
+		let order = handler.inbound_orders.lock().unwrap()[&response.order_id].clone();
+		assert_eq!(order.payment, paid.payment);
+	}
+
+	#[test]
+	fn client_pays_orders_and_reports_the_outcome() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let response = order_response(order(), 6_000);
+		let message =
+			LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response.clone()));
+		handler.handle_message(message, &lsp_node_id).unwrap();
+		pending_events.get_and_clear_pending_events();
+
+		assert!(handler.pay_order(&OrderId("unknown".to_string()), Retry::Attempts(3)).is_err());
+		handler.pay_order(&response.order_id, Retry::Attempts(3)).unwrap();
+		{
+			let paid_invoices = handler.channel_manager.paid_invoices.lock().unwrap();
+			assert_eq!(paid_invoices.len(), 1);
+			assert_eq!(paid_invoices[0].0.to_string(), response.payment.bolt11_invoice);
+			assert_eq!(paid_invoices[0].1, PaymentId([1; 32]));
+		}
+
+		// Only payments of our orders are reported.
+		handler.payment_failed(PaymentHash([9; 32]));
+		handler.payment_sent(PaymentHash([9; 32]));
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.payment_failed(PaymentHash([1; 32]));
+		handler.payment_sent(PaymentHash([1; 32]));
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![
+				Event::LSPS1(LSPS1Event::OrderPaymentFailed {
+					order_id: response.order_id.clone(),
+					user_channel_id: 42,
+					counterparty_node_id: lsp_node_id,
+					payment_hash: PaymentHash([1; 32]),
+				}),
+				Event::LSPS1(LSPS1Event::OrderPaymentSent {
+					order_id: response.order_id.clone(),
+					user_channel_id: 42,
+					counterparty_node_id: lsp_node_id,
+					payment_hash: PaymentHash([1; 32]),
+				}),
+			]
+		);
+
+		// The LSP can't swap the invoice we paid for another one.
+		handler.check_order_status(&lsp_node_id, response.order_id.clone()).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut changed = response.clone();
+		changed.payment.order_total_sat += 1;
+		changed.payment.bolt11_invoice = order_invoice(changed.payment.order_total_sat);
+		let message = LSPS1Message::Response(request_id, LSPS1Response::GetOrder(changed));
+		assert!(handler.handle_message(message, &lsp_node_id).is_err());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.check_order_status(&lsp_node_id, response.order_id.clone()).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut paid = response.clone();
+		paid.payment.state = PaymentState::Hold;
+		let message = LSPS1Message::Response(request_id, LSPS1Response::GetOrder(paid));
+		handler.handle_message(message, &lsp_node_id).unwrap();
+		assert!(handler.pay_order(&response.order_id, Retry::Attempts(3)).is_err());
+		assert_eq!(handler.channel_manager.paid_invoices.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn client_fails_orders_expiring_unpaid() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let response = order_response(order(), 6_000);
+		let message =
+			LSPS1Message::Response(request_id, LSPS1Response::CreateOrder(response.clone()));
+		handler.handle_message(message, &lsp_node_id).unwrap();
+		pending_events.get_and_clear_pending_events();
+		handler.get_and_clear_needs_persistence();
+
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.inbound_orders.lock().unwrap().get_mut(&response.order_id).unwrap().expires_at =
+			LSPSDateTime::now() - Duration::from_secs(1);
+		handler.timer_tick_occurred();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1(LSPS1Event::OrderStateChanged {
+				order_id: response.order_id.clone(),
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id,
+				order_state: OrderState::Failed,
+				payment_state: PaymentState::ExpectPayment,
+			})]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(handler.pay_order(&response.order_id, Retry::Attempts(3)).is_err());
+
+		// The LSP can't revive the order.
+		handler.check_order_status(&lsp_node_id, response.order_id.clone()).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let mut paid = response;
+		paid.payment.state = PaymentState::Hold;
+		let message = LSPS1Message::Response(request_id, LSPS1Response::GetOrder(paid));
+		assert!(handler.handle_message(message, &lsp_node_id).is_err());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn client_reports_error_responses_and_unanswered_requests() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(None, &pending_messages, &pending_events);
+		let lsp_node_id = lsp_node_id();
+
+		client_request_options(&handler, &pending_messages, &pending_events);
+		handler.create_order(&lsp_node_id, 42, order(), None, None).unwrap();
+		let (request_id, _) = pop_message(&pending_messages);
+		let error = LSPS1Message::Response(
+			request_id,
+			LSPS1Response::CreateOrderError(ResponseError {
+				code: LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+				message: "option_mismatch".to_string(),
+				data: Some(json!({ "property": "lsp_balance_sat" })),
+			}),
+		);
+		assert!(handler.handle_message(error, &lsp_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::OptionMismatch {
+				property: "lsp_balance_sat".to_string()
+			})
+		);
+
+		handler.request_options(lsp_node_id, 42);
+		let (request_id, _) = pop_message(&pending_messages);
+		let response = LSPS1Message::Response(
+			request_id,
+			LSPS1Response::GetInfo(GetInfoResponse {
+				supported_versions: vec![2],
+				website: String::new(),
+				options: options(),
+			}),
+		);
+		assert!(handler.handle_message(response, &lsp_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::NoCommonVersion)
+		);
+
+		// Options nobody orders from time out just like unanswered requests.
+		client_request_options(&handler, &pending_messages, &pending_events);
+		for _ in 0..REQUEST_TIMEOUT_TICKS - 1 {
+			handler.timer_tick_occurred();
+		}
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+		handler.timer_tick_occurred();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::Timeout)
+		);
+
+		handler.request_options(lsp_node_id, 42);
+		pending_messages.lock().unwrap().clear();
+		for _ in 0..REQUEST_TIMEOUT_TICKS {
+			handler.timer_tick_occurred();
+		}
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			order_creation_failed(OrderCreationFailureReason::Timeout)
+		);
+		assert!(handler.per_peer_state.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn restores_orders_after_restart() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(Some(config()), &pending_messages, &pending_events);
+
+		create_order(&handler, order(), 1);
+		pending_messages.lock().unwrap().clear();
+		let orders = outbound_orders(&handler);
+
+		let encoded = handler.encode();
+		let restored = test_handler(Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &encoded[..]).unwrap();
+		assert_eq!(outbound_orders(&restored), orders);
+		assert!(!restored.get_and_clear_needs_persistence());
+
+		// The restored orders can still be paid for.
+		assert!(!restored.payment_claimable(PaymentHash([1; 32]), 26_000_000));
+		assert_eq!(restored.channel_manager.created_channels.lock().unwrap().len(), 1);
+
+		// State persisted before channel orders were supported has none of ours.
+		let restored = test_handler(Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &[][..]).unwrap();
+		assert!(outbound_orders(&restored).is_empty());
+	}
+}
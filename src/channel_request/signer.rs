@@ -0,0 +1,11 @@
+use bitcoin::secp256k1::ecdsa::RecoverableSignature;
+use bitcoin::secp256k1::Message;
+
+/// Signs the receipts of the channel orders we accept as an LSP.
+pub trait OrderReceiptSigner {
+	/// Signs the given message with our node key.
+	///
+	/// The message is a hash tagged with `lsps1receipt`, so its signature can't be taken for that
+	/// of an invoice or a gossip message. If no signature can be provided, the order is rejected.
+	fn sign_order_receipt(&self, message: &Message) -> Option<RecoverableSignature>;
+}
@@ -0,0 +1,11 @@
+use crate::channel_request::msgs::OrderId;
+
+use bitcoin::Address;
+
+/// Provides the addresses clients pay for the channels they order to on-chain.
+pub trait ChannelOrderWallet {
+	/// Returns a fresh address of our wallet, only to be used for the order with the given id.
+	///
+	/// If no address can be provided, the order can only be paid via Lightning.
+	fn new_order_address(&self, order_id: &OrderId) -> Option<Address>;
+}
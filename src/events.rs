@@ -13,6 +13,9 @@
 //! Because we don't have a built-in runtime, it's up to the end-user to poll
 //! [`crate::LiquidityManager::get_and_clear_pending_events()`] to receive events.
 
+use crate::channel_request::event::LSPS1Event;
+use crate::jit_channel::event::LSPS2Event;
+
 use std::collections::VecDeque;
 use std::sync::{Condvar, Mutex};
 
@@ -55,4 +58,9 @@ impl EventQueue {
 
 /// Event which you should probably take some action in response to.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Event {}
+pub enum Event {
+	/// An LSPS1 (Channel Request) protocol event.
+	LSPS1(LSPS1Event),
+	/// An LSPS2 (JIT Channel) protocol event.
+	LSPS2(LSPS2Event),
+}
@@ -0,0 +1,232 @@
+use bitcoin::{Transaction, Txid};
+use lightning::chain::chaininterface::BroadcasterInterface;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// A [`BroadcasterInterface`] that holds back the funding transactions of JIT channels sold in
+/// `client_trusts_lsp` mode until the client revealed the preimage of the initial payment.
+///
+/// Should be used as the broadcaster of the LSP's [`ChannelManager`]. All other transactions are
+/// passed through to the wrapped broadcaster.
+///
+/// The [`ChannelManager`] broadcasts a funding transaction only once, so the held transactions
+/// have to survive restarts until they are released. They are persisted by writing the
+/// broadcaster whenever [`get_and_clear_needs_persistence`] returns true, and restored by
+/// reading it back via [`ReadableArgs`] with the wrapped broadcaster as argument, before the
+/// [`ChannelManager`] is started.
+///
+/// If the channel is closed before the transaction is released, it is never broadcast and its
+/// inputs may be spent otherwise. It should then be released via [`forget_transaction`].
+///
+/// [`get_and_clear_needs_persistence`]: Self::get_and_clear_needs_persistence
+/// [`forget_transaction`]: Self::forget_transaction
+///
+/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+pub struct JITFundingBroadcaster<B: Deref>
+where
+	B::Target: BroadcasterInterface,
+{
+	inner: B,
+	held_transactions: Mutex<HashMap<Txid, Option<Transaction>>>,
+	needs_persistence: AtomicBool,
+}
+
+impl<B: Deref> JITFundingBroadcaster<B>
+where
+	B::Target: BroadcasterInterface,
+{
+	/// Creates a new broadcaster passing transactions through to the given one.
+	pub fn new(inner: B) -> Self {
+		Self {
+			inner,
+			held_transactions: Mutex::new(HashMap::new()),
+			needs_persistence: AtomicBool::new(false),
+		}
+	}
+
+	/// Holds back the transaction with the given txid, if it is broadcast before it is
+	/// [released].
+	///
+	/// [released]: Self::release_transaction
+	pub fn hold_transaction(&self, txid: Txid) {
+		self.held_transactions.lock().unwrap().entry(txid).or_insert(None);
+		self.needs_persistence.store(true, Ordering::Release);
+	}
+
+	/// Broadcasts the transaction with the given txid if it was held back, and stops holding it
+	/// otherwise.
+	pub fn release_transaction(&self, txid: &Txid) {
+		let transaction = self.held_transactions.lock().unwrap().remove(txid).and_then(|tx| tx);
+		self.needs_persistence.store(true, Ordering::Release);
+		if let Some(transaction) = transaction {
+			self.inner.broadcast_transactions(&[&transaction]);
+		}
+	}
+
+	/// Stops holding the transaction with the given txid without broadcasting it.
+	///
+	/// Should be called if the channel it funds was closed before the transaction was released.
+	pub fn forget_transaction(&self, txid: &Txid) {
+		self.held_transactions.lock().unwrap().remove(txid);
+		self.needs_persistence.store(true, Ordering::Release);
+	}
+
+	/// Checks whether the held transactions changed since the last call, in which case the
+	/// broadcaster should be persisted.
+	pub fn get_and_clear_needs_persistence(&self) -> bool {
+		self.needs_persistence.swap(false, Ordering::AcqRel)
+	}
+}
+
+impl<B: Deref> BroadcasterInterface for JITFundingBroadcaster<B>
+where
+	B::Target: BroadcasterInterface,
+{
+	fn broadcast_transactions(&self, txs: &[&Transaction]) {
+		let mut held_transactions = self.held_transactions.lock().unwrap();
+		let mut passed_txs = Vec::new();
+		for tx in txs {
+			match held_transactions.get_mut(&tx.txid()) {
+				Some(held_tx) => {
+					*held_tx = Some((*tx).clone());
+					self.needs_persistence.store(true, Ordering::Release);
+				}
+				None => passed_txs.push(*tx),
+			}
+		}
+		drop(held_transactions);
+
+		if !passed_txs.is_empty() {
+			self.inner.broadcast_transactions(&passed_txs);
+		}
+	}
+}
+
+impl<B: Deref> Writeable for JITFundingBroadcaster<B>
+where
+	B::Target: BroadcasterInterface,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let held_transactions = self.held_transactions.lock().unwrap();
+		SERIALIZATION_VERSION.write(writer)?;
+		(held_transactions.len() as u64).write(writer)?;
+		for (txid, transaction) in held_transactions.iter() {
+			txid.write(writer)?;
+			transaction.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<B: Deref> ReadableArgs<B> for JITFundingBroadcaster<B>
+where
+	B::Target: BroadcasterInterface,
+{
+	fn read<R: io::Read>(reader: &mut R, inner: B) -> Result<Self, DecodeError> {
+		let version: u8 = Readable::read(reader)?;
+		if version > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+
+		let num_transactions: u64 = Readable::read(reader)?;
+		let mut held_transactions = HashMap::new();
+		for _ in 0..num_transactions {
+			let txid: Txid = Readable::read(reader)?;
+			let transaction: Option<Transaction> = Readable::read(reader)?;
+			held_transactions.insert(txid, transaction);
+		}
+
+		Ok(Self {
+			inner,
+			held_transactions: Mutex::new(held_transactions),
+			needs_persistence: AtomicBool::new(false),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::PackedLockTime;
+	use std::sync::Arc;
+
+	#[derive(Default)]
+	struct TestBroadcaster {
+		broadcast_txids: Mutex<Vec<Txid>>,
+	}
+
+	impl BroadcasterInterface for TestBroadcaster {
+		fn broadcast_transactions(&self, txs: &[&Transaction]) {
+			self.broadcast_txids.lock().unwrap().extend(txs.iter().map(|tx| tx.txid()));
+		}
+	}
+
+	fn transaction(lock_time: u32) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: PackedLockTime(lock_time),
+			input: Vec::new(),
+			output: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn holds_funding_transaction_until_released() {
+		let inner = Arc::new(TestBroadcaster::default());
+		let broadcaster = JITFundingBroadcaster::new(Arc::clone(&inner));
+		let funding_tx = transaction(0);
+		let other_tx = transaction(1);
+
+		broadcaster.hold_transaction(funding_tx.txid());
+		broadcaster.broadcast_transactions(&[&funding_tx, &other_tx]);
+		assert_eq!(*inner.broadcast_txids.lock().unwrap(), vec![other_tx.txid()]);
+
+		broadcaster.release_transaction(&funding_tx.txid());
+		assert_eq!(
+			*inner.broadcast_txids.lock().unwrap(),
+			vec![other_tx.txid(), funding_tx.txid()]
+		);
+
+		// Once released, the transaction is passed through.
+		broadcaster.broadcast_transactions(&[&funding_tx]);
+		assert_eq!(inner.broadcast_txids.lock().unwrap().len(), 3);
+	}
+
+	#[test]
+	fn remembers_held_transactions_after_restart() {
+		let inner = Arc::new(TestBroadcaster::default());
+		let broadcaster = JITFundingBroadcaster::new(Arc::clone(&inner));
+		let funding_tx = transaction(0);
+		let forgotten_tx = transaction(1);
+
+		assert!(!broadcaster.get_and_clear_needs_persistence());
+		broadcaster.hold_transaction(funding_tx.txid());
+		broadcaster.hold_transaction(forgotten_tx.txid());
+		assert!(broadcaster.get_and_clear_needs_persistence());
+		broadcaster.broadcast_transactions(&[&funding_tx, &forgotten_tx]);
+		assert!(broadcaster.get_and_clear_needs_persistence());
+		broadcaster.forget_transaction(&forgotten_tx.txid());
+		assert!(broadcaster.get_and_clear_needs_persistence());
+
+		let restored_inner = Arc::new(TestBroadcaster::default());
+		let restored = JITFundingBroadcaster::read(
+			&mut &broadcaster.encode()[..],
+			Arc::clone(&restored_inner),
+		)
+		.unwrap();
+		assert!(!restored.get_and_clear_needs_persistence());
+
+		restored.release_transaction(&forgotten_tx.txid());
+		assert!(restored_inner.broadcast_txids.lock().unwrap().is_empty());
+		restored.release_transaction(&funding_tx.txid());
+		assert_eq!(*restored_inner.broadcast_txids.lock().unwrap(), vec![funding_tx.txid()]);
+		assert!(inner.broadcast_txids.lock().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,183 @@
+use crate::jit_channel::msgs::OpeningFeeParams;
+
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Txid;
+use lightning::ln::PaymentHash;
+use lightning_invoice::Bolt11Invoice;
+
+/// The reason we failed back the HTLCs intercepted for a JIT channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JITChannelFailureReason {
+	/// The payment did not cover the opening fee.
+	OpeningFeeNotCovered,
+	/// Opening the channel to the client failed.
+	ChannelOpenFailed,
+	/// The parts of the payment did not add up to the `payment_size_msat` the client bought the
+	/// channel for in time.
+	MPPTimeout,
+	/// The opening fee parameters the client bought the channel with expired before the payment
+	/// arrived. The intercept scid is no longer valid.
+	OpeningFeeParamsExpired,
+	/// The channel didn't get ready in time to forward the HTLCs before they would time out.
+	ChannelOpenTimeout,
+}
+
+/// The reason we failed to buy a JIT channel and create an invoice for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvoiceCreationFailureReason {
+	/// The LSP doesn't support any of the LSPS2 versions we do.
+	NoCommonVersion,
+	/// The payment size we asked for is outside of the range the LSP accepts.
+	PaymentSizeOutOfRange {
+		/// The minimum payment size the LSP accepts.
+		min_payment_size_msat: u64,
+		/// The maximum payment size the LSP accepts.
+		max_payment_size_msat: u64,
+	},
+	/// None of the opening fee parameters the LSP offered are valid for the payment size.
+	NoSuitableOpeningFeeParams,
+	/// The LSP responded with an error.
+	ErrorResponse {
+		/// The error code the LSP responded with.
+		code: i32,
+		/// The error message the LSP responded with.
+		message: String,
+	},
+	/// The LSP returned an intercept scid we couldn't parse.
+	InvalidScid,
+	/// Building or signing the invoice failed.
+	InvoiceGenerationFailed {
+		/// A description of the error.
+		error: String,
+	},
+	/// The LSP didn't respond in time, or we didn't select any of the opening fee parameters it
+	/// offered in time.
+	Timeout,
+}
+
+/// An event which an LSPS2 client or LSP should take some action in response to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Event {
+	/// The LSP we're buying a JIT channel from via [`LiquidityManager::jit_channel_create_invoice`]
+	/// offered opening fee parameters.
+	///
+	/// One of them has to be selected via
+	/// [`LiquidityManager::jit_channel_select_opening_fee_params`] to buy the channel. The fee
+	/// each of them would incur can be computed with [`compute_opening_fee`].
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	/// [`LiquidityManager::jit_channel_select_opening_fee_params`]: crate::LiquidityManager::jit_channel_select_opening_fee_params
+	/// [`compute_opening_fee`]: crate::compute_opening_fee
+	OpeningFeeParamsMenuReady {
+		/// The identifier passed into [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The node id of the LSP offering the channel.
+		counterparty_node_id: PublicKey,
+		/// The unexpired opening fee parameters that are valid for `payment_size_msat`, cheapest
+		/// first.
+		opening_fee_params_menu: Vec<OpeningFeeParams>,
+		/// The minimum payment size the LSP accepts.
+		min_payment_size_msat: u64,
+		/// The maximum payment size the LSP accepts.
+		max_payment_size_msat: u64,
+		/// The size of the initial payment we asked for, if any.
+		payment_size_msat: Option<u64>,
+	},
+	/// An invoice requested via [`LiquidityManager::jit_channel_create_invoice`] is ready.
+	///
+	/// Payments to the invoice will be routed through the LSP, which opens a channel to us once
+	/// the payment arrives and deducts its opening fee from the forwarded amount.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	InvoiceReady {
+		/// The identifier passed into [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The node id of the LSP that will open the channel.
+		counterparty_node_id: PublicKey,
+		/// The invoice including a route hint through the LSP.
+		invoice: Box<Bolt11Invoice>,
+		/// The opening fee parameters we bought the channel with.
+		opening_fee_params: OpeningFeeParams,
+		/// The size of the initial payment we told the LSP we'd receive, if any.
+		payment_size_msat: Option<u64>,
+		/// The opening fee the LSP will deduct from the initial payment, if its size is known.
+		///
+		/// See [`compute_opening_fee`].
+		///
+		/// [`compute_opening_fee`]: crate::compute_opening_fee
+		opening_fee_msat: Option<u64>,
+		/// Whether the LSP sold the channel in `client_trusts_lsp` mode.
+		///
+		/// In this mode the LSP only broadcasts the funding transaction after we claimed the
+		/// initial payment, so we have to trust it to do so.
+		client_trusts_lsp: bool,
+	},
+	/// Buying a JIT channel via [`LiquidityManager::jit_channel_create_invoice`] failed.
+	///
+	/// No invoice will be created, but another attempt may be made.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	InvoiceCreationFailed {
+		/// The identifier passed into [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The node id of the LSP we tried to buy the channel from.
+		counterparty_node_id: PublicKey,
+		/// Why buying the channel failed.
+		reason: InvoiceCreationFailureReason,
+	},
+	/// We failed back the HTLCs of a payment intercepted for a JIT channel a client bought from
+	/// us.
+	///
+	/// This is informational, no action is required.
+	HTLCsFailed {
+		/// The intercept scid the HTLCs were sent to.
+		intercept_scid: u64,
+		/// The node id of the client that bought the channel.
+		counterparty_node_id: PublicKey,
+		/// The payment hash of the failed HTLCs.
+		payment_hash: PaymentHash,
+		/// Why we failed the HTLCs.
+		reason: JITChannelFailureReason,
+	},
+	/// The client claimed a payment over a JIT channel we sold in `client_trusts_lsp` mode, so we
+	/// may broadcast its funding transaction.
+	///
+	/// The funding transaction should be released via
+	/// [`JITFundingBroadcaster::release_transaction`].
+	///
+	/// [`JITFundingBroadcaster::release_transaction`]: crate::JITFundingBroadcaster::release_transaction
+	FundingBroadcastSafe {
+		/// The intercept scid of the channel.
+		intercept_scid: u64,
+		/// The node id of the client that bought the channel.
+		counterparty_node_id: PublicKey,
+		/// The txid of the funding transaction to release.
+		funding_txid: Txid,
+	},
+	/// The LSP deducted more than the opening fee we agreed on from the payment to an invoice
+	/// created via [`LiquidityManager::jit_channel_create_invoice`].
+	///
+	/// The payment was failed back instead of being claimed.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	OpeningFeeOvercharged {
+		/// The identifier passed into [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The node id of the LSP that opened the channel.
+		counterparty_node_id: PublicKey,
+		/// The payment hash of the failed payment.
+		payment_hash: PaymentHash,
+		/// The maximum opening fee the LSP was allowed to deduct, or `None` if it overflows.
+		opening_fee_msat: Option<u64>,
+		/// The fee the LSP actually deducted.
+		skimmed_fee_msat: u64,
+	},
+}
@@ -7,4 +7,11 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-//! Types and primitives that implement the LSPS3: JIT Channel Negotiation specification.
+//! Types and primitives that implement the LSPS2: JIT Channel Negotiation specification.
+
+pub(crate) mod broadcaster;
+pub(crate) mod event;
+pub mod msgs;
+pub(crate) mod pricing;
+pub(crate) mod protocol;
+pub(crate) mod utils;
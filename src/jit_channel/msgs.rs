@@ -0,0 +1,458 @@
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::{fmt, io};
+
+use crate::jit_channel::utils::compute_opening_fee_params_promise;
+use crate::transport::msgs::{string_amount, string_amount_option};
+use crate::transport::msgs::{LSPSDateTime, LSPSMessage, RequestId, ResponseError};
+
+pub(crate) const LSPS2_GET_VERSIONS_METHOD_NAME: &str = "lsps2.get_versions";
+pub(crate) const LSPS2_GET_INFO_METHOD_NAME: &str = "lsps2.get_info";
+pub(crate) const LSPS2_BUY_METHOD_NAME: &str = "lsps2.buy";
+
+pub(crate) const LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
+pub(crate) const LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE: i32 = 2;
+
+pub(crate) const LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
+pub(crate) const LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE: i32 = 2;
+pub(crate) const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE: i32 = 3;
+pub(crate) const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE: i32 = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct GetVersionsRequest {}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GetVersionsResponse {
+	pub versions: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GetInfoRequest {
+	pub version: u16,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub token: Option<String>,
+}
+
+/// Fees and parameters for a JIT channel without the promise.
+///
+/// The promise will be calculated automatically for the LSP and this type converted
+/// into an [`OpeningFeeParams`] for transit over the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawOpeningFeeParams {
+	/// The minimum fee required for the channel open.
+	pub min_fee_msat: u64,
+	/// A fee proportional to the size of the initial payment.
+	pub proportional: u32,
+	/// An [`ISO8601`](https://www.iso.org/iso-8601-date-and-time-format.html) formatted date for which these params are valid.
+	pub valid_until: LSPSDateTime,
+	/// The number of blocks after confirmation that the LSP promises it will keep the channel alive without closing.
+	pub min_lifetime: u32,
+	/// The maximum number of blocks that the client is allowed to set its `to_self_delay` parameter.
+	pub max_client_to_self_delay: u32,
+}
+
+impl RawOpeningFeeParams {
+	pub(crate) fn into_opening_fee_params(self, promise_secret: &[u8; 32]) -> OpeningFeeParams {
+		let promise = compute_opening_fee_params_promise(
+			self.min_fee_msat,
+			self.proportional,
+			&self.valid_until,
+			self.min_lifetime,
+			self.max_client_to_self_delay,
+			promise_secret,
+		);
+		OpeningFeeParams {
+			min_fee_msat: self.min_fee_msat,
+			proportional: self.proportional,
+			valid_until: self.valid_until,
+			min_lifetime: self.min_lifetime,
+			max_client_to_self_delay: self.max_client_to_self_delay,
+			promise,
+		}
+	}
+}
+
+/// Fees and parameters for a JIT channel including the promise.
+///
+/// The promise is an HMAC calculated using a secret known to the LSP and the rest of the fields as input.
+/// It exists so the LSP can verify the authenticity of a client provided [`OpeningFeeParams`] by recalculating
+/// the promise using the secret. Once verified they can be confident it was not modified by the client.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OpeningFeeParams {
+	/// The minimum fee required for the channel open.
+	#[serde(with = "string_amount")]
+	pub min_fee_msat: u64,
+	/// A fee proportional to the size of the initial payment.
+	pub proportional: u32,
+	/// An [`ISO8601`](https://www.iso.org/iso-8601-date-and-time-format.html) formatted date for which these params are valid.
+	pub valid_until: LSPSDateTime,
+	/// The number of blocks after confirmation that the LSP promises it will keep the channel alive without closing.
+	pub min_lifetime: u32,
+	/// The maximum number of blocks that the client is allowed to set its `to_self_delay` parameter.
+	pub max_client_to_self_delay: u32,
+	/// The HMAC used to verify the authenticity of these parameters.
+	pub promise: String,
+}
+
+impl Writeable for OpeningFeeParams {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.min_fee_msat.write(writer)?;
+		self.proportional.write(writer)?;
+		self.valid_until.write(writer)?;
+		self.min_lifetime.write(writer)?;
+		self.max_client_to_self_delay.write(writer)?;
+		self.promise.write(writer)
+	}
+}
+
+impl Readable for OpeningFeeParams {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let min_fee_msat = Readable::read(reader)?;
+		let proportional = Readable::read(reader)?;
+		let valid_until = Readable::read(reader)?;
+		let min_lifetime = Readable::read(reader)?;
+		let max_client_to_self_delay = Readable::read(reader)?;
+		let promise = Readable::read(reader)?;
+
+		Ok(Self {
+			min_fee_msat,
+			proportional,
+			valid_until,
+			min_lifetime,
+			max_client_to_self_delay,
+			promise,
+		})
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GetInfoResponse {
+	pub opening_fee_params_menu: Vec<OpeningFeeParams>,
+	#[serde(with = "string_amount")]
+	pub min_payment_size_msat: u64,
+	#[serde(with = "string_amount")]
+	pub max_payment_size_msat: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuyRequest {
+	pub version: u16,
+	pub opening_fee_params: OpeningFeeParams,
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	#[serde(with = "string_amount_option")]
+	pub payment_size_msat: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct JitChannelScid(pub String);
+
+impl JitChannelScid {
+	pub fn to_scid(&self) -> Result<u64, ()> {
+		let mut parts = self.0.split('x');
+
+		let block = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+		let tx_index = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+		let output_index = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+
+		if parts.next().is_some()
+			|| block > 0xFF_FFFF
+			|| tx_index > 0xFF_FFFF
+			|| output_index > 0xFFFF
+		{
+			return Err(());
+		}
+
+		Ok((block << 40) | (tx_index << 16) | output_index)
+	}
+}
+
+impl From<u64> for JitChannelScid {
+	fn from(scid: u64) -> Self {
+		let block = scid >> 40;
+		let tx_index = (scid >> 16) & 0xFF_FFFF;
+		let output_index = scid & 0xFFFF;
+		Self(format!("{}x{}x{}", block, tx_index, output_index))
+	}
+}
+
+impl fmt::Display for JitChannelScid {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuyResponse {
+	pub jit_channel_scid: JitChannelScid,
+	pub lsp_cltv_expiry_delta: u32,
+	#[serde(default)]
+	pub client_trusts_lsp: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Request {
+	GetVersions(GetVersionsRequest),
+	GetInfo(GetInfoRequest),
+	Buy(BuyRequest),
+}
+
+impl LSPS2Request {
+	pub fn method(&self) -> &str {
+		match self {
+			LSPS2Request::GetVersions(_) => LSPS2_GET_VERSIONS_METHOD_NAME,
+			LSPS2Request::GetInfo(_) => LSPS2_GET_INFO_METHOD_NAME,
+			LSPS2Request::Buy(_) => LSPS2_BUY_METHOD_NAME,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Response {
+	GetVersions(GetVersionsResponse),
+	GetVersionsError(ResponseError),
+	GetInfo(GetInfoResponse),
+	GetInfoError(ResponseError),
+	Buy(BuyResponse),
+	BuyError(ResponseError),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Message {
+	Request(RequestId, LSPS2Request),
+	Response(RequestId, LSPS2Response),
+}
+
+impl TryFrom<LSPSMessage> for LSPS2Message {
+	type Error = ();
+
+	fn try_from(message: LSPSMessage) -> Result<Self, Self::Error> {
+		match message {
+			LSPSMessage::LSPS2(message) => Ok(message),
+			_ => Err(()),
+		}
+	}
+}
+
+impl From<LSPS2Message> for LSPSMessage {
+	fn from(message: LSPS2Message) -> Self {
+		LSPSMessage::LSPS2(message)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn opening_fee_params() -> OpeningFeeParams {
+		OpeningFeeParams {
+			min_fee_msat: 546000,
+			proportional: 1200,
+			valid_until: LSPSDateTime::from_rfc3339("2023-02-23T08:47:30.511Z").unwrap(),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+			promise: "abcdefghijklmnopqrstuvwxyz".to_string(),
+		}
+	}
+
+	fn round_trip(message: LSPSMessage, request_id_to_method: &mut HashMap<String, String>) {
+		if let Some((request_id, method)) = message.get_request_id_and_method() {
+			request_id_to_method.insert(request_id, method);
+		}
+		let json = serde_json::to_string(&message).unwrap();
+		let parsed = LSPSMessage::from_str_with_id_map(&json, request_id_to_method).unwrap();
+		assert_eq!(parsed, message);
+	}
+
+	#[test]
+	fn serializes_get_info_request() {
+		let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+			RequestId("request:id:xyz123".to_string()),
+			LSPS2Request::GetInfo(GetInfoRequest { version: 1, token: None }),
+		));
+		let json = serde_json::to_string(&request).unwrap();
+		assert_eq!(
+			json,
+			r#"{"jsonrpc":"2.0","method":"lsps2.get_info","id":"request:id:xyz123","params":{"version":1}}"#
+		);
+	}
+
+	#[test]
+	fn deserializes_buy_request() {
+		let json = r#"{
+			"jsonrpc": "2.0",
+			"id": "request:id:xyz123",
+			"method": "lsps2.buy",
+			"params": {
+				"version": 1,
+				"opening_fee_params": {
+					"min_fee_msat": "546000",
+					"proportional": 1200,
+					"valid_until": "2023-02-23T08:47:30.511Z",
+					"min_lifetime": 1008,
+					"max_client_to_self_delay": 2016,
+					"promise": "abcdefghijklmnopqrstuvwxyz"
+				},
+				"payment_size_msat": "42000"
+			}
+		}"#;
+
+		let mut request_id_to_method = HashMap::new();
+		let msg = LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method).unwrap();
+		assert_eq!(
+			msg,
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId("request:id:xyz123".to_string()),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params(),
+					payment_size_msat: Some(42000),
+				})
+			))
+		);
+		assert_eq!(
+			request_id_to_method.get("request:id:xyz123"),
+			Some(&LSPS2_BUY_METHOD_NAME.to_string())
+		);
+	}
+
+	#[test]
+	fn requests_round_trip() {
+		let mut request_id_to_method = HashMap::new();
+
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId("get_versions".to_string()),
+				LSPS2Request::GetVersions(GetVersionsRequest {}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId("get_info".to_string()),
+				LSPS2Request::GetInfo(GetInfoRequest {
+					version: 1,
+					token: Some("coupon".to_string()),
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId("buy".to_string()),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params(),
+					payment_size_msat: None,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+	}
+
+	#[test]
+	fn responses_round_trip() {
+		let mut request_id_to_method = HashMap::new();
+		request_id_to_method
+			.insert("get_versions".to_string(), LSPS2_GET_VERSIONS_METHOD_NAME.to_string());
+		request_id_to_method.insert("get_info".to_string(), LSPS2_GET_INFO_METHOD_NAME.to_string());
+		request_id_to_method.insert("buy".to_string(), LSPS2_BUY_METHOD_NAME.to_string());
+
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("get_versions".to_string()),
+				LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("get_info".to_string()),
+				LSPS2Response::GetInfo(GetInfoResponse {
+					opening_fee_params_menu: vec![opening_fee_params()],
+					min_payment_size_msat: 1000,
+					max_payment_size_msat: 1_000_000,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("get_info".to_string()),
+				LSPS2Response::GetInfoError(ResponseError {
+					code: LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+					message: "unrecognized_or_stale_token".to_string(),
+					data: None,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("buy".to_string()),
+				LSPS2Response::Buy(BuyResponse {
+					jit_channel_scid: JitChannelScid("29451x4815x1".to_string()),
+					lsp_cltv_expiry_delta: 144,
+					client_trusts_lsp: false,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+		round_trip(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("buy".to_string()),
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+					message: "payment_size_too_small".to_string(),
+					data: None,
+				}),
+			)),
+			&mut request_id_to_method,
+		);
+	}
+
+	#[test]
+	fn deserializes_buy_response_without_client_trusts_lsp() {
+		let json = r#"{
+			"jsonrpc": "2.0",
+			"id": "request:id:xyz123",
+			"result": {
+				"jit_channel_scid": "29451x4815x1",
+				"lsp_cltv_expiry_delta": 144
+			}
+		}"#;
+		let mut request_id_to_method = HashMap::new();
+		request_id_to_method
+			.insert("request:id:xyz123".to_string(), LSPS2_BUY_METHOD_NAME.to_string());
+
+		let msg = LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method).unwrap();
+		assert_eq!(
+			msg,
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("request:id:xyz123".to_string()),
+				LSPS2Response::Buy(BuyResponse {
+					jit_channel_scid: JitChannelScid("29451x4815x1".to_string()),
+					lsp_cltv_expiry_delta: 144,
+					client_trusts_lsp: false,
+				})
+			))
+		);
+	}
+
+	#[test]
+	fn jit_channel_scid_conversion() {
+		let scid = JitChannelScid("29451x4815x1".to_string());
+		let scid_u64 = scid.to_scid().unwrap();
+		assert_eq!(scid_u64, (29451 << 40) | (4815 << 16) | 1);
+		assert_eq!(JitChannelScid::from(scid_u64), scid);
+
+		assert!(JitChannelScid("29451x4815".to_string()).to_scid().is_err());
+		assert!(JitChannelScid("29451x4815x1x1".to_string()).to_scid().is_err());
+		assert!(JitChannelScid("29451x4815x65536".to_string()).to_scid().is_err());
+		assert!(JitChannelScid("foo".to_string()).to_scid().is_err());
+	}
+}
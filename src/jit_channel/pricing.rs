@@ -0,0 +1,14 @@
+use crate::jit_channel::msgs::RawOpeningFeeParams;
+
+use bitcoin::secp256k1::PublicKey;
+
+/// Prices the JIT channels we sell as an LSP.
+pub trait JITChannelPricing {
+	/// Generates the opening fee parameters offered to a client in response to `lsps2.get_info`.
+	///
+	/// Called with the client's node id and the token it provided, if any. Returning `None`
+	/// rejects the request because the token is unrecognized or stale.
+	fn opening_fee_params_menu(
+		&self, counterparty_node_id: &PublicKey, token: Option<&str>,
+	) -> Option<Vec<RawOpeningFeeParams>>;
+}
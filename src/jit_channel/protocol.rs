@@ -0,0 +1,3536 @@
+use bitcoin::bech32::ToBase32;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Network, Txid};
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channelmanager::{InterceptId, MIN_FINAL_CLTV_EXPIRY_DELTA};
+use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError};
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::sign::{EntropySource, NodeSigner, Recipient};
+use lightning::util::errors::APIError;
+use lightning::util::logger::Level;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::channel_manager::ChannelManagerInterface;
+use crate::events::{Event, EventQueue};
+use crate::jit_channel::event::{
+	InvoiceCreationFailureReason, JITChannelFailureReason, LSPS2Event,
+};
+use crate::jit_channel::msgs::{
+	BuyRequest, BuyResponse, GetInfoRequest, GetInfoResponse, GetVersionsRequest,
+	GetVersionsResponse, JitChannelScid, LSPS2Message, LSPS2Request, LSPS2Response,
+	OpeningFeeParams, LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+	LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+	LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE,
+	LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+};
+use crate::jit_channel::pricing::JITChannelPricing;
+use crate::jit_channel::utils::{
+	compute_amounts_to_forward_msat, compute_channel_size_sat, compute_opening_fee,
+	is_expired_opening_fee_params, is_valid_opening_fee_params, qualifying_opening_fee_params,
+};
+use crate::transport::message_handler::ProtocolMessageHandler;
+use crate::transport::msgs::{LSPSDateTime, LSPSMessage, RequestId, ResponseError};
+use crate::utils;
+
+const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
+
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// The number of timer ticks after which we give up waiting for the remaining parts of an
+/// initial payment.
+const MPP_TIMEOUT_TICKS: u8 = 2;
+
+/// The number of timer ticks we hold HTLCs for a JIT channel that is being opened, after which
+/// they are failed back rather than left to time out.
+const CHANNEL_OPEN_TIMEOUT_TICKS: u8 = 10;
+
+/// The number of timer ticks after which we give up waiting for an LSP to respond to a request
+/// made while buying a JIT channel.
+const REQUEST_TIMEOUT_TICKS: u8 = 5;
+
+/// Configuration options for JIT channels.
+#[derive(Clone, Debug)]
+pub struct JITChannelsConfig<JP: Deref>
+where
+	JP::Target: JITChannelPricing,
+{
+	/// Used to calculate the promise for channel parameters supplied to clients.
+	///
+	/// If not set, a random secret will be drawn from the node's
+	/// [`EntropySource`](lightning::sign::EntropySource) on startup.
+	///
+	/// Promises made with a previous secret, whether it was rotated via
+	/// [`LiquidityManager::rotate_promise_secret`] or changed here across a restart of a persisted
+	/// [`LiquidityManager`], are accepted until the opening fee parameters promised with it
+	/// expire.
+	///
+	/// [`LiquidityManager::rotate_promise_secret`]: crate::LiquidityManager::rotate_promise_secret
+	/// [`LiquidityManager`]: crate::LiquidityManager
+	pub promise_secret: Option<[u8; 32]>,
+	/// Generates the opening fee parameters offered to clients in response to `lsps2.get_info`.
+	pub pricing: JP,
+	/// The minimum payment size we are willing to accept.
+	pub min_payment_size_msat: u64,
+	/// The maximum payment size we are willing to accept.
+	pub max_payment_size_msat: u64,
+	/// The `cltv_expiry_delta` clients need to use in the route hint to our intercept scids.
+	///
+	/// Should be at least the `cltv_expiry_delta` we use when forwarding over our channels.
+	pub cltv_expiry_delta: u32,
+	/// The minimum size of the channels we open to clients.
+	pub min_channel_size_sat: u64,
+	/// How much larger than the forwarded payment the channels we open are, in parts per
+	/// million.
+	///
+	/// Leaves room for the channel reserve and commitment fees, as well as future payments.
+	pub channel_overprovisioning_ppm: u32,
+	/// Whether we sell channels in `client_trusts_lsp` mode.
+	///
+	/// In this mode we hold back the funding transaction until the client revealed the preimage
+	/// of the initial payment, which requires using a [`JITFundingBroadcaster`].
+	///
+	/// [`JITFundingBroadcaster`]: crate::JITFundingBroadcaster
+	pub client_trusts_lsp: bool,
+}
+
+/// Configuration options for buying JIT channels as a client.
+#[derive(Clone, Debug)]
+pub struct JITChannelsClientConfig {
+	/// The node ids of the LSPs whose JIT channels we accept as zero-conf.
+	///
+	/// Channels are only accepted as zero-conf while we have an unpaid invoice for a JIT channel
+	/// bought from the LSP.
+	pub trusted_lsp_node_ids: Vec<PublicKey>,
+}
+
+/// A JIT channel a client bought from us, identified by the intercept scid we issued for it.
+///
+/// Payments to the client's invoice will be intercepted on this scid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboundJITChannel {
+	/// The intercept scid we issued to the client.
+	pub intercept_scid: u64,
+	/// The node id of the client that bought the channel.
+	pub counterparty_node_id: PublicKey,
+	/// The opening fee parameters the client bought the channel with.
+	pub opening_fee_params: OpeningFeeParams,
+	/// The size of the initial payment the client expects to receive, if any.
+	pub payment_size_msat: Option<u64>,
+	/// Whether we sold the channel in `client_trusts_lsp` mode.
+	pub client_trusts_lsp: bool,
+}
+
+impl_writeable_tlv_based!(OutboundJITChannel, {
+	(0, intercept_scid, required),
+	(2, counterparty_node_id, required),
+	(4, opening_fee_params, required),
+	(6, payment_size_msat, option),
+	(8, client_trusts_lsp, required),
+});
+
+/// A JIT channel we bought from an LSP, identified by the payment hash of the invoice we created
+/// for it.
+///
+/// Kept until the initial payment is claimed or the invoice expires, so we can verify the opening
+/// fee the LSP deducted from it.
+struct InboundJITPayment {
+	payment_hash: PaymentHash,
+	user_channel_id: u128,
+	counterparty_node_id: PublicKey,
+	opening_fee_params: OpeningFeeParams,
+	payment_size_msat: Option<u64>,
+	/// The time the invoice expires at, in seconds since the UNIX epoch.
+	expires_at: u64,
+	/// The `user_channel_id` of the zero-conf channel we accepted from the LSP for this buy, if
+	/// any. Each buy is good for a single zero-conf channel.
+	zero_conf_user_channel_id: Option<u128>,
+}
+
+impl_writeable_tlv_based!(InboundJITPayment, {
+	(0, payment_hash, required),
+	(2, user_channel_id, required),
+	(4, counterparty_node_id, required),
+	(6, opening_fee_params, required),
+	(8, payment_size_msat, option),
+	(10, expires_at, required),
+	(12, zero_conf_user_channel_id, option),
+});
+
+struct InboundJITChannelConfig {
+	user_channel_id: u128,
+	payment_size_msat: Option<u64>,
+	token: Option<String>,
+	description: String,
+	expiry_secs: u32,
+}
+
+enum InboundJITChannelState {
+	VersionsRequested,
+	MenuRequested { version: u16 },
+	MenuReceived { version: u16, opening_fee_params_menu: Vec<OpeningFeeParams> },
+	BuyRequested { opening_fee_params: OpeningFeeParams },
+}
+
+struct InboundJITChannel {
+	state: InboundJITChannelState,
+	config: InboundJITChannelConfig,
+	/// The number of timer ticks since we sent the pending request, or since we received the
+	/// menu we have to select from.
+	ticks_elapsed: u8,
+}
+
+struct PeerState {
+	inbound_channels_by_request_id: HashMap<RequestId, InboundJITChannel>,
+}
+
+impl PeerState {
+	fn new() -> Self {
+		Self { inbound_channels_by_request_id: HashMap::new() }
+	}
+}
+
+struct InterceptedHTLC {
+	intercept_id: InterceptId,
+	expected_outbound_amount_msat: u64,
+	payment_hash: PaymentHash,
+}
+
+#[derive(Default)]
+struct PendingPayment {
+	htlcs: Vec<InterceptedHTLC>,
+	ticks_elapsed: u8,
+}
+
+enum OutboundJITChannelState {
+	/// We hold the parts of the initial payments, by payment hash, until one of them reaches the
+	/// `payment_size_msat` the client bought the channel for.
+	PendingInitialPayment { payments: HashMap<PaymentHash, PendingPayment> },
+	/// We are opening the channel and hold the intercepted HTLCs until it is ready.
+	///
+	/// The opening fee is spread across the parts of the initial payment, while any other HTLCs
+	/// are forwarded in full. `ticks_elapsed` counts the timer ticks HTLCs have been held for.
+	PendingChannelOpen {
+		payment_hash: PaymentHash,
+		opening_fee_msat: u64,
+		htlcs: Vec<InterceptedHTLC>,
+		queued_htlcs: Vec<InterceptedHTLC>,
+		ticks_elapsed: u8,
+	},
+	/// The channel is ready, any further HTLCs are forwarded over it as they arrive.
+	ChannelReady { channel_id: [u8; 32] },
+}
+
+/// The part of the state of a JIT channel we opened, or started to open, that survives restarts.
+///
+/// HTLCs still held for the channel are not persisted, as the [`ChannelManager`] regenerates an
+/// `HTLCIntercepted` event for each of them on startup.
+///
+/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+struct OpenedJITChannel {
+	intercept_scid: u64,
+	user_channel_id: u128,
+	/// The initial payment and the fee it pays, while the channel is pending.
+	payment_hash: Option<PaymentHash>,
+	opening_fee_msat: Option<u64>,
+	/// The id of the channel, once it is ready.
+	channel_id: Option<[u8; 32]>,
+	/// The txid of the funding transaction we hold back until the client claimed a payment.
+	held_funding_txid: Option<Txid>,
+}
+
+impl_writeable_tlv_based!(OpenedJITChannel, {
+	(0, intercept_scid, required),
+	(2, user_channel_id, required),
+	(4, payment_hash, option),
+	(6, opening_fee_msat, option),
+	(8, channel_id, option),
+	(10, held_funding_txid, option),
+});
+
+impl OpenedJITChannel {
+	fn into_state(self) -> Option<OutboundJITChannelState> {
+		match (self.channel_id, self.payment_hash, self.opening_fee_msat) {
+			(Some(channel_id), _, _) => Some(OutboundJITChannelState::ChannelReady { channel_id }),
+			(None, Some(payment_hash), Some(opening_fee_msat)) => {
+				Some(OutboundJITChannelState::PendingChannelOpen {
+					payment_hash,
+					opening_fee_msat,
+					htlcs: Vec::new(),
+					queued_htlcs: Vec::new(),
+					ticks_elapsed: 0,
+				})
+			}
+			_ => None,
+		}
+	}
+}
+
+impl OutboundJITChannelState {
+	fn is_channel_opened(&self) -> bool {
+		match self {
+			OutboundJITChannelState::PendingInitialPayment { .. } => false,
+			OutboundJITChannelState::PendingChannelOpen { .. }
+			| OutboundJITChannelState::ChannelReady { .. } => true,
+		}
+	}
+}
+
+#[derive(Default)]
+struct OutboundJITChannels {
+	channels_by_scid: HashMap<u64, OutboundJITChannel>,
+	scids_by_counterparty: HashMap<PublicKey, Vec<u64>>,
+	states_by_scid: HashMap<u64, OutboundJITChannelState>,
+	scids_by_user_channel_id: HashMap<u128, u64>,
+	held_funding_txids_by_scid: HashMap<u64, Txid>,
+}
+
+impl OutboundJITChannels {
+	fn insert(&mut self, channel: OutboundJITChannel) {
+		self.scids_by_counterparty
+			.entry(channel.counterparty_node_id)
+			.or_default()
+			.push(channel.intercept_scid);
+		self.channels_by_scid.insert(channel.intercept_scid, channel);
+	}
+
+	fn remove(
+		&mut self, intercept_scid: u64,
+	) -> Option<(OutboundJITChannel, Option<OutboundJITChannelState>)> {
+		let channel = self.channels_by_scid.remove(&intercept_scid)?;
+		if let Some(scids) = self.scids_by_counterparty.get_mut(&channel.counterparty_node_id) {
+			scids.retain(|scid| *scid != intercept_scid);
+			if scids.is_empty() {
+				self.scids_by_counterparty.remove(&channel.counterparty_node_id);
+			}
+		}
+		self.scids_by_user_channel_id.retain(|_, scid| *scid != intercept_scid);
+		self.held_funding_txids_by_scid.remove(&intercept_scid);
+		let state = self.states_by_scid.remove(&intercept_scid);
+		Some((channel, state))
+	}
+
+	fn get_for_counterparty(&self, counterparty_node_id: &PublicKey) -> Vec<OutboundJITChannel> {
+		self.scids_by_counterparty
+			.get(counterparty_node_id)
+			.map(|scids| {
+				scids.iter().filter_map(|scid| self.channels_by_scid.get(scid)).cloned().collect()
+			})
+			.unwrap_or_default()
+	}
+}
+
+/// A secret we made promises for opening fee parameters with.
+struct PromiseSecret {
+	secret: [u8; 32],
+	/// The latest `valid_until` of the opening fee parameters we promised with the secret.
+	valid_until: Option<LSPSDateTime>,
+}
+
+impl PromiseSecret {
+	fn new(secret: [u8; 32]) -> Self {
+		Self { secret, valid_until: None }
+	}
+
+	/// Records a promise valid until the given time, returning whether that extended the secret's
+	/// lifetime.
+	fn promised_until(&mut self, valid_until: LSPSDateTime) -> bool {
+		if self.valid_until.map_or(true, |current| current < valid_until) {
+			self.valid_until = Some(valid_until);
+			true
+		} else {
+			false
+		}
+	}
+
+	fn has_valid_promises(&self) -> bool {
+		self.valid_until.map_or(false, |valid_until| !valid_until.is_past())
+	}
+}
+
+impl Writeable for PromiseSecret {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.secret.write(writer)?;
+		self.valid_until.write(writer)
+	}
+}
+
+impl Readable for PromiseSecret {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let secret = Readable::read(reader)?;
+		let valid_until = Readable::read(reader)?;
+		Ok(Self { secret, valid_until })
+	}
+}
+
+/// The secret we currently make promises with, and the previous ones we still accept promises of.
+struct PromiseSecrets {
+	current: PromiseSecret,
+	previous: Vec<PromiseSecret>,
+}
+
+impl PromiseSecrets {
+	fn rotate(&mut self, secret: [u8; 32]) {
+		let previous = std::mem::replace(&mut self.current, PromiseSecret::new(secret));
+		self.previous.push(previous);
+		self.retire_expired();
+	}
+
+	/// Forgets the previous secrets whose promises all expired.
+	fn retire_expired(&mut self) -> bool {
+		let num_previous = self.previous.len();
+		self.previous.retain(|secret| secret.has_valid_promises());
+		self.previous.len() != num_previous
+	}
+
+	fn is_valid(&self, opening_fee_params: &OpeningFeeParams) -> bool {
+		std::iter::once(&self.current)
+			.chain(self.previous.iter())
+			.any(|secret| is_valid_opening_fee_params(opening_fee_params, &secret.secret))
+	}
+}
+
+struct ServiceState<JP: Deref>
+where
+	JP::Target: JITChannelPricing,
+{
+	config: JITChannelsConfig<JP>,
+	promise_secrets: Mutex<PromiseSecrets>,
+	outbound_channels: Mutex<OutboundJITChannels>,
+}
+
+pub struct LSPS2MessageHandler<ES: Deref, CM: Deref, NS: Deref, JP: Deref>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+{
+	entropy_source: ES,
+	channel_manager: CM,
+	node_signer: NS,
+	network: Network,
+	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+	pending_events: Arc<EventQueue>,
+	per_peer_state: Mutex<HashMap<PublicKey, PeerState>>,
+	service_state: Option<ServiceState<JP>>,
+	trusted_lsp_node_ids: Vec<PublicKey>,
+	inbound_payments: Mutex<HashMap<PaymentHash, InboundJITPayment>>,
+	needs_persistence: AtomicBool,
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, JP: Deref> LSPS2MessageHandler<ES, CM, NS, JP>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+{
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(
+		entropy_source: ES, channel_manager: CM, node_signer: NS, network: Network,
+		config: Option<JITChannelsConfig<JP>>, client_config: Option<JITChannelsClientConfig>,
+		pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		pending_events: Arc<EventQueue>,
+	) -> Self {
+		let service_state = config.map(|config| {
+			let promise_secret =
+				config.promise_secret.unwrap_or_else(|| entropy_source.get_secure_random_bytes());
+			ServiceState {
+				config,
+				promise_secrets: Mutex::new(PromiseSecrets {
+					current: PromiseSecret::new(promise_secret),
+					previous: Vec::new(),
+				}),
+				outbound_channels: Mutex::new(OutboundJITChannels::default()),
+			}
+		});
+
+		Self {
+			entropy_source,
+			channel_manager,
+			node_signer,
+			network,
+			pending_messages,
+			pending_events,
+			per_peer_state: Mutex::new(HashMap::new()),
+			service_state,
+			trusted_lsp_node_ids: client_config
+				.map(|client_config| client_config.trusted_lsp_node_ids)
+				.unwrap_or_default(),
+			inbound_payments: Mutex::new(HashMap::new()),
+			needs_persistence: AtomicBool::new(false),
+		}
+	}
+
+	pub fn create_invoice(
+		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
+		token: Option<String>, description: String, expiry_secs: u32, user_channel_id: u128,
+	) {
+		let channel = InboundJITChannel {
+			state: InboundJITChannelState::VersionsRequested,
+			config: InboundJITChannelConfig {
+				user_channel_id,
+				payment_size_msat,
+				token,
+				description,
+				expiry_secs,
+			},
+			ticks_elapsed: 0,
+		};
+
+		let request_id = utils::generate_request_id(&self.entropy_source);
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let peer_state =
+				per_peer_state.entry(counterparty_node_id).or_insert_with(PeerState::new);
+			peer_state.inbound_channels_by_request_id.insert(request_id.clone(), channel);
+		}
+
+		self.enqueue_request(
+			counterparty_node_id,
+			request_id,
+			LSPS2Request::GetVersions(GetVersionsRequest {}),
+		);
+	}
+
+	pub fn select_opening_fee_params(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+		opening_fee_params: OpeningFeeParams,
+	) -> Result<(), APIError> {
+		let (channel, version) = {
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let channels = per_peer_state
+				.get_mut(counterparty_node_id)
+				.map(|peer_state| &mut peer_state.inbound_channels_by_request_id);
+
+			let offer = channels.as_ref().and_then(|channels| {
+				channels.iter().find_map(|(request_id, channel)| match &channel.state {
+					InboundJITChannelState::MenuReceived { version, opening_fee_params_menu }
+						if channel.config.user_channel_id == user_channel_id =>
+					{
+						let is_offered = opening_fee_params_menu.contains(&opening_fee_params);
+						Some((request_id.clone(), *version, is_offered))
+					}
+					_ => None,
+				})
+			});
+
+			match (channels, offer) {
+				(Some(channels), Some((request_id, version, true))) => {
+					if is_expired_opening_fee_params(&opening_fee_params) {
+						return Err(APIError::APIMisuseError {
+							err: "The selected opening fee parameters have expired".to_string(),
+						});
+					}
+					match channels.remove(&request_id) {
+						Some(channel) => (channel, version),
+						None => {
+							return Err(APIError::APIMisuseError {
+								err: format!(
+									"Channel {} is no longer awaiting selection",
+									user_channel_id
+								),
+							});
+						}
+					}
+				}
+				(_, Some(_)) => {
+					return Err(APIError::APIMisuseError {
+						err: format!(
+							"The selected opening fee parameters were not offered by {}",
+							counterparty_node_id
+						),
+					});
+				}
+				(_, None) => {
+					return Err(APIError::APIMisuseError {
+						err: format!(
+							"No opening fee parameters from {} are awaiting selection for channel {}",
+							counterparty_node_id, user_channel_id
+						),
+					});
+				}
+			}
+		};
+
+		let request = LSPS2Request::Buy(BuyRequest {
+			version,
+			opening_fee_params: opening_fee_params.clone(),
+			payment_size_msat: channel.config.payment_size_msat,
+		});
+		self.advance_inbound_channel(
+			counterparty_node_id,
+			channel,
+			InboundJITChannelState::BuyRequested { opening_fee_params },
+			request,
+		);
+		Ok(())
+	}
+
+	pub fn outbound_channel_by_scid(&self, intercept_scid: u64) -> Option<OutboundJITChannel> {
+		self.service_state.as_ref().and_then(|service_state| {
+			service_state
+				.outbound_channels
+				.lock()
+				.unwrap()
+				.channels_by_scid
+				.get(&intercept_scid)
+				.cloned()
+		})
+	}
+
+	pub fn outbound_channels_for_counterparty(
+		&self, counterparty_node_id: &PublicKey,
+	) -> Vec<OutboundJITChannel> {
+		self.service_state
+			.as_ref()
+			.map(|service_state| {
+				service_state
+					.outbound_channels
+					.lock()
+					.unwrap()
+					.get_for_counterparty(counterparty_node_id)
+			})
+			.unwrap_or_default()
+	}
+
+	pub fn htlc_intercepted(
+		&self, intercept_scid: u64, intercept_id: InterceptId, expected_outbound_amount_msat: u64,
+		payment_hash: PaymentHash,
+	) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Ok(()),
+		};
+
+		let htlc = InterceptedHTLC { intercept_id, expected_outbound_amount_msat, payment_hash };
+
+		let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+		let channel = match outbound_channels.channels_by_scid.get(&intercept_scid) {
+			Some(channel) => channel.clone(),
+			None => return Ok(()),
+		};
+
+		let state = outbound_channels.states_by_scid.remove(&intercept_scid);
+		let channel_opened = state.as_ref().map_or(false, |state| state.is_channel_opened());
+		if !channel_opened && is_expired_opening_fee_params(&channel.opening_fee_params) {
+			// The client has to buy a new channel, so the scid is of no further use.
+			let mut htlcs = match state {
+				Some(OutboundJITChannelState::PendingInitialPayment { mut payments }) => {
+					payments.drain().flat_map(|(_, payment)| payment.htlcs).collect()
+				}
+				_ => Vec::new(),
+			};
+			htlcs.push(htlc);
+			outbound_channels.remove(intercept_scid);
+			drop(outbound_channels);
+			self.needs_persistence.store(true, Ordering::Release);
+			self.fail_htlcs(&channel, htlcs, JITChannelFailureReason::OpeningFeeParamsExpired);
+			return Ok(());
+		}
+
+		let (htlcs, opening_fee_msat, queued_htlcs) = match (state, channel.payment_size_msat) {
+			(Some(OutboundJITChannelState::ChannelReady { channel_id }), _) => {
+				outbound_channels
+					.states_by_scid
+					.insert(intercept_scid, OutboundJITChannelState::ChannelReady { channel_id });
+				drop(outbound_channels);
+				return self.forward_htlc(htlc, &channel_id, &channel.counterparty_node_id, 0);
+			}
+			(
+				Some(OutboundJITChannelState::PendingChannelOpen {
+					payment_hash,
+					opening_fee_msat,
+					mut htlcs,
+					mut queued_htlcs,
+					ticks_elapsed,
+				}),
+				_,
+			) => {
+				// Additional parts of the initial payment bear the fee, too.
+				if htlc.payment_hash == payment_hash {
+					htlcs.push(htlc);
+				} else {
+					queued_htlcs.push(htlc);
+				}
+				outbound_channels.states_by_scid.insert(
+					intercept_scid,
+					OutboundJITChannelState::PendingChannelOpen {
+						payment_hash,
+						opening_fee_msat,
+						htlcs,
+						queued_htlcs,
+						ticks_elapsed,
+					},
+				);
+				return Ok(());
+			}
+			(state, Some(payment_size_msat)) => {
+				let mut payments = match state {
+					Some(OutboundJITChannelState::PendingInitialPayment { payments }) => payments,
+					_ => HashMap::new(),
+				};
+				let payment = payments.entry(payment_hash).or_default();
+				payment.htlcs.push(htlc);
+
+				let amounts_msat: Vec<u64> =
+					payment.htlcs.iter().map(|htlc| htlc.expected_outbound_amount_msat).collect();
+				if amounts_msat.iter().sum::<u64>() < payment_size_msat {
+					outbound_channels.states_by_scid.insert(
+						intercept_scid,
+						OutboundJITChannelState::PendingInitialPayment { payments },
+					);
+					return Ok(());
+				}
+
+				// In MPP mode the fee is based on the payment size the client bought the channel for.
+				let opening_fee_msat =
+					compute_opening_fee(payment_size_msat, &channel.opening_fee_params).filter(
+						|fee| compute_amounts_to_forward_msat(&amounts_msat, *fee, 1).is_some(),
+					);
+
+				let htlcs = payments.remove(&payment_hash).unwrap_or_default().htlcs;
+				let opening_fee_msat = match opening_fee_msat {
+					Some(opening_fee_msat) => opening_fee_msat,
+					None => {
+						if !payments.is_empty() {
+							outbound_channels.states_by_scid.insert(
+								intercept_scid,
+								OutboundJITChannelState::PendingInitialPayment { payments },
+							);
+						}
+						drop(outbound_channels);
+						self.fail_htlcs(
+							&channel,
+							htlcs,
+							JITChannelFailureReason::OpeningFeeNotCovered,
+						);
+						return Ok(());
+					}
+				};
+				let queued_htlcs =
+					payments.drain().flat_map(|(_, payment)| payment.htlcs).collect();
+				(htlcs, opening_fee_msat, queued_htlcs)
+			}
+			(_, None) => {
+				// In no-MPP mode every HTLC is handled on its own until the channel is open.
+				match compute_opening_fee(
+					expected_outbound_amount_msat,
+					&channel.opening_fee_params,
+				)
+				.filter(|fee| {
+					compute_amounts_to_forward_msat(&[expected_outbound_amount_msat], *fee, 1)
+						.is_some()
+				}) {
+					Some(opening_fee_msat) => (vec![htlc], opening_fee_msat, Vec::new()),
+					None => {
+						drop(outbound_channels);
+						self.fail_htlcs(
+							&channel,
+							vec![htlc],
+							JITChannelFailureReason::OpeningFeeNotCovered,
+						);
+						return Ok(());
+					}
+				}
+			}
+		};
+
+		let user_channel_id = {
+			let random_bytes = self.entropy_source.get_secure_random_bytes();
+			let mut user_channel_id = [0; 16];
+			user_channel_id.copy_from_slice(&random_bytes[..16]);
+			u128::from_be_bytes(user_channel_id)
+		};
+		let channel_size_sat = compute_channel_size_sat(
+			htlcs.iter().map(|htlc| htlc.expected_outbound_amount_msat).sum::<u64>()
+				- opening_fee_msat,
+			service_state.config.min_channel_size_sat,
+			service_state.config.channel_overprovisioning_ppm,
+		);
+
+		outbound_channels.states_by_scid.insert(
+			intercept_scid,
+			OutboundJITChannelState::PendingChannelOpen {
+				payment_hash,
+				opening_fee_msat,
+				htlcs,
+				queued_htlcs,
+				ticks_elapsed: 0,
+			},
+		);
+		outbound_channels.scids_by_user_channel_id.insert(user_channel_id, intercept_scid);
+		drop(outbound_channels);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		if let Err(e) = self.channel_manager.create_channel(
+			channel.counterparty_node_id,
+			channel_size_sat,
+			0,
+			user_channel_id,
+			None,
+		) {
+			self.channel_closed(user_channel_id);
+			return Err(e);
+		}
+
+		Ok(())
+	}
+
+	pub fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+	) -> Result<(), APIError> {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return Ok(()),
+		};
+
+		let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+		let intercept_scid = match outbound_channels.scids_by_user_channel_id.get(&user_channel_id)
+		{
+			Some(intercept_scid) => *intercept_scid,
+			None => return Ok(()),
+		};
+
+		let channel = match outbound_channels.channels_by_scid.get(&intercept_scid) {
+			Some(channel) if channel.counterparty_node_id == *counterparty_node_id => {
+				channel.clone()
+			}
+			_ => {
+				return Err(APIError::APIMisuseError {
+					err: format!(
+						"Channel {} is not the JIT channel we opened to {}",
+						user_channel_id, counterparty_node_id
+					),
+				});
+			}
+		};
+
+		let (opening_fee_msat, htlcs, queued_htlcs) = match outbound_channels.states_by_scid.insert(
+			intercept_scid,
+			OutboundJITChannelState::ChannelReady { channel_id: *channel_id },
+		) {
+			Some(OutboundJITChannelState::PendingChannelOpen {
+				opening_fee_msat,
+				htlcs,
+				queued_htlcs,
+				..
+			}) => (opening_fee_msat, htlcs, queued_htlcs),
+			_ => return Ok(()),
+		};
+		drop(outbound_channels);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		let htlc_minimum_msat = self
+			.channel_manager
+			.list_channels()
+			.into_iter()
+			.find(|details| details.channel_id == *channel_id)
+			.map(|details| details.next_outbound_htlc_minimum_msat)
+			.unwrap_or(1);
+		let amounts_msat: Vec<u64> =
+			htlcs.iter().map(|htlc| htlc.expected_outbound_amount_msat).collect();
+
+		let mut result = Ok(());
+		match compute_amounts_to_forward_msat(&amounts_msat, opening_fee_msat, htlc_minimum_msat) {
+			Some(amounts_to_forward_msat) => {
+				for (htlc, amt_to_forward_msat) in htlcs.into_iter().zip(amounts_to_forward_msat) {
+					let fee_msat = htlc.expected_outbound_amount_msat - amt_to_forward_msat;
+					if let Err(e) =
+						self.forward_htlc(htlc, channel_id, counterparty_node_id, fee_msat)
+					{
+						result = Err(e);
+					}
+				}
+			}
+			None => {
+				self.fail_htlcs(&channel, htlcs, JITChannelFailureReason::OpeningFeeNotCovered);
+			}
+		}
+
+		for htlc in queued_htlcs {
+			if let Err(e) = self.forward_htlc(htlc, channel_id, counterparty_node_id, 0) {
+				result = Err(e);
+			}
+		}
+		result
+	}
+
+	pub fn funding_transaction_generated(&self, user_channel_id: u128, funding_txid: Txid) -> bool {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return false,
+		};
+
+		let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+		let intercept_scid = match outbound_channels.scids_by_user_channel_id.get(&user_channel_id)
+		{
+			Some(intercept_scid) => *intercept_scid,
+			None => return false,
+		};
+
+		let client_trusts_lsp = outbound_channels
+			.channels_by_scid
+			.get(&intercept_scid)
+			.map_or(false, |channel| channel.client_trusts_lsp);
+		if client_trusts_lsp {
+			outbound_channels.held_funding_txids_by_scid.insert(intercept_scid, funding_txid);
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+		client_trusts_lsp
+	}
+
+	pub fn payment_forwarded(&self, next_channel_id: &[u8; 32]) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+		let intercept_scid = outbound_channels.states_by_scid.iter().find_map(
+			|(intercept_scid, state)| match state {
+				OutboundJITChannelState::ChannelReady { channel_id }
+					if channel_id == next_channel_id =>
+				{
+					Some(*intercept_scid)
+				}
+				_ => None,
+			},
+		);
+		let intercept_scid = match intercept_scid {
+			Some(intercept_scid) => intercept_scid,
+			None => return,
+		};
+
+		// The client claimed a payment, so it revealed the preimage and we may broadcast.
+		let funding_txid =
+			match outbound_channels.held_funding_txids_by_scid.remove(&intercept_scid) {
+				Some(funding_txid) => funding_txid,
+				None => return,
+			};
+		self.needs_persistence.store(true, Ordering::Release);
+		let counterparty_node_id = match outbound_channels.channels_by_scid.get(&intercept_scid) {
+			Some(channel) => channel.counterparty_node_id,
+			None => return,
+		};
+		drop(outbound_channels);
+
+		self.pending_events.enqueue(Event::LSPS2(LSPS2Event::FundingBroadcastSafe {
+			intercept_scid,
+			counterparty_node_id,
+			funding_txid,
+		}));
+	}
+
+	pub fn channel_closed(&self, user_channel_id: u128) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+		let intercept_scid =
+			match outbound_channels.scids_by_user_channel_id.remove(&user_channel_id) {
+				Some(intercept_scid) => intercept_scid,
+				None => return,
+			};
+		self.needs_persistence.store(true, Ordering::Release);
+
+		// The client got the channel it paid for, so the scid is of no further use.
+		if let Some(OutboundJITChannelState::ChannelReady { .. }) =
+			outbound_channels.states_by_scid.get(&intercept_scid)
+		{
+			outbound_channels.remove(intercept_scid);
+			return;
+		}
+
+		// Otherwise the open failed, and further HTLCs have to pay for a new channel.
+		outbound_channels.held_funding_txids_by_scid.remove(&intercept_scid);
+		let state = outbound_channels.states_by_scid.remove(&intercept_scid);
+		let channel = outbound_channels.channels_by_scid.get(&intercept_scid).cloned();
+		drop(outbound_channels);
+
+		if let (
+			Some(channel),
+			Some(OutboundJITChannelState::PendingChannelOpen { mut htlcs, queued_htlcs, .. }),
+		) = (channel, state)
+		{
+			htlcs.extend(queued_htlcs);
+			self.fail_htlcs(&channel, htlcs, JITChannelFailureReason::ChannelOpenFailed);
+		}
+	}
+
+	pub fn timer_tick_occurred(&self) {
+		self.prune_expired_inbound_payments();
+		self.prune_stale_inbound_channels();
+
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		if service_state.promise_secrets.lock().unwrap().retire_expired() {
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+
+		let mut failed_htlcs = Vec::new();
+		{
+			let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+			let outbound_channels = &mut *outbound_channels;
+
+			// Once its opening fee parameters expired, an scid can't be used to buy a channel
+			// anymore. If the channel was opened, the payment it was bought for has been made, so
+			// the scid is only kept while the channel is still being opened.
+			let expired_scids: Vec<u64> = outbound_channels
+				.channels_by_scid
+				.values()
+				.filter(|channel| {
+					let channel_pending = matches!(
+						outbound_channels.states_by_scid.get(&channel.intercept_scid),
+						Some(OutboundJITChannelState::PendingChannelOpen { .. })
+					);
+					!channel_pending && is_expired_opening_fee_params(&channel.opening_fee_params)
+				})
+				.map(|channel| channel.intercept_scid)
+				.collect();
+
+			for intercept_scid in expired_scids {
+				if let Some((
+					channel,
+					Some(OutboundJITChannelState::PendingInitialPayment { mut payments }),
+				)) = outbound_channels.remove(intercept_scid)
+				{
+					let htlcs = payments.drain().flat_map(|(_, payment)| payment.htlcs).collect();
+					failed_htlcs.push((
+						channel,
+						htlcs,
+						JITChannelFailureReason::OpeningFeeParamsExpired,
+					));
+				}
+				self.needs_persistence.store(true, Ordering::Release);
+			}
+
+			for (intercept_scid, state) in outbound_channels.states_by_scid.iter_mut() {
+				let payments = match state {
+					OutboundJITChannelState::PendingInitialPayment { payments } => payments,
+					_ => continue,
+				};
+				let channel = match outbound_channels.channels_by_scid.get(intercept_scid) {
+					Some(channel) => channel,
+					None => continue,
+				};
+
+				let mut timed_out_payment_hashes = Vec::new();
+				for (payment_hash, payment) in payments.iter_mut() {
+					payment.ticks_elapsed += 1;
+					if payment.ticks_elapsed >= MPP_TIMEOUT_TICKS {
+						timed_out_payment_hashes.push(*payment_hash);
+					}
+				}
+
+				for payment_hash in timed_out_payment_hashes {
+					if let Some(payment) = payments.remove(&payment_hash) {
+						failed_htlcs.push((
+							channel.clone(),
+							payment.htlcs,
+							JITChannelFailureReason::MPPTimeout,
+						));
+					}
+				}
+			}
+
+			// HTLCs held for a channel that doesn't get ready are failed back before they time out.
+			// Should the channel get ready later, further parts of the initial payment pay the fee.
+			for (intercept_scid, state) in outbound_channels.states_by_scid.iter_mut() {
+				let (htlcs, queued_htlcs, ticks_elapsed) = match state {
+					OutboundJITChannelState::PendingChannelOpen {
+						htlcs,
+						queued_htlcs,
+						ticks_elapsed,
+						..
+					} if !htlcs.is_empty() || !queued_htlcs.is_empty() => (htlcs, queued_htlcs, ticks_elapsed),
+					_ => continue,
+				};
+				let channel = match outbound_channels.channels_by_scid.get(intercept_scid) {
+					Some(channel) => channel,
+					None => continue,
+				};
+
+				*ticks_elapsed += 1;
+				if *ticks_elapsed >= CHANNEL_OPEN_TIMEOUT_TICKS {
+					*ticks_elapsed = 0;
+					let mut timed_out_htlcs = std::mem::take(htlcs);
+					timed_out_htlcs.append(queued_htlcs);
+					failed_htlcs.push((
+						channel.clone(),
+						timed_out_htlcs,
+						JITChannelFailureReason::ChannelOpenTimeout,
+					));
+				}
+			}
+
+			outbound_channels.states_by_scid.retain(|_, state| match state {
+				OutboundJITChannelState::PendingInitialPayment { payments } => !payments.is_empty(),
+				_ => true,
+			});
+		}
+
+		for (channel, htlcs, reason) in failed_htlcs {
+			self.fail_htlcs(&channel, htlcs, reason);
+		}
+	}
+
+	fn prune_expired_inbound_payments(&self) {
+		let now = LSPSDateTime::now().duration_since_epoch().as_secs();
+		let mut inbound_payments = self.inbound_payments.lock().unwrap();
+		let num_payments = inbound_payments.len();
+		inbound_payments.retain(|_, payment| payment.expires_at > now);
+		if inbound_payments.len() != num_payments {
+			self.needs_persistence.store(true, Ordering::Release);
+		}
+	}
+
+	fn prune_stale_inbound_channels(&self) {
+		let mut stale_channels = Vec::new();
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			for (counterparty_node_id, peer_state) in per_peer_state.iter_mut() {
+				let channels = &mut peer_state.inbound_channels_by_request_id;
+				let stale_request_ids: Vec<RequestId> = channels
+					.iter_mut()
+					.filter_map(|(request_id, channel)| {
+						channel.ticks_elapsed += 1;
+						if channel.ticks_elapsed >= REQUEST_TIMEOUT_TICKS {
+							Some(request_id.clone())
+						} else {
+							None
+						}
+					})
+					.collect();
+				for request_id in stale_request_ids {
+					if let Some(channel) = channels.remove(&request_id) {
+						stale_channels.push((*counterparty_node_id, channel));
+					}
+				}
+			}
+			per_peer_state
+				.retain(|_, peer_state| !peer_state.inbound_channels_by_request_id.is_empty());
+		}
+
+		for (counterparty_node_id, channel) in stale_channels {
+			self.fail_inbound_channel(
+				&counterparty_node_id,
+				channel.config.user_channel_id,
+				InvoiceCreationFailureReason::Timeout,
+			);
+		}
+	}
+
+	fn fail_inbound_channel(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+		reason: InvoiceCreationFailureReason,
+	) {
+		self.pending_events.enqueue(Event::LSPS2(LSPS2Event::InvoiceCreationFailed {
+			user_channel_id,
+			counterparty_node_id: *counterparty_node_id,
+			reason,
+		}));
+	}
+
+	fn forward_htlc(
+		&self, htlc: InterceptedHTLC, channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		fee_msat: u64,
+	) -> Result<(), APIError> {
+		let amt_to_forward_msat = htlc.expected_outbound_amount_msat - fee_msat;
+		self.channel_manager
+			.forward_intercepted_htlc(
+				htlc.intercept_id,
+				channel_id,
+				*counterparty_node_id,
+				amt_to_forward_msat,
+			)
+			.map_err(|e| {
+				// An HTLC we can't forward has to be failed back, or it would linger until expiry.
+				let _ = self.channel_manager.fail_intercepted_htlc(htlc.intercept_id);
+				e
+			})
+	}
+
+	fn fail_htlcs(
+		&self, channel: &OutboundJITChannel, htlcs: Vec<InterceptedHTLC>,
+		reason: JITChannelFailureReason,
+	) {
+		let mut payment_hashes = Vec::new();
+		for htlc in htlcs {
+			let _ = self.channel_manager.fail_intercepted_htlc(htlc.intercept_id);
+			if !payment_hashes.contains(&htlc.payment_hash) {
+				payment_hashes.push(htlc.payment_hash);
+			}
+		}
+
+		for payment_hash in payment_hashes {
+			self.pending_events.enqueue(Event::LSPS2(LSPS2Event::HTLCsFailed {
+				intercept_scid: channel.intercept_scid,
+				counterparty_node_id: channel.counterparty_node_id,
+				payment_hash,
+				reason,
+			}));
+		}
+	}
+
+	pub fn payment_claimable(
+		&self, payment_hash: PaymentHash, amount_msat: u64, counterparty_skimmed_fee_msat: u64,
+	) -> bool {
+		let mut inbound_payments = self.inbound_payments.lock().unwrap();
+		let payment = match inbound_payments.get(&payment_hash) {
+			Some(payment) => payment,
+			None => return true,
+		};
+
+		// Without a fixed payment size the fee is based on the amount the LSP forwarded to us.
+		let payment_size_msat = payment
+			.payment_size_msat
+			.unwrap_or_else(|| amount_msat.saturating_add(counterparty_skimmed_fee_msat));
+		let opening_fee_msat = compute_opening_fee(payment_size_msat, &payment.opening_fee_params);
+
+		match opening_fee_msat {
+			Some(opening_fee_msat) if counterparty_skimmed_fee_msat <= opening_fee_msat => {
+				inbound_payments.remove(&payment_hash);
+				self.needs_persistence.store(true, Ordering::Release);
+				return true;
+			}
+			_ => {}
+		}
+
+		// If we can't tell which fee the LSP was allowed to deduct, we don't accept any. We keep
+		// the payment around, so any retry is checked as well.
+		let user_channel_id = payment.user_channel_id;
+		let counterparty_node_id = payment.counterparty_node_id;
+		drop(inbound_payments);
+
+		self.channel_manager.fail_htlc_backwards(&payment_hash);
+		self.pending_events.enqueue(Event::LSPS2(LSPS2Event::OpeningFeeOvercharged {
+			user_channel_id,
+			counterparty_node_id,
+			payment_hash,
+			opening_fee_msat,
+			skimmed_fee_msat: counterparty_skimmed_fee_msat,
+		}));
+		false
+	}
+
+	pub fn open_channel_requested(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<bool, APIError> {
+		if !self.trusted_lsp_node_ids.contains(counterparty_node_id) {
+			return Ok(false);
+		}
+
+		// The channel is matched to the buy from the LSP whose invoice expires first, among those
+		// no zero-conf channel was accepted for yet.
+		let mut inbound_payments = self.inbound_payments.lock().unwrap();
+		let payment = inbound_payments
+			.values_mut()
+			.filter(|payment| {
+				payment.counterparty_node_id == *counterparty_node_id
+					&& payment.zero_conf_user_channel_id.is_none()
+			})
+			.min_by_key(|payment| payment.expires_at);
+
+		let payment = match payment {
+			Some(payment) => payment,
+			None => return Ok(false),
+		};
+
+		self.channel_manager.accept_inbound_channel_from_trusted_peer_0conf(
+			temporary_channel_id,
+			counterparty_node_id,
+			user_channel_id,
+		)?;
+		payment.zero_conf_user_channel_id = Some(user_channel_id);
+		self.needs_persistence.store(true, Ordering::Release);
+		Ok(true)
+	}
+
+	pub fn rotate_promise_secret(&self, promise_secret: Option<[u8; 32]>) {
+		let service_state = match self.service_state.as_ref() {
+			Some(service_state) => service_state,
+			None => return,
+		};
+
+		let promise_secret =
+			promise_secret.unwrap_or_else(|| self.entropy_source.get_secure_random_bytes());
+		service_state.promise_secrets.lock().unwrap().rotate(promise_secret);
+		self.needs_persistence.store(true, Ordering::Release);
+	}
+
+	pub fn get_and_clear_needs_persistence(&self) -> bool {
+		self.needs_persistence.swap(false, Ordering::AcqRel)
+	}
+
+	pub fn read_state<R: io::Read>(&self, reader: &mut R) -> Result<(), DecodeError> {
+		let version: u8 = Readable::read(reader)?;
+		if version > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+
+		let num_channels: u64 = Readable::read(reader)?;
+		let mut channels = Vec::new();
+		for _ in 0..num_channels {
+			let channel: OutboundJITChannel = Readable::read(reader)?;
+			channels.push(channel);
+		}
+
+		let num_payments: u64 = Readable::read(reader)?;
+		let mut inbound_payments = self.inbound_payments.lock().unwrap();
+		for _ in 0..num_payments {
+			let payment: InboundJITPayment = Readable::read(reader)?;
+			inbound_payments.insert(payment.payment_hash, payment);
+		}
+
+		let num_secrets: u64 = Readable::read(reader)?;
+		let mut promise_secrets = Vec::new();
+		for _ in 0..num_secrets {
+			let promise_secret: PromiseSecret = Readable::read(reader)?;
+			promise_secrets.push(promise_secret);
+		}
+
+		let num_opened_channels: u64 = Readable::read(reader)?;
+		let mut opened_channels = Vec::new();
+		for _ in 0..num_opened_channels {
+			let opened_channel: OpenedJITChannel = Readable::read(reader)?;
+			opened_channels.push(opened_channel);
+		}
+
+		// If we are no longer configured as a service we have no use for the channels we sold.
+		if let Some(service_state) = self.service_state.as_ref() {
+			let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+			for channel in channels {
+				outbound_channels.insert(channel);
+			}
+			for opened_channel in opened_channels {
+				let intercept_scid = opened_channel.intercept_scid;
+				let user_channel_id = opened_channel.user_channel_id;
+				if !outbound_channels.channels_by_scid.contains_key(&intercept_scid) {
+					continue;
+				}
+				if let Some(funding_txid) = opened_channel.held_funding_txid {
+					outbound_channels
+						.held_funding_txids_by_scid
+						.insert(intercept_scid, funding_txid);
+				}
+				let state = opened_channel.into_state().ok_or(DecodeError::InvalidValue)?;
+				outbound_channels.states_by_scid.insert(intercept_scid, state);
+				outbound_channels.scids_by_user_channel_id.insert(user_channel_id, intercept_scid);
+			}
+
+			// The secret we were configured with takes over, but we keep accepting the promises
+			// made before the restart.
+			let mut current_promise_secrets = service_state.promise_secrets.lock().unwrap();
+			for promise_secret in promise_secrets {
+				if promise_secret.secret == current_promise_secrets.current.secret {
+					if let Some(valid_until) = promise_secret.valid_until {
+						current_promise_secrets.current.promised_until(valid_until);
+					}
+				} else {
+					current_promise_secrets.previous.push(promise_secret);
+				}
+			}
+			current_promise_secrets.retire_expired();
+		}
+		Ok(())
+	}
+
+	fn enqueue_request(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, request: LSPS2Request,
+	) {
+		let msg: LSPSMessage = LSPS2Message::Request(request_id, request).into();
+		self.pending_messages.lock().unwrap().push((counterparty_node_id, msg));
+	}
+
+	fn enqueue_response(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, response: LSPS2Response,
+	) {
+		let msg: LSPSMessage = LSPS2Message::Response(request_id, response).into();
+		self.pending_messages.lock().unwrap().push((counterparty_node_id, msg));
+	}
+
+	fn remove_inbound_channel(
+		&self, request_id: &RequestId, counterparty_node_id: &PublicKey,
+	) -> Result<InboundJITChannel, LightningError> {
+		let mut per_peer_state = self.per_peer_state.lock().unwrap();
+		per_peer_state
+			.get_mut(counterparty_node_id)
+			.and_then(|peer_state| peer_state.inbound_channels_by_request_id.remove(request_id))
+			.ok_or_else(|| LightningError {
+				err: format!(
+					"Received LSPS2 response from {} for unknown request {:?}",
+					counterparty_node_id, request_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			})
+	}
+
+	/// Puts a channel we removed to handle a response back under the request id it was kept under.
+	fn restore_inbound_channel(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, channel: InboundJITChannel,
+	) {
+		let mut per_peer_state = self.per_peer_state.lock().unwrap();
+		let peer_state = per_peer_state.entry(*counterparty_node_id).or_insert_with(PeerState::new);
+		peer_state.inbound_channels_by_request_id.insert(request_id, channel);
+	}
+
+	fn advance_inbound_channel(
+		&self, counterparty_node_id: &PublicKey, mut channel: InboundJITChannel,
+		state: InboundJITChannelState, request: LSPS2Request,
+	) {
+		channel.state = state;
+		channel.ticks_elapsed = 0;
+
+		let request_id = utils::generate_request_id(&self.entropy_source);
+		{
+			let mut per_peer_state = self.per_peer_state.lock().unwrap();
+			let peer_state =
+				per_peer_state.entry(*counterparty_node_id).or_insert_with(PeerState::new);
+			peer_state.inbound_channels_by_request_id.insert(request_id.clone(), channel);
+		}
+
+		self.enqueue_request(*counterparty_node_id, request_id, request);
+	}
+
+	fn handle_get_versions_request(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse {
+				versions: SUPPORTED_SPEC_VERSIONS.to_vec(),
+			}),
+		);
+		Ok(())
+	}
+
+	fn handle_get_info_request(
+		&self, service_state: &ServiceState<JP>, request_id: RequestId,
+		counterparty_node_id: &PublicKey, params: GetInfoRequest,
+	) -> Result<(), LightningError> {
+		if !SUPPORTED_SPEC_VERSIONS.contains(&params.version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::GetInfoError(ResponseError {
+					code: LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE,
+					message: "unsupported_version".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("client requested unsupported version {}", params.version),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let raw_opening_fee_params_menu = match service_state
+			.config
+			.pricing
+			.opening_fee_params_menu(counterparty_node_id, params.token.as_deref())
+		{
+			Some(raw_opening_fee_params_menu) => raw_opening_fee_params_menu,
+			None => {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::GetInfoError(ResponseError {
+						code: LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+						message: "unrecognized_or_stale_token".to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: format!(
+						"client {} provided an unrecognized or stale token",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let mut promise_secrets = service_state.promise_secrets.lock().unwrap();
+		let mut opening_fee_params_menu = Vec::new();
+		for raw_opening_fee_params in raw_opening_fee_params_menu {
+			// We have to keep accepting the promise until it expires, even if the secret rotates.
+			if promise_secrets.current.promised_until(raw_opening_fee_params.valid_until) {
+				self.needs_persistence.store(true, Ordering::Release);
+			}
+			opening_fee_params_menu.push(
+				raw_opening_fee_params.into_opening_fee_params(&promise_secrets.current.secret),
+			);
+		}
+		drop(promise_secrets);
+
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu,
+				min_payment_size_msat: service_state.config.min_payment_size_msat,
+				max_payment_size_msat: service_state.config.max_payment_size_msat,
+			}),
+		);
+		Ok(())
+	}
+
+	fn handle_buy_request(
+		&self, service_state: &ServiceState<JP>, request_id: RequestId,
+		counterparty_node_id: &PublicKey, params: BuyRequest,
+	) -> Result<(), LightningError> {
+		if !SUPPORTED_SPEC_VERSIONS.contains(&params.version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+					message: "unsupported_version".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("client requested unsupported version {}", params.version),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if !service_state.promise_secrets.lock().unwrap().is_valid(&params.opening_fee_params)
+			|| is_expired_opening_fee_params(&params.opening_fee_params)
+		{
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+					message: "invalid_opening_fee_params".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: "client provided invalid or expired opening fee parameters".to_string(),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if let Some(payment_size_msat) = params.payment_size_msat {
+			let opening_fee_msat =
+				compute_opening_fee(payment_size_msat, &params.opening_fee_params);
+			let error = if payment_size_msat < service_state.config.min_payment_size_msat {
+				Some((
+					LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+					"payment_size_too_small",
+				))
+			} else if payment_size_msat > service_state.config.max_payment_size_msat {
+				Some((
+					LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+					"payment_size_too_large",
+				))
+			} else {
+				match opening_fee_msat {
+					// The fee computation overflowed, so the payment is too large to handle.
+					None => Some((
+						LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+						"payment_size_too_large",
+					)),
+					Some(opening_fee_msat) if opening_fee_msat >= payment_size_msat => Some((
+						LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+						"payment_size_too_small",
+					)),
+					Some(_) => None,
+				}
+			};
+
+			if let Some((code, message)) = error {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::BuyError(ResponseError {
+						code,
+						message: message.to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: format!(
+						"client requested a channel for an unacceptable payment size of {}msat: {}",
+						payment_size_msat, message
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		}
+
+		let intercept_scid = self.channel_manager.get_intercept_scid();
+		service_state.outbound_channels.lock().unwrap().insert(OutboundJITChannel {
+			intercept_scid,
+			counterparty_node_id: *counterparty_node_id,
+			opening_fee_params: params.opening_fee_params,
+			payment_size_msat: params.payment_size_msat,
+			client_trusts_lsp: service_state.config.client_trusts_lsp,
+		});
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS2Response::Buy(BuyResponse {
+				jit_channel_scid: JitChannelScid::from(intercept_scid),
+				lsp_cltv_expiry_delta: service_state.config.cltv_expiry_delta,
+				client_trusts_lsp: service_state.config.client_trusts_lsp,
+			}),
+		);
+		Ok(())
+	}
+
+	fn handle_get_versions_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey,
+		response: GetVersionsResponse,
+	) -> Result<(), LightningError> {
+		let channel = self.remove_inbound_channel(&request_id, counterparty_node_id)?;
+
+		let version = match response
+			.versions
+			.iter()
+			.filter(|version| SUPPORTED_SPEC_VERSIONS.contains(version))
+			.max()
+		{
+			Some(version) => *version,
+			None => {
+				self.fail_inbound_channel(
+					counterparty_node_id,
+					channel.config.user_channel_id,
+					InvoiceCreationFailureReason::NoCommonVersion,
+				);
+				return Err(LightningError {
+					err: format!(
+						"LSP {} does not support any of our LSPS2 versions: {:?}",
+						counterparty_node_id, response.versions
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let request =
+			LSPS2Request::GetInfo(GetInfoRequest { version, token: channel.config.token.clone() });
+		self.advance_inbound_channel(
+			counterparty_node_id,
+			channel,
+			InboundJITChannelState::MenuRequested { version },
+			request,
+		);
+		Ok(())
+	}
+
+	fn handle_get_info_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, response: GetInfoResponse,
+	) -> Result<(), LightningError> {
+		let channel = self.remove_inbound_channel(&request_id, counterparty_node_id)?;
+
+		// Unexpected responses, e.g., duplicates, leave the channel as it was.
+		let version = match channel.state {
+			InboundJITChannelState::MenuRequested { version } => version,
+			_ => {
+				self.restore_inbound_channel(request_id, counterparty_node_id, channel);
+				return Err(LightningError {
+					err: format!(
+						"Received unexpected lsps2.get_info response from {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		if let Some(payment_size_msat) = channel.config.payment_size_msat {
+			if payment_size_msat < response.min_payment_size_msat
+				|| payment_size_msat > response.max_payment_size_msat
+			{
+				self.fail_inbound_channel(
+					counterparty_node_id,
+					channel.config.user_channel_id,
+					InvoiceCreationFailureReason::PaymentSizeOutOfRange {
+						min_payment_size_msat: response.min_payment_size_msat,
+						max_payment_size_msat: response.max_payment_size_msat,
+					},
+				);
+				return Err(LightningError {
+					err: format!(
+						"Payment size of {}msat is outside of the range accepted by LSP {}: {}msat to {}msat",
+						payment_size_msat,
+						counterparty_node_id,
+						response.min_payment_size_msat,
+						response.max_payment_size_msat
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		}
+
+		let opening_fee_params_menu = qualifying_opening_fee_params(
+			&response.opening_fee_params_menu,
+			channel.config.payment_size_msat,
+		);
+		if opening_fee_params_menu.is_empty() {
+			self.fail_inbound_channel(
+				counterparty_node_id,
+				channel.config.user_channel_id,
+				InvoiceCreationFailureReason::NoSuitableOpeningFeeParams,
+			);
+			return Err(LightningError {
+				err: format!(
+					"LSP {} did not offer any suitable opening fee parameters",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let event = LSPS2Event::OpeningFeeParamsMenuReady {
+			user_channel_id: channel.config.user_channel_id,
+			counterparty_node_id: *counterparty_node_id,
+			opening_fee_params_menu: opening_fee_params_menu.clone(),
+			min_payment_size_msat: response.min_payment_size_msat,
+			max_payment_size_msat: response.max_payment_size_msat,
+			payment_size_msat: channel.config.payment_size_msat,
+		};
+
+		// Nothing is requested until the user selected an entry of the menu, so we keep the
+		// channel under the request id of the get_info request.
+		let mut channel = channel;
+		channel.state = InboundJITChannelState::MenuReceived { version, opening_fee_params_menu };
+		channel.ticks_elapsed = 0;
+		self.restore_inbound_channel(request_id, counterparty_node_id, channel);
+
+		self.pending_events.enqueue(Event::LSPS2(event));
+		Ok(())
+	}
+
+	fn handle_buy_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, response: BuyResponse,
+	) -> Result<(), LightningError> {
+		let channel = self.remove_inbound_channel(&request_id, counterparty_node_id)?;
+
+		let opening_fee_params = match channel.state {
+			InboundJITChannelState::BuyRequested { opening_fee_params } => opening_fee_params,
+			_ => {
+				self.restore_inbound_channel(request_id, counterparty_node_id, channel);
+				return Err(LightningError {
+					err: format!(
+						"Received unexpected lsps2.buy response from {}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let scid = match response.jit_channel_scid.to_scid() {
+			Ok(scid) => scid,
+			Err(()) => {
+				self.fail_inbound_channel(
+					counterparty_node_id,
+					channel.config.user_channel_id,
+					InvoiceCreationFailureReason::InvalidScid,
+				);
+				return Err(LightningError {
+					err: format!(
+						"LSP {} provided an invalid jit_channel_scid: {}",
+						counterparty_node_id, response.jit_channel_scid
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		};
+
+		let invoice = match self.create_route_hinted_invoice(
+			counterparty_node_id,
+			scid,
+			response.lsp_cltv_expiry_delta,
+			&channel.config,
+		) {
+			Ok(invoice) => invoice,
+			Err(e) => {
+				self.fail_inbound_channel(
+					counterparty_node_id,
+					channel.config.user_channel_id,
+					InvoiceCreationFailureReason::InvoiceGenerationFailed { error: e.clone() },
+				);
+				return Err(LightningError {
+					err: format!("Failed to create JIT channel invoice: {}", e),
+					action: ErrorAction::IgnoreAndLog(Level::Error),
+				});
+			}
+		};
+
+		let opening_fee_msat = channel.config.payment_size_msat.and_then(|payment_size_msat| {
+			compute_opening_fee(payment_size_msat, &opening_fee_params)
+		});
+
+		let payment_hash = PaymentHash(invoice.payment_hash().into_inner());
+		let expires_at = (invoice.duration_since_epoch() + invoice.expiry_time()).as_secs();
+		self.inbound_payments.lock().unwrap().insert(
+			payment_hash,
+			InboundJITPayment {
+				payment_hash,
+				user_channel_id: channel.config.user_channel_id,
+				counterparty_node_id: *counterparty_node_id,
+				opening_fee_params: opening_fee_params.clone(),
+				payment_size_msat: channel.config.payment_size_msat,
+				expires_at,
+				zero_conf_user_channel_id: None,
+			},
+		);
+		self.needs_persistence.store(true, Ordering::Release);
+
+		self.pending_events.enqueue(Event::LSPS2(LSPS2Event::InvoiceReady {
+			user_channel_id: channel.config.user_channel_id,
+			counterparty_node_id: *counterparty_node_id,
+			invoice: Box::new(invoice),
+			opening_fee_params,
+			payment_size_msat: channel.config.payment_size_msat,
+			opening_fee_msat,
+			client_trusts_lsp: response.client_trusts_lsp,
+		}));
+		Ok(())
+	}
+
+	fn create_route_hinted_invoice(
+		&self, counterparty_node_id: &PublicKey, scid: u64, lsp_cltv_expiry_delta: u32,
+		config: &InboundJITChannelConfig,
+	) -> Result<Bolt11Invoice, String> {
+		let cltv_expiry_delta = u16::try_from(lsp_cltv_expiry_delta)
+			.map_err(|_| format!("lsp_cltv_expiry_delta too large: {}", lsp_cltv_expiry_delta))?;
+
+		let (payment_hash, payment_secret) = self
+			.channel_manager
+			.create_inbound_payment(
+				config.payment_size_msat,
+				config.expiry_secs,
+				Some(MIN_FINAL_CLTV_EXPIRY_DELTA),
+			)
+			.ok_or_else(|| "failed to register inbound payment".to_string())?;
+
+		let route_hint = RouteHint(vec![RouteHintHop {
+			src_node_id: *counterparty_node_id,
+			short_channel_id: scid,
+			fees: RoutingFees { base_msat: 0, proportional_millionths: 0 },
+			cltv_expiry_delta,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		}]);
+
+		let payment_hash = sha256::Hash::from_slice(&payment_hash.0)
+			.map_err(|_| "invalid payment hash".to_string())?;
+
+		let mut invoice_builder = InvoiceBuilder::new(Currency::from(self.network))
+			.description(config.description.clone())
+			.payment_hash(payment_hash)
+			.payment_secret(payment_secret)
+			.current_timestamp()
+			.min_final_cltv_expiry_delta(MIN_FINAL_CLTV_EXPIRY_DELTA.into())
+			.expiry_time(Duration::from_secs(config.expiry_secs.into()))
+			.private_route(route_hint);
+
+		if let Some(amount_msat) = config.payment_size_msat {
+			invoice_builder = invoice_builder.amount_milli_satoshis(amount_msat);
+		}
+
+		let raw_invoice = invoice_builder.build_raw().map_err(|e| format!("{:?}", e))?;
+		let hrp_str = raw_invoice.hrp.to_string();
+		let hrp_bytes = hrp_str.as_bytes();
+		let data_without_signature = raw_invoice.data.to_base32();
+		let signed_raw_invoice = raw_invoice
+			.sign(|_| {
+				self.node_signer.sign_invoice(hrp_bytes, &data_without_signature, Recipient::Node)
+			})
+			.map_err(|()| "failed to sign invoice".to_string())?;
+
+		Bolt11Invoice::from_signed(signed_raw_invoice).map_err(|e| format!("{:?}", e))
+	}
+
+	fn handle_error_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, method: &str,
+		error: ResponseError,
+	) -> Result<(), LightningError> {
+		let channel = self.remove_inbound_channel(&request_id, counterparty_node_id)?;
+		self.fail_inbound_channel(
+			counterparty_node_id,
+			channel.config.user_channel_id,
+			InvoiceCreationFailureReason::ErrorResponse {
+				code: error.code,
+				message: error.message.clone(),
+			},
+		);
+
+		Err(LightningError {
+			err: format!(
+				"{} error received from {}. code = {}, message = {}, data = {:?}",
+				method, counterparty_node_id, error.code, error.message, error.data
+			),
+			action: ErrorAction::IgnoreAndLog(Level::Info),
+		})
+	}
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, JP: Deref> Writeable for LSPS2MessageHandler<ES, CM, NS, JP>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let channels: Vec<OutboundJITChannel> = match self.service_state.as_ref() {
+			Some(service_state) => service_state
+				.outbound_channels
+				.lock()
+				.unwrap()
+				.channels_by_scid
+				.values()
+				.cloned()
+				.collect(),
+			None => Vec::new(),
+		};
+
+		let opened_channels: Vec<OpenedJITChannel> = match self.service_state.as_ref() {
+			Some(service_state) => {
+				let outbound_channels = service_state.outbound_channels.lock().unwrap();
+				outbound_channels
+					.scids_by_user_channel_id
+					.iter()
+					.filter_map(|(user_channel_id, intercept_scid)| {
+						let (payment_hash, opening_fee_msat, channel_id) = match outbound_channels
+							.states_by_scid
+							.get(intercept_scid)?
+						{
+							OutboundJITChannelState::PendingChannelOpen {
+								payment_hash,
+								opening_fee_msat,
+								..
+							} => (Some(*payment_hash), Some(*opening_fee_msat), None),
+							OutboundJITChannelState::ChannelReady { channel_id } => {
+								(None, None, Some(*channel_id))
+							}
+							OutboundJITChannelState::PendingInitialPayment { .. } => return None,
+						};
+						Some(OpenedJITChannel {
+							intercept_scid: *intercept_scid,
+							user_channel_id: *user_channel_id,
+							payment_hash,
+							opening_fee_msat,
+							channel_id,
+							held_funding_txid: outbound_channels
+								.held_funding_txids_by_scid
+								.get(intercept_scid)
+								.copied(),
+						})
+					})
+					.collect()
+			}
+			None => Vec::new(),
+		};
+
+		SERIALIZATION_VERSION.write(writer)?;
+		(channels.len() as u64).write(writer)?;
+		for channel in channels {
+			channel.write(writer)?;
+		}
+
+		let inbound_payments = self.inbound_payments.lock().unwrap();
+		(inbound_payments.len() as u64).write(writer)?;
+		for payment in inbound_payments.values() {
+			payment.write(writer)?;
+		}
+
+		match self.service_state.as_ref() {
+			Some(service_state) => {
+				let promise_secrets = service_state.promise_secrets.lock().unwrap();
+				let promise_secrets: Vec<&PromiseSecret> =
+					std::iter::once(&promise_secrets.current)
+						.chain(promise_secrets.previous.iter())
+						.filter(|secret| secret.has_valid_promises())
+						.collect();
+				(promise_secrets.len() as u64).write(writer)?;
+				for promise_secret in promise_secrets {
+					promise_secret.write(writer)?;
+				}
+			}
+			None => 0u64.write(writer)?,
+		}
+
+		(opened_channels.len() as u64).write(writer)?;
+		for opened_channel in opened_channels {
+			opened_channel.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<ES: Deref, CM: Deref, NS: Deref, JP: Deref> ProtocolMessageHandler
+	for LSPS2MessageHandler<ES, CM, NS, JP>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+{
+	type ProtocolMessage = LSPS2Message;
+	const PROTOCOL_NUMBER: Option<u16> = Some(2);
+
+	fn handle_message(
+		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		match message {
+			LSPS2Message::Request(request_id, request) => {
+				let service_state = self.service_state.as_ref().ok_or_else(|| LightningError {
+					err: format!(
+						"Received LSPS2 request from {} but we are not configured as an LSPS2 service",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				})?;
+
+				match request {
+					LSPS2Request::GetVersions(_) => {
+						self.handle_get_versions_request(request_id, counterparty_node_id)
+					}
+					LSPS2Request::GetInfo(params) => self.handle_get_info_request(
+						service_state,
+						request_id,
+						counterparty_node_id,
+						params,
+					),
+					LSPS2Request::Buy(params) => self.handle_buy_request(
+						service_state,
+						request_id,
+						counterparty_node_id,
+						params,
+					),
+				}
+			}
+			LSPS2Message::Response(request_id, response) => match response {
+				LSPS2Response::GetVersions(response) => {
+					self.handle_get_versions_response(request_id, counterparty_node_id, response)
+				}
+				LSPS2Response::GetInfo(response) => {
+					self.handle_get_info_response(request_id, counterparty_node_id, response)
+				}
+				LSPS2Response::Buy(response) => {
+					self.handle_buy_response(request_id, counterparty_node_id, response)
+				}
+				LSPS2Response::GetVersionsError(error) => self.handle_error_response(
+					request_id,
+					counterparty_node_id,
+					"GetVersions",
+					error,
+				),
+				LSPS2Response::GetInfoError(error) => {
+					self.handle_error_response(request_id, counterparty_node_id, "GetInfo", error)
+				}
+				LSPS2Response::BuyError(error) => {
+					self.handle_error_response(request_id, counterparty_node_id, "Buy", error)
+				}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jit_channel::msgs::{OpeningFeeParams, RawOpeningFeeParams};
+	use crate::utils;
+	use lightning::ln::channelmanager::{ChannelDetails, PaymentId, Retry};
+	use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+	use lightning::sign::KeysManager;
+	use lightning::util::config::UserConfig;
+	use lightning_invoice::payment::PaymentError;
+	use std::time::Duration;
+
+	struct TestEntropy {}
+	impl EntropySource for TestEntropy {
+		fn get_secure_random_bytes(&self) -> [u8; 32] {
+			[21; 32]
+		}
+	}
+
+	#[derive(Default)]
+	struct TestChannelManager {
+		created_channels: Mutex<Vec<(PublicKey, u64, u128)>>,
+		forwarded_htlcs: Mutex<Vec<(InterceptId, [u8; 32], u64)>>,
+		failed_htlcs: Mutex<Vec<InterceptId>>,
+		failed_payments: Mutex<Vec<PaymentHash>>,
+		accepted_channels: Mutex<Vec<([u8; 32], bool)>>,
+	}
+
+	impl ChannelManagerInterface for TestChannelManager {
+		fn create_inbound_payment(
+			&self, _min_value_msat: Option<u64>, _invoice_expiry_delta_secs: u32,
+			_min_final_cltv_expiry_delta: Option<u16>,
+		) -> Option<(PaymentHash, PaymentSecret)> {
+			Some((PaymentHash([1; 32]), PaymentSecret([2; 32])))
+		}
+
+		fn get_payment_preimage(
+			&self, _payment_hash: PaymentHash, _payment_secret: PaymentSecret,
+		) -> Result<PaymentPreimage, APIError> {
+			unimplemented!()
+		}
+
+		fn claim_funds(&self, _payment_preimage: PaymentPreimage) {
+			unimplemented!()
+		}
+
+		fn get_intercept_scid(&self) -> u64 {
+			(29451 << 40) | (4815 << 16) | 1
+		}
+
+		fn create_channel(
+			&self, their_network_key: PublicKey, channel_value_satoshis: u64, _push_msat: u64,
+			user_channel_id: u128, _override_config: Option<UserConfig>,
+		) -> Result<[u8; 32], APIError> {
+			self.created_channels.lock().unwrap().push((
+				their_network_key,
+				channel_value_satoshis,
+				user_channel_id,
+			));
+			Ok([3; 32])
+		}
+
+		fn forward_intercepted_htlc(
+			&self, intercept_id: InterceptId, next_hop_channel_id: &[u8; 32],
+			_next_node_id: PublicKey, amt_to_forward_msat: u64,
+		) -> Result<(), APIError> {
+			self.forwarded_htlcs.lock().unwrap().push((
+				intercept_id,
+				*next_hop_channel_id,
+				amt_to_forward_msat,
+			));
+			Ok(())
+		}
+
+		fn fail_intercepted_htlc(&self, intercept_id: InterceptId) -> Result<(), APIError> {
+			self.failed_htlcs.lock().unwrap().push(intercept_id);
+			Ok(())
+		}
+
+		fn list_channels(&self) -> Vec<ChannelDetails> {
+			Vec::new()
+		}
+
+		fn pay_invoice(
+			&self, _invoice: &Bolt11Invoice, _payment_id: PaymentId, _retry_strategy: Retry,
+		) -> Result<(), PaymentError> {
+			unimplemented!()
+		}
+
+		fn fail_htlc_backwards(&self, payment_hash: &PaymentHash) {
+			self.failed_payments.lock().unwrap().push(*payment_hash);
+		}
+
+		fn accept_inbound_channel(
+			&self, temporary_channel_id: &[u8; 32], _counterparty_node_id: &PublicKey,
+			_user_channel_id: u128,
+		) -> Result<(), APIError> {
+			self.accepted_channels.lock().unwrap().push((*temporary_channel_id, false));
+			Ok(())
+		}
+
+		fn accept_inbound_channel_from_trusted_peer_0conf(
+			&self, temporary_channel_id: &[u8; 32], _counterparty_node_id: &PublicKey,
+			_user_channel_id: u128,
+		) -> Result<(), APIError> {
+			self.accepted_channels.lock().unwrap().push((*temporary_channel_id, true));
+			Ok(())
+		}
+	}
+
+	#[derive(Debug)]
+	struct TestPricing;
+
+	impl JITChannelPricing for TestPricing {
+		fn opening_fee_params_menu(
+			&self, counterparty_node_id: &PublicKey, token: Option<&str>,
+		) -> Option<Vec<RawOpeningFeeParams>> {
+			assert_eq!(*counterparty_node_id, self::counterparty_node_id());
+			let min_fee_msat = match token {
+				None => 546000,
+				Some("discount") => 1_000,
+				Some(_) => return None,
+			};
+			Some(vec![RawOpeningFeeParams {
+				min_fee_msat,
+				proportional: 1200,
+				valid_until: LSPSDateTime::now() + Duration::from_secs(3600),
+				min_lifetime: 1008,
+				max_client_to_self_delay: 2016,
+			}])
+		}
+	}
+
+	type TestHandler<ES> =
+		LSPS2MessageHandler<ES, Arc<TestChannelManager>, Arc<KeysManager>, Arc<TestPricing>>;
+
+	fn keys_manager() -> Arc<KeysManager> {
+		Arc::new(KeysManager::new(&[42; 32], 42, 42))
+	}
+
+	fn test_handler<ES: Deref>(
+		entropy_source: ES, config: Option<JITChannelsConfig<Arc<TestPricing>>>,
+		pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		pending_events: &Arc<EventQueue>,
+	) -> TestHandler<ES>
+	where
+		ES::Target: EntropySource,
+	{
+		LSPS2MessageHandler::new(
+			entropy_source,
+			Arc::new(TestChannelManager::default()),
+			keys_manager(),
+			Network::Regtest,
+			config,
+			Some(JITChannelsClientConfig { trusted_lsp_node_ids: vec![counterparty_node_id()] }),
+			Arc::clone(pending_messages),
+			Arc::clone(pending_events),
+		)
+	}
+
+	fn counterparty_node_id() -> PublicKey {
+		utils::parse_pubkey("027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190")
+			.unwrap()
+	}
+
+	fn config() -> JITChannelsConfig<Arc<TestPricing>> {
+		JITChannelsConfig {
+			promise_secret: Some([42; 32]),
+			pricing: Arc::new(TestPricing),
+			min_payment_size_msat: 1000,
+			max_payment_size_msat: 1_000_000_000,
+			cltv_expiry_delta: 144,
+			min_channel_size_sat: 100_000,
+			channel_overprovisioning_ppm: 100_000,
+			client_trusts_lsp: false,
+		}
+	}
+
+	fn get_info<ES: Deref>(
+		handler: &TestHandler<ES>, pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+	) -> OpeningFeeParams
+	where
+		ES::Target: EntropySource,
+	{
+		let request = LSPS2Message::Request(
+			RequestId("get_info".to_string()),
+			LSPS2Request::GetInfo(GetInfoRequest { version: 1, token: None }),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+
+		let (_, message) = pending_messages.lock().unwrap().pop().unwrap();
+		match message {
+			LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::GetInfo(response))) => {
+				response.opening_fee_params_menu[0].clone()
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn buy<ES: Deref>(
+		handler: &TestHandler<ES>, pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		payment_size_msat: Option<u64>,
+	) -> u64
+	where
+		ES::Target: EntropySource,
+	{
+		let opening_fee_params = get_info(handler, pending_messages);
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest { version: 1, opening_fee_params, payment_size_msat }),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+
+		let (_, message) = pending_messages.lock().unwrap().pop().unwrap();
+		match message {
+			LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::Buy(response))) => {
+				response.jit_channel_scid.to_scid().unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn htlcs_failed(
+		intercept_scid: u64, payment_hash: [u8; 32], reason: JITChannelFailureReason,
+	) -> Event {
+		Event::LSPS2(LSPS2Event::HTLCsFailed {
+			intercept_scid,
+			counterparty_node_id: counterparty_node_id(),
+			payment_hash: PaymentHash(payment_hash),
+			reason,
+		})
+	}
+
+	fn expect_buy_error(
+		pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>, expected_code: i32,
+	) {
+		let (_, message) = pending_messages.lock().unwrap().pop().unwrap();
+		match message {
+			LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::BuyError(error))) => {
+				assert_eq!(error.code, expected_code)
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn client_create_invoice<ES: Deref>(
+		handler: &TestHandler<ES>, pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+		payment_size_msat: Option<u64>,
+	) -> PaymentHash
+	where
+		ES::Target: EntropySource,
+	{
+		let lsp_node_id = counterparty_node_id();
+		handler.create_invoice(lsp_node_id, payment_size_msat, None, "JIT".to_string(), 3600, 42);
+
+		let (request_id, _) = pop_request(pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+
+		let (request_id, _) = pop_request(pending_messages);
+		let opening_fee_params = RawOpeningFeeParams {
+			min_fee_msat: 5_000,
+			proportional: 1_000,
+			valid_until: LSPSDateTime::now() + Duration::from_secs(3600),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu: vec![opening_fee_params.clone()],
+				min_payment_size_msat: 1_000,
+				max_payment_size_msat: 100_000_000,
+			}),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		handler.select_opening_fee_params(&lsp_node_id, 42, opening_fee_params).unwrap();
+
+		let (request_id, _) = pop_request(pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::Buy(BuyResponse {
+				jit_channel_scid: JitChannelScid("29451x4815x1".to_string()),
+				lsp_cltv_expiry_delta: 144,
+				client_trusts_lsp: false,
+			}),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		PaymentHash([1; 32])
+	}
+
+	fn pop_request(
+		pending_messages: &Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
+	) -> (RequestId, LSPS2Request) {
+		let mut pending_messages = pending_messages.lock().unwrap();
+		assert_eq!(pending_messages.len(), 1);
+		match pending_messages.pop().unwrap() {
+			(_, LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request))) => {
+				(request_id, request)
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn accepts_buy_with_promised_params() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+
+		let opening_fee_params = get_info(&handler, &pending_messages);
+
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params: opening_fee_params.clone(),
+				payment_size_msat: Some(42_000_000),
+			}),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		let (_, message) = pending_messages.lock().unwrap().pop().unwrap();
+		assert_eq!(
+			message,
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("buy".to_string()),
+				LSPS2Response::Buy(BuyResponse {
+					jit_channel_scid: JitChannelScid("29451x4815x1".to_string()),
+					lsp_cltv_expiry_delta: 144,
+					client_trusts_lsp: false,
+				})
+			))
+		);
+
+		let scid = (29451 << 40) | (4815 << 16) | 1;
+		let expected_channel = OutboundJITChannel {
+			intercept_scid: scid,
+			counterparty_node_id: counterparty_node_id(),
+			opening_fee_params,
+			payment_size_msat: Some(42_000_000),
+			client_trusts_lsp: false,
+		};
+		assert_eq!(handler.outbound_channel_by_scid(scid), Some(expected_channel.clone()));
+		assert_eq!(handler.outbound_channel_by_scid(scid + 1), None);
+		assert_eq!(
+			handler.outbound_channels_for_counterparty(&counterparty_node_id()),
+			vec![expected_channel]
+		);
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(!handler.get_and_clear_needs_persistence());
+	}
+
+	#[test]
+	fn restores_sold_channels_after_restart() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+
+		let opening_fee_params = get_info(&handler, &pending_messages);
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params,
+				payment_size_msat: None,
+			}),
+		);
+		handler.handle_message(request, &counterparty_node_id()).unwrap();
+		let scid = (29451 << 40) | (4815 << 16) | 1;
+		let channel = handler.outbound_channel_by_scid(scid).unwrap();
+
+		let encoded = handler.encode();
+		let restored =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &encoded[..]).unwrap();
+		assert_eq!(restored.outbound_channel_by_scid(scid), Some(channel.clone()));
+		assert_eq!(
+			restored.outbound_channels_for_counterparty(&counterparty_node_id()),
+			vec![channel]
+		);
+		assert!(!restored.get_and_clear_needs_persistence());
+	}
+
+	#[test]
+	fn remembers_opened_channels_after_restart() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		let user_channel_id = handler.channel_manager.created_channels.lock().unwrap()[0].2;
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// After a restart while the channel is pending, the HTLC is intercepted again and held
+		// until the channel we already started to open is ready.
+		let restored =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &handler.encode()[..]).unwrap();
+		restored
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		assert!(restored.channel_manager.created_channels.lock().unwrap().is_empty());
+		restored.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+		assert_eq!(
+			*restored.channel_manager.forwarded_htlcs.lock().unwrap(),
+			vec![(InterceptId([1; 32]), [3; 32], 9_454_000)]
+		);
+		assert!(restored.get_and_clear_needs_persistence());
+
+		// After a restart once the channel is ready, HTLCs are forwarded over it without paying
+		// the opening fee again.
+		let restored_again =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		restored_again.read_state(&mut &restored.encode()[..]).unwrap();
+		restored_again
+			.htlc_intercepted(scid, InterceptId([2; 32]), 10_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert!(restored_again.channel_manager.created_channels.lock().unwrap().is_empty());
+		assert_eq!(
+			*restored_again.channel_manager.forwarded_htlcs.lock().unwrap(),
+			vec![(InterceptId([2; 32]), [3; 32], 10_000_000)]
+		);
+		assert!(restored_again.channel_manager.failed_htlcs.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn opens_channel_and_forwards_intercepted_htlc_minus_fee() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+
+		// HTLCs for other scids are none of our business.
+		handler
+			.htlc_intercepted(scid + 1, InterceptId([0; 32]), 10_000_000, PaymentHash([0; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		let user_channel_id = {
+			let created_channels = handler.channel_manager.created_channels.lock().unwrap();
+			assert_eq!(created_channels.len(), 1);
+			let (node_id, channel_value_satoshis, user_channel_id) = created_channels[0];
+			assert_eq!(node_id, counterparty_node_id());
+			// The forwarded amount plus 10% is below the minimum channel size.
+			assert_eq!(channel_value_satoshis, 100_000);
+			user_channel_id
+		};
+
+		// Further HTLCs are held until the channel is ready.
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 1_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+		assert!(handler.channel_manager.forwarded_htlcs.lock().unwrap().is_empty());
+
+		handler.channel_ready(user_channel_id + 1, &[3; 32], &counterparty_node_id()).unwrap();
+		assert!(handler.channel_manager.forwarded_htlcs.lock().unwrap().is_empty());
+
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+		assert_eq!(
+			*handler.channel_manager.forwarded_htlcs.lock().unwrap(),
+			vec![
+				(InterceptId([1; 32]), [3; 32], 9_454_000),
+				(InterceptId([2; 32]), [3; 32], 1_000_000)
+			]
+		);
+
+		// Once the channel is open HTLCs are forwarded right away.
+		handler
+			.htlc_intercepted(scid, InterceptId([4; 32]), 2_000_000, PaymentHash([4; 32]))
+			.unwrap();
+		assert_eq!(
+			handler.channel_manager.forwarded_htlcs.lock().unwrap().last(),
+			Some(&(InterceptId([4; 32]), [3; 32], 2_000_000))
+		);
+		assert!(handler.channel_manager.failed_htlcs.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn fails_htlc_not_covering_opening_fee() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 546_000, PaymentHash([1; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+		assert_eq!(
+			*handler.channel_manager.failed_htlcs.lock().unwrap(),
+			vec![InterceptId([1; 32])]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![htlcs_failed(scid, [1; 32], JITChannelFailureReason::OpeningFeeNotCovered)]
+		);
+
+		// In no-MPP mode the next HTLC gets its own chance to pay for the channel.
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 546_001, PaymentHash([2; 32]))
+			.unwrap();
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn accumulates_mpp_parts_until_payment_size_is_reached() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, Some(10_000_000));
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 4_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 1_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+
+		handler
+			.htlc_intercepted(scid, InterceptId([3; 32]), 6_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		let user_channel_id = {
+			let created_channels = handler.channel_manager.created_channels.lock().unwrap();
+			assert_eq!(created_channels.len(), 1);
+			created_channels[0].2
+		};
+
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+		// The 546_000msat fee is spread across the parts of the payment, while the part of the
+		// other payment is forwarded in full.
+		assert_eq!(
+			*handler.channel_manager.forwarded_htlcs.lock().unwrap(),
+			vec![
+				(InterceptId([1; 32]), [3; 32], 3_781_600),
+				(InterceptId([3; 32]), [3; 32], 5_672_400),
+				(InterceptId([2; 32]), [3; 32], 1_000_000),
+			]
+		);
+		assert!(handler.channel_manager.failed_htlcs.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn holds_funding_until_client_claims_in_trusted_mode() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let mut config = config();
+		config.client_trusts_lsp = true;
+		let handler =
+			test_handler(keys_manager(), Some(config), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+		assert!(handler.outbound_channel_by_scid(scid).unwrap().client_trusts_lsp);
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		let user_channel_id = handler.channel_manager.created_channels.lock().unwrap()[0].2;
+		let funding_txid = Txid::from_inner([5; 32]);
+		assert!(!handler.funding_transaction_generated(user_channel_id + 1, funding_txid));
+		assert!(handler.funding_transaction_generated(user_channel_id, funding_txid));
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+
+		// Payments forwarded over other channels don't reveal anything.
+		handler.payment_forwarded(&[4; 32]);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.payment_forwarded(&[3; 32]);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::FundingBroadcastSafe {
+				intercept_scid: scid,
+				counterparty_node_id: counterparty_node_id(),
+				funding_txid,
+			})]
+		);
+
+		handler.payment_forwarded(&[3; 32]);
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn remembers_held_funding_after_restart() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let mut config = config();
+		config.client_trusts_lsp = true;
+		let handler =
+			test_handler(keys_manager(), Some(config.clone()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		let user_channel_id = handler.channel_manager.created_channels.lock().unwrap()[0].2;
+		let funding_txid = Txid::from_inner([5; 32]);
+		handler.get_and_clear_needs_persistence();
+		assert!(handler.funding_transaction_generated(user_channel_id, funding_txid));
+		assert!(handler.get_and_clear_needs_persistence());
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+
+		let mut state = Vec::new();
+		handler.write(&mut state).unwrap();
+		let restored_events = Arc::new(EventQueue::default());
+		let restored =
+			test_handler(keys_manager(), Some(config), &pending_messages, &restored_events);
+		restored.read_state(&mut &state[..]).unwrap();
+
+		restored.payment_forwarded(&[3; 32]);
+		assert_eq!(
+			restored_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::FundingBroadcastSafe {
+				intercept_scid: scid,
+				counterparty_node_id: counterparty_node_id(),
+				funding_txid,
+			})]
+		);
+		assert!(restored.get_and_clear_needs_persistence());
+	}
+
+	#[test]
+	fn fails_held_htlcs_if_channel_does_not_get_ready() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 1_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		let user_channel_id = handler.channel_manager.created_channels.lock().unwrap()[0].2;
+
+		for _ in 1..CHANNEL_OPEN_TIMEOUT_TICKS {
+			handler.timer_tick_occurred();
+		}
+		assert!(handler.channel_manager.failed_htlcs.lock().unwrap().is_empty());
+
+		handler.timer_tick_occurred();
+		assert_eq!(
+			*handler.channel_manager.failed_htlcs.lock().unwrap(),
+			vec![InterceptId([1; 32]), InterceptId([2; 32])]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![
+				htlcs_failed(scid, [1; 32], JITChannelFailureReason::ChannelOpenTimeout),
+				htlcs_failed(scid, [2; 32], JITChannelFailureReason::ChannelOpenTimeout),
+			]
+		);
+
+		// Should the channel get ready after all, there is nothing left to forward.
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+		assert!(handler.channel_manager.forwarded_htlcs.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn fails_held_htlcs_if_channel_open_fails() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, None);
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 1_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		let user_channel_id = handler.channel_manager.created_channels.lock().unwrap()[0].2;
+
+		handler.channel_closed(user_channel_id);
+		assert_eq!(
+			*handler.channel_manager.failed_htlcs.lock().unwrap(),
+			vec![InterceptId([1; 32]), InterceptId([2; 32])]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![
+				htlcs_failed(scid, [1; 32], JITChannelFailureReason::ChannelOpenFailed),
+				htlcs_failed(scid, [2; 32], JITChannelFailureReason::ChannelOpenFailed),
+			]
+		);
+
+		// A late channel_ready for the failed open is ignored, while the next payment gets to open
+		// a new channel.
+		handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+		assert!(handler.channel_manager.forwarded_htlcs.lock().unwrap().is_empty());
+		handler
+			.htlc_intercepted(scid, InterceptId([3; 32]), 10_000_000, PaymentHash([3; 32]))
+			.unwrap();
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn forgets_scids_of_opened_channels() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let open_channel = |payment_hash: [u8; 32]| {
+			let scid = buy(&handler, &pending_messages, None);
+			handler
+				.htlc_intercepted(scid, InterceptId([0; 32]), 10_000_000, PaymentHash(payment_hash))
+				.unwrap();
+			let user_channel_id =
+				handler.channel_manager.created_channels.lock().unwrap().last().unwrap().2;
+			handler.channel_ready(user_channel_id, &[3; 32], &counterparty_node_id()).unwrap();
+			handler.get_and_clear_needs_persistence();
+			(scid, user_channel_id)
+		};
+
+		// A closed channel isn't replaced by a new one.
+		let (scid, user_channel_id) = open_channel([1; 32]);
+		handler.channel_closed(user_channel_id);
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(handler.outbound_channel_by_scid(scid).is_none());
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 10_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+
+		// Open channels are forgotten once their opening fee parameters expired.
+		let (scid, _) = open_channel([3; 32]);
+		handler.timer_tick_occurred();
+		assert!(handler.outbound_channel_by_scid(scid).is_some());
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+			let channel = outbound_channels.channels_by_scid.get_mut(&scid).unwrap();
+			channel.opening_fee_params.valid_until =
+				LSPSDateTime::now() - Duration::from_secs(3600);
+		}
+		handler.timer_tick_occurred();
+		assert!(handler.outbound_channel_by_scid(scid).is_none());
+		assert!(handler.get_and_clear_needs_persistence());
+
+		let mut state = Vec::new();
+		handler.write(&mut state).unwrap();
+		let restored =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &state[..]).unwrap();
+		assert!(restored.outbound_channels_for_counterparty(&counterparty_node_id()).is_empty());
+	}
+
+	#[test]
+	fn times_out_incomplete_mpp_payments() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let scid = buy(&handler, &pending_messages, Some(10_000_000));
+
+		handler
+			.htlc_intercepted(scid, InterceptId([1; 32]), 4_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		handler.timer_tick_occurred();
+		handler
+			.htlc_intercepted(scid, InterceptId([2; 32]), 4_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.failed_htlcs.lock().unwrap().is_empty());
+
+		handler.timer_tick_occurred();
+		assert_eq!(
+			*handler.channel_manager.failed_htlcs.lock().unwrap(),
+			vec![InterceptId([1; 32])]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![htlcs_failed(scid, [1; 32], JITChannelFailureReason::MPPTimeout)]
+		);
+
+		// The timed out parts no longer count towards the payment size.
+		handler
+			.htlc_intercepted(scid, InterceptId([3; 32]), 6_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+
+		handler
+			.htlc_intercepted(scid, InterceptId([4; 32]), 6_000_000, PaymentHash([2; 32]))
+			.unwrap();
+		assert_eq!(handler.channel_manager.created_channels.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn fails_htlcs_for_expired_params_and_forgets_scid() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+
+		let expired = RawOpeningFeeParams {
+			min_fee_msat: 546000,
+			proportional: 1200,
+			valid_until: LSPSDateTime::now() - Duration::from_secs(3600),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		{
+			let service_state = handler.service_state.as_ref().unwrap();
+			let mut outbound_channels = service_state.outbound_channels.lock().unwrap();
+			for (intercept_scid, payment_size_msat) in vec![(1, None), (2, Some(10_000_000))] {
+				outbound_channels.insert(OutboundJITChannel {
+					intercept_scid,
+					counterparty_node_id: counterparty_node_id(),
+					opening_fee_params: expired.clone(),
+					payment_size_msat,
+					client_trusts_lsp: false,
+				});
+			}
+		}
+
+		handler
+			.htlc_intercepted(1, InterceptId([1; 32]), 10_000_000, PaymentHash([1; 32]))
+			.unwrap();
+		assert!(handler.channel_manager.created_channels.lock().unwrap().is_empty());
+		assert_eq!(
+			*handler.channel_manager.failed_htlcs.lock().unwrap(),
+			vec![InterceptId([1; 32])]
+		);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![htlcs_failed(1, [1; 32], JITChannelFailureReason::OpeningFeeParamsExpired)]
+		);
+		assert_eq!(handler.outbound_channel_by_scid(1), None);
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// Unused scids are forgotten on the next timer tick.
+		handler.timer_tick_occurred();
+		assert_eq!(handler.outbound_channel_by_scid(2), None);
+		assert!(handler.outbound_channels_for_counterparty(&counterparty_node_id()).is_empty());
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn rejects_tampered_or_expired_params() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+
+		let mut tampered = get_info(&handler, &pending_messages);
+		tampered.min_fee_msat = 0;
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params: tampered,
+				payment_size_msat: None,
+			}),
+		);
+		assert!(handler.handle_message(request, &counterparty_node_id()).is_err());
+		expect_buy_error(
+			&pending_messages,
+			LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+		);
+
+		let expired = RawOpeningFeeParams {
+			min_fee_msat: 546000,
+			proportional: 1200,
+			valid_until: LSPSDateTime::now() - Duration::from_secs(3600),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params: expired,
+				payment_size_msat: None,
+			}),
+		);
+		assert!(handler.handle_message(request, &counterparty_node_id()).is_err());
+		expect_buy_error(
+			&pending_messages,
+			LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+		);
+
+		let opening_fee_params = get_info(&handler, &pending_messages);
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 2,
+				opening_fee_params,
+				payment_size_msat: None,
+			}),
+		);
+		assert!(handler.handle_message(request, &counterparty_node_id()).is_err());
+		expect_buy_error(&pending_messages, LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE);
+
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn rejects_unacceptable_payment_sizes() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let opening_fee_params = get_info(&handler, &pending_messages);
+
+		let cases = vec![
+			// Below the minimum payment size.
+			(999, LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE),
+			// Doesn't cover the 546_000msat minimum fee.
+			(546_000, LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE),
+			// Above the maximum payment size.
+			(1_000_000_001, LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE),
+		];
+		for (payment_size_msat, expected_code) in cases {
+			let request = LSPS2Message::Request(
+				RequestId("buy".to_string()),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params.clone(),
+					payment_size_msat: Some(payment_size_msat),
+				}),
+			);
+			assert!(handler.handle_message(request, &counterparty_node_id()).is_err());
+			expect_buy_error(&pending_messages, expected_code);
+		}
+
+		let mut config = config();
+		config.max_payment_size_msat = u64::MAX;
+		let handler =
+			test_handler(keys_manager(), Some(config), &pending_messages, &pending_events);
+		let opening_fee_params = get_info(&handler, &pending_messages);
+		let request = LSPS2Message::Request(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params,
+				payment_size_msat: Some(u64::MAX),
+			}),
+		);
+		assert!(handler.handle_message(request, &counterparty_node_id()).is_err());
+		expect_buy_error(&pending_messages, LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE);
+
+		assert!(handler.outbound_channels_for_counterparty(&counterparty_node_id()).is_empty());
+	}
+
+	#[test]
+	fn generates_menu_from_pricing() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let handler = test_handler(
+			keys_manager(),
+			Some(config()),
+			&pending_messages,
+			&Arc::new(EventQueue::default()),
+		);
+
+		assert_eq!(get_info(&handler, &pending_messages).min_fee_msat, 546000);
+
+		let get_info_with_token = |token: &str| {
+			let request = LSPS2Message::Request(
+				RequestId("get_info".to_string()),
+				LSPS2Request::GetInfo(GetInfoRequest {
+					version: 1,
+					token: Some(token.to_string()),
+				}),
+			);
+			let result = handler.handle_message(request, &counterparty_node_id());
+			let (_, message) = pending_messages.lock().unwrap().pop().unwrap();
+			(result, message)
+		};
+
+		match get_info_with_token("discount") {
+			(
+				Ok(()),
+				LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::GetInfo(response))),
+			) => {
+				assert_eq!(response.opening_fee_params_menu[0].min_fee_msat, 1_000);
+				assert!(is_valid_opening_fee_params(
+					&response.opening_fee_params_menu[0],
+					&[42; 32]
+				));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Tokens the pricing doesn't recognize are rejected.
+		match get_info_with_token("stale") {
+			(
+				Err(_),
+				LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::GetInfoError(error))),
+			) => {
+				assert_eq!(
+					error.code,
+					LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE
+				);
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn accepts_promises_of_previous_secrets_until_they_expire() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		let buy_with = |handler: &TestHandler<Arc<KeysManager>>,
+		                opening_fee_params: OpeningFeeParams| {
+			let request = LSPS2Message::Request(
+				RequestId("buy".to_string()),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params,
+					payment_size_msat: None,
+				}),
+			);
+			let result = handler.handle_message(request, &counterparty_node_id());
+			pending_messages.lock().unwrap().clear();
+			result
+		};
+
+		let old_params = get_info(&handler, &pending_messages);
+		handler.get_and_clear_needs_persistence();
+		handler.rotate_promise_secret(Some([43; 32]));
+		assert!(handler.get_and_clear_needs_persistence());
+
+		let new_params = get_info(&handler, &pending_messages);
+		assert!(is_valid_opening_fee_params(&new_params, &[43; 32]));
+		assert!(buy_with(&handler, old_params.clone()).is_ok());
+		assert!(buy_with(&handler, new_params.clone()).is_ok());
+
+		// Restarting with the old secret configured makes the rotated one a previous secret.
+		let restored =
+			test_handler(keys_manager(), Some(config()), &pending_messages, &pending_events);
+		restored.read_state(&mut &handler.encode()[..]).unwrap();
+		assert!(buy_with(&restored, old_params.clone()).is_ok());
+		assert!(buy_with(&restored, new_params.clone()).is_ok());
+		assert!(is_valid_opening_fee_params(&get_info(&restored, &pending_messages), &[42; 32]));
+
+		// Once all of its promises expired, a previous secret is retired.
+		handler.service_state.as_ref().unwrap().promise_secrets.lock().unwrap().previous[0]
+			.valid_until = Some(LSPSDateTime::now() - Duration::from_secs(3600));
+		handler.timer_tick_occurred();
+		assert!(handler.get_and_clear_needs_persistence());
+		assert!(buy_with(&handler, old_params).is_err());
+		assert!(buy_with(&handler, new_params).is_ok());
+	}
+
+	#[test]
+	fn uses_random_secret_if_none_configured() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let mut config = config();
+		config.promise_secret = None;
+		let handler = test_handler(
+			Arc::new(TestEntropy {}),
+			Some(config),
+			&pending_messages,
+			&Arc::new(EventQueue::default()),
+		);
+
+		let opening_fee_params = get_info(&handler, &pending_messages);
+		assert!(is_valid_opening_fee_params(&opening_fee_params, &[21; 32]));
+		assert!(!is_valid_opening_fee_params(&opening_fee_params, &[42; 32]));
+	}
+
+	#[test]
+	fn client_buys_selected_channel_and_creates_invoice() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let lsp_node_id = counterparty_node_id();
+
+		handler.create_invoice(
+			lsp_node_id,
+			Some(10_000_000),
+			Some("token".to_string()),
+			"JIT channel".to_string(),
+			3600,
+			42,
+		);
+
+		let (request_id, request) = pop_request(&pending_messages);
+		assert_eq!(request, LSPS2Request::GetVersions(GetVersionsRequest {}));
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1, 2] }),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+
+		let (request_id, request) = pop_request(&pending_messages);
+		assert_eq!(
+			request,
+			LSPS2Request::GetInfo(GetInfoRequest { version: 1, token: Some("token".to_string()) })
+		);
+
+		let valid_until = LSPSDateTime::now() + Duration::from_secs(3600);
+		let expensive = RawOpeningFeeParams {
+			min_fee_msat: 1_000,
+			proportional: 10_000,
+			valid_until,
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		let cheap = RawOpeningFeeParams {
+			min_fee_msat: 5_000,
+			proportional: 1_000,
+			valid_until,
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu: vec![expensive.clone(), cheap.clone()],
+				min_payment_size_msat: 1_000,
+				max_payment_size_msat: 100_000_000,
+			}),
+		);
+		handler.handle_message(response.clone(), &lsp_node_id).unwrap();
+
+		// Nothing is bought until we selected one of the offered parameters, cheapest first.
+		assert!(pending_messages.lock().unwrap().is_empty());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::OpeningFeeParamsMenuReady {
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id,
+				opening_fee_params_menu: vec![cheap.clone(), expensive],
+				min_payment_size_msat: 1_000,
+				max_payment_size_msat: 100_000_000,
+				payment_size_msat: Some(10_000_000),
+			})]
+		);
+
+		// A duplicate response is ignored, keeping the menu to select from.
+		assert!(handler.handle_message(response, &lsp_node_id).is_err());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		let unoffered = RawOpeningFeeParams {
+			min_fee_msat: 0,
+			proportional: 0,
+			valid_until,
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		assert!(handler.select_opening_fee_params(&lsp_node_id, 42, unoffered).is_err());
+		assert!(handler.select_opening_fee_params(&lsp_node_id, 43, cheap.clone()).is_err());
+		assert!(pending_messages.lock().unwrap().is_empty());
+
+		handler.select_opening_fee_params(&lsp_node_id, 42, cheap.clone()).unwrap();
+		assert!(handler.select_opening_fee_params(&lsp_node_id, 42, cheap.clone()).is_err());
+
+		let (request_id, request) = pop_request(&pending_messages);
+		assert_eq!(
+			request,
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params: cheap.clone(),
+				payment_size_msat: Some(10_000_000),
+			})
+		);
+
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::Buy(BuyResponse {
+				jit_channel_scid: JitChannelScid("29451x4815x1".to_string()),
+				lsp_cltv_expiry_delta: 144,
+				client_trusts_lsp: true,
+			}),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		assert!(pending_messages.lock().unwrap().is_empty());
+
+		let mut events = pending_events.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events.pop().unwrap() {
+			Event::LSPS2(LSPS2Event::InvoiceReady {
+				user_channel_id,
+				counterparty_node_id,
+				invoice,
+				opening_fee_params,
+				payment_size_msat,
+				opening_fee_msat,
+				client_trusts_lsp,
+			}) => {
+				assert_eq!(user_channel_id, 42);
+				assert!(client_trusts_lsp);
+				// 0.1% of 10_000_000msat exceeds the 5_000msat minimum fee.
+				assert_eq!(opening_fee_msat, Some(10_000));
+				assert_eq!(counterparty_node_id, lsp_node_id);
+				assert_eq!(opening_fee_params, cheap);
+				assert_eq!(payment_size_msat, Some(10_000_000));
+				assert_eq!(invoice.amount_milli_satoshis(), Some(10_000_000));
+				assert_eq!(invoice.payment_hash().into_inner(), [1; 32]);
+
+				let route_hints = invoice.route_hints();
+				assert_eq!(route_hints.len(), 1);
+				assert_eq!(route_hints[0].0.len(), 1);
+				let hop = &route_hints[0].0[0];
+				assert_eq!(hop.src_node_id, lsp_node_id);
+				assert_eq!(hop.short_channel_id, (29451 << 40) | (4815 << 16) | 1);
+				assert_eq!(hop.cltv_expiry_delta, 144);
+			}
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn client_aborts_if_no_params_qualify() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let lsp_node_id = counterparty_node_id();
+
+		handler.create_invoice(lsp_node_id, Some(1_000), None, String::new(), 3600, 42);
+
+		let (request_id, _) = pop_request(&pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+
+		let (request_id, _) = pop_request(&pending_messages);
+		let response = LSPS2Message::Response(
+			request_id.clone(),
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu: vec![RawOpeningFeeParams {
+					min_fee_msat: 546000,
+					proportional: 1200,
+					valid_until: LSPSDateTime::now() + Duration::from_secs(3600),
+					min_lifetime: 1008,
+					max_client_to_self_delay: 2016,
+				}
+				.into_opening_fee_params(&[42; 32])],
+				min_payment_size_msat: 1_000,
+				max_payment_size_msat: 100_000_000,
+			}),
+		);
+		assert!(handler.handle_message(response.clone(), &lsp_node_id).is_err());
+		assert!(pending_messages.lock().unwrap().is_empty());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::InvoiceCreationFailed {
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id,
+				reason: InvoiceCreationFailureReason::NoSuitableOpeningFeeParams,
+			})]
+		);
+
+		// The request is forgotten, so a repeated response is rejected.
+		assert!(handler.handle_message(response, &lsp_node_id).is_err());
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn client_reports_error_responses_and_unanswered_requests() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let lsp_node_id = counterparty_node_id();
+
+		handler.create_invoice(lsp_node_id, None, None, String::new(), 3600, 42);
+		let (request_id, _) = pop_request(&pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersionsError(ResponseError {
+				code: -32601,
+				message: "method not found".to_string(),
+				data: None,
+			}),
+		);
+		assert!(handler.handle_message(response, &lsp_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::InvoiceCreationFailed {
+				user_channel_id: 42,
+				counterparty_node_id: lsp_node_id,
+				reason: InvoiceCreationFailureReason::ErrorResponse {
+					code: -32601,
+					message: "method not found".to_string(),
+				},
+			})]
+		);
+
+		// Requests the LSP never responds to are given up on.
+		handler.create_invoice(lsp_node_id, None, None, String::new(), 3600, 43);
+		let (request_id, _) = pop_request(&pending_messages);
+		for _ in 0..REQUEST_TIMEOUT_TICKS - 1 {
+			handler.timer_tick_occurred();
+		}
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+		handler.timer_tick_occurred();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::InvoiceCreationFailed {
+				user_channel_id: 43,
+				counterparty_node_id: lsp_node_id,
+				reason: InvoiceCreationFailureReason::Timeout,
+			})]
+		);
+		assert!(handler.per_peer_state.lock().unwrap().is_empty());
+
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+		);
+		assert!(handler.handle_message(response, &lsp_node_id).is_err());
+
+		// So are menus we never select from.
+		handler.create_invoice(lsp_node_id, None, None, String::new(), 3600, 44);
+		let (request_id, _) = pop_request(&pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		let (request_id, _) = pop_request(&pending_messages);
+		let response = LSPS2Message::Response(
+			request_id,
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu: vec![RawOpeningFeeParams {
+					min_fee_msat: 546000,
+					proportional: 1200,
+					valid_until: LSPSDateTime::now() + Duration::from_secs(3600),
+					min_lifetime: 1008,
+					max_client_to_self_delay: 2016,
+				}
+				.into_opening_fee_params(&[42; 32])],
+				min_payment_size_msat: 1_000,
+				max_payment_size_msat: 100_000_000,
+			}),
+		);
+		handler.handle_message(response, &lsp_node_id).unwrap();
+		assert_eq!(pending_events.get_and_clear_pending_events().len(), 1);
+		for _ in 0..REQUEST_TIMEOUT_TICKS {
+			handler.timer_tick_occurred();
+		}
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::InvoiceCreationFailed {
+				user_channel_id: 44,
+				counterparty_node_id: lsp_node_id,
+				reason: InvoiceCreationFailureReason::Timeout,
+			})]
+		);
+	}
+
+	#[test]
+	fn client_refuses_to_claim_overcharged_payment() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let payment_hash = client_create_invoice(&handler, &pending_messages, Some(10_000_000));
+		pending_events.get_and_clear_pending_events();
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// The agreed fee survives a restart.
+		let restored = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		restored.read_state(&mut &handler.encode()[..]).unwrap();
+
+		// 0.1% of 10_000_000msat is 10_000msat.
+		assert!(!restored.payment_claimable(payment_hash, 9_980_000, 20_000));
+		assert_eq!(*restored.channel_manager.failed_payments.lock().unwrap(), vec![payment_hash]);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::OpeningFeeOvercharged {
+				user_channel_id: 42,
+				counterparty_node_id: counterparty_node_id(),
+				payment_hash,
+				opening_fee_msat: Some(10_000),
+				skimmed_fee_msat: 20_000,
+			})]
+		);
+
+		// A retry taking the agreed fee may be claimed.
+		assert!(restored.payment_claimable(payment_hash, 9_990_000, 10_000));
+		assert!(restored.get_and_clear_needs_persistence());
+
+		// Payments to other invoices are none of our business.
+		assert!(restored.payment_claimable(PaymentHash([2; 32]), 9_980_000, 20_000));
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		// If the fee for the forwarded amount overflows, no fee may be deducted.
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let payment_hash = client_create_invoice(&handler, &pending_messages, None);
+		pending_events.get_and_clear_pending_events();
+		assert!(!handler.payment_claimable(payment_hash, u64::MAX - 5_000, 5_000));
+		assert_eq!(*handler.channel_manager.failed_payments.lock().unwrap(), vec![payment_hash]);
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2(LSPS2Event::OpeningFeeOvercharged {
+				user_channel_id: 42,
+				counterparty_node_id: counterparty_node_id(),
+				payment_hash,
+				opening_fee_msat: None,
+				skimmed_fee_msat: 5_000,
+			})]
+		);
+	}
+
+	#[test]
+	fn client_checks_fee_against_forwarded_amount_without_payment_size() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let payment_hash = client_create_invoice(&handler, &pending_messages, None);
+		pending_events.get_and_clear_pending_events();
+
+		// The fee on the 1_000_000msat the LSP forwarded is its 5_000msat minimum.
+		assert!(!handler.payment_claimable(payment_hash, 994_000, 6_000));
+		assert!(handler.payment_claimable(payment_hash, 995_000, 5_000));
+	}
+
+	#[test]
+	fn client_accepts_one_zero_conf_channel_per_outstanding_buy() {
+		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+		let handler = test_handler(keys_manager(), None, &pending_messages, &pending_events);
+		let other_node_id = utils::parse_pubkey(
+			"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+		)
+		.unwrap();
+
+		assert!(!handler.open_channel_requested(&[1; 32], &counterparty_node_id(), 1).unwrap());
+
+		let payment_hash = client_create_invoice(&handler, &pending_messages, None);
+		assert!(!handler.open_channel_requested(&[2; 32], &other_node_id, 2).unwrap());
+		handler.get_and_clear_needs_persistence();
+		assert!(handler.open_channel_requested(&[3; 32], &counterparty_node_id(), 3).unwrap());
+		assert!(handler.get_and_clear_needs_persistence());
+
+		// The buy is consumed by the first channel, even across restarts.
+		let mut state = Vec::new();
+		handler.write(&mut state).unwrap();
+		handler.read_state(&mut &state[..]).unwrap();
+		assert!(!handler.open_channel_requested(&[4; 32], &counterparty_node_id(), 4).unwrap());
+		assert_eq!(
+			handler.inbound_payments.lock().unwrap()[&payment_hash].zero_conf_user_channel_id,
+			Some(3)
+		);
+
+		// Once the payment is claimed the buy is no longer outstanding.
+		assert!(handler.payment_claimable(payment_hash, 995_000, 5_000));
+		assert!(!handler.open_channel_requested(&[5; 32], &counterparty_node_id(), 5).unwrap());
+
+		// Channels we don't accept as zero-conf are left to the user.
+		assert_eq!(
+			*handler.channel_manager.accepted_channels.lock().unwrap(),
+			vec![([3; 32], true)]
+		);
+	}
+}
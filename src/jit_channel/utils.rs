@@ -0,0 +1,331 @@
+use bitcoin::hashes::cmp::fixed_time_eq;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+
+use crate::jit_channel::msgs::OpeningFeeParams;
+use crate::transport::msgs::LSPSDateTime;
+use crate::utils;
+
+/// Computes the `promise` for the given opening fee parameters.
+///
+/// The promise is an HMAC-SHA256 over the canonical encoding of all other fields, which allows us
+/// to verify the parameters a client hands back in `lsps2.buy` without storing every quote.
+pub(crate) fn compute_opening_fee_params_promise(
+	min_fee_msat: u64, proportional: u32, valid_until: &LSPSDateTime, min_lifetime: u32,
+	max_client_to_self_delay: u32, promise_secret: &[u8; 32],
+) -> String {
+	let promise_bytes = opening_fee_params_promise_bytes(
+		min_fee_msat,
+		proportional,
+		valid_until,
+		min_lifetime,
+		max_client_to_self_delay,
+		promise_secret,
+	);
+	utils::hex_str(&promise_bytes[..])
+}
+
+fn opening_fee_params_promise_bytes(
+	min_fee_msat: u64, proportional: u32, valid_until: &LSPSDateTime, min_lifetime: u32,
+	max_client_to_self_delay: u32, promise_secret: &[u8; 32],
+) -> [u8; 32] {
+	let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
+	hmac.input(&min_fee_msat.to_be_bytes());
+	hmac.input(&proportional.to_be_bytes());
+	hmac.input(valid_until.to_rfc3339().as_bytes());
+	hmac.input(&min_lifetime.to_be_bytes());
+	hmac.input(&max_client_to_self_delay.to_be_bytes());
+	Hmac::from_engine(hmac).into_inner()
+}
+
+/// Determines if the given parameters are valid given the secret used to generate the promise.
+pub(crate) fn is_valid_opening_fee_params(
+	fee_params: &OpeningFeeParams, promise_secret: &[u8; 32],
+) -> bool {
+	let promise = match utils::to_vec(&fee_params.promise) {
+		Some(promise) if promise.len() == 32 => promise,
+		_ => return false,
+	};
+	let expected_promise = opening_fee_params_promise_bytes(
+		fee_params.min_fee_msat,
+		fee_params.proportional,
+		&fee_params.valid_until,
+		fee_params.min_lifetime,
+		fee_params.max_client_to_self_delay,
+		promise_secret,
+	);
+	// The promise is compared in constant time, so its bytes can't be guessed one at a time.
+	fixed_time_eq(&promise, &expected_promise)
+}
+
+/// Determines if the given parameters are expired, or still valid.
+pub(crate) fn is_expired_opening_fee_params(fee_params: &OpeningFeeParams) -> bool {
+	fee_params.valid_until.is_past()
+}
+
+/// Computes the opening fee for a JIT channel.
+///
+/// The fee is `max(min_fee_msat, ceil(payment_size_msat * proportional / 1_000_000))`, where
+/// `min_fee_msat` and `proportional` are taken from the [`OpeningFeeParams`] the channel is
+/// bought with.
+///
+/// Returns `None` if the computation overflows, in which case the payment is too large to be
+/// received via a JIT channel.
+pub fn compute_opening_fee(
+	payment_size_msat: u64, opening_fee_params: &OpeningFeeParams,
+) -> Option<u64> {
+	payment_size_msat
+		.checked_mul(u64::from(opening_fee_params.proportional))
+		.and_then(|f| f.checked_add(999_999))
+		.map(|f| f / 1_000_000)
+		.map(|f| std::cmp::max(f, opening_fee_params.min_fee_msat))
+}
+
+/// Computes the size of the channel to open for forwarding the given amount.
+///
+/// The channel is overprovisioned by the given parts per million, but is at least
+/// `min_channel_size_sat` large.
+pub(crate) fn compute_channel_size_sat(
+	amt_to_forward_msat: u64, min_channel_size_sat: u64, overprovisioning_ppm: u32,
+) -> u64 {
+	let overprovisioned_msat =
+		amt_to_forward_msat.saturating_mul(1_000_000 + u64::from(overprovisioning_ppm)) / 1_000_000;
+	let channel_size_sat = (overprovisioned_msat + 999) / 1000;
+	std::cmp::max(channel_size_sat, min_channel_size_sat)
+}
+
+/// Spreads the opening fee across the parts of a payment, returning the amounts to forward.
+///
+/// Every part bears a share of the fee proportional to its amount, but is left with at least
+/// `htlc_minimum_msat`. Returns `None` if the parts can't cover the fee this way.
+pub(crate) fn compute_amounts_to_forward_msat(
+	amounts_msat: &[u64], opening_fee_msat: u64, htlc_minimum_msat: u64,
+) -> Option<Vec<u64>> {
+	let htlc_minimum_msat = std::cmp::max(htlc_minimum_msat, 1);
+	if amounts_msat.iter().any(|amount_msat| *amount_msat < htlc_minimum_msat) {
+		return None;
+	}
+
+	let total_msat =
+		amounts_msat.iter().try_fold(0u64, |total, amount| total.checked_add(*amount))?;
+	let max_fee_msat: u64 =
+		amounts_msat.iter().map(|amount_msat| amount_msat - htlc_minimum_msat).sum();
+	if max_fee_msat < opening_fee_msat {
+		return None;
+	}
+
+	let mut remaining_fee_msat = opening_fee_msat;
+	let mut amounts_to_forward_msat = Vec::with_capacity(amounts_msat.len());
+	for amount_msat in amounts_msat {
+		let proportional_fee_msat = (u128::from(opening_fee_msat) * u128::from(*amount_msat)
+			/ u128::from(total_msat)) as u64;
+		let fee_msat = std::cmp::min(
+			std::cmp::min(proportional_fee_msat, amount_msat - htlc_minimum_msat),
+			remaining_fee_msat,
+		);
+		remaining_fee_msat -= fee_msat;
+		amounts_to_forward_msat.push(amount_msat - fee_msat);
+	}
+
+	// Whatever rounding left over is taken from the parts that can still bear it.
+	for amount_to_forward_msat in amounts_to_forward_msat.iter_mut() {
+		let fee_msat =
+			std::cmp::min(*amount_to_forward_msat - htlc_minimum_msat, remaining_fee_msat);
+		*amount_to_forward_msat -= fee_msat;
+		remaining_fee_msat -= fee_msat;
+	}
+
+	Some(amounts_to_forward_msat)
+}
+
+/// Returns the unexpired entries of the given menu, cheapest first.
+///
+/// If the payment size is known, entries whose opening fee would not be covered by the payment
+/// are disregarded and the remaining ones are ordered by their actual fee. Otherwise, entries
+/// are ordered by their minimum fee, then their proportional fee.
+pub(crate) fn qualifying_opening_fee_params(
+	opening_fee_params_menu: &[OpeningFeeParams], payment_size_msat: Option<u64>,
+) -> Vec<OpeningFeeParams> {
+	let candidates = opening_fee_params_menu.iter().filter(|p| !is_expired_opening_fee_params(p));
+
+	match payment_size_msat {
+		Some(payment_size_msat) => {
+			let mut candidates: Vec<(u64, &OpeningFeeParams)> = candidates
+				.filter_map(|p| {
+					compute_opening_fee(payment_size_msat, p)
+						.filter(|fee| *fee < payment_size_msat)
+						.map(|fee| (fee, p))
+				})
+				.collect();
+			candidates.sort_by_key(|(fee, _)| *fee);
+			candidates.into_iter().map(|(_, p)| p.clone()).collect()
+		}
+		None => {
+			let mut candidates: Vec<&OpeningFeeParams> = candidates.collect();
+			candidates.sort_by_key(|p| (p.min_fee_msat, p.proportional));
+			candidates.into_iter().cloned().collect()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jit_channel::msgs::RawOpeningFeeParams;
+	use std::time::Duration;
+
+	#[test]
+	fn validates_promise() {
+		let promise_secret = [42; 32];
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: LSPSDateTime::now() + Duration::from_secs(3600),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		};
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret);
+
+		assert!(is_valid_opening_fee_params(&opening_fee_params, &promise_secret));
+		assert!(!is_valid_opening_fee_params(&opening_fee_params, &[0; 32]));
+		assert!(!is_expired_opening_fee_params(&opening_fee_params));
+
+		let mut tampered = opening_fee_params.clone();
+		tampered.min_fee_msat = 99;
+		assert!(!is_valid_opening_fee_params(&tampered, &promise_secret));
+
+		let mut tampered = opening_fee_params.clone();
+		tampered.valid_until = tampered.valid_until + Duration::from_secs(1);
+		assert!(!is_valid_opening_fee_params(&tampered, &promise_secret));
+
+		// Malformed promises are rejected rather than compared.
+		let mut malformed = opening_fee_params;
+		malformed.promise.truncate(62);
+		assert!(!is_valid_opening_fee_params(&malformed, &promise_secret));
+		malformed.promise = "zz".repeat(32);
+		assert!(!is_valid_opening_fee_params(&malformed, &promise_secret));
+	}
+
+	#[test]
+	fn promise_survives_serialization() {
+		let promise_secret = [42; 32];
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 546000,
+			proportional: 1200,
+			valid_until: LSPSDateTime::now() - Duration::from_secs(1),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		};
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret);
+
+		let json = serde_json::to_string(&opening_fee_params).unwrap();
+		let parsed: OpeningFeeParams = serde_json::from_str(&json).unwrap();
+		assert!(is_valid_opening_fee_params(&parsed, &promise_secret));
+		assert!(is_expired_opening_fee_params(&parsed));
+	}
+
+	fn opening_fee_params(
+		min_fee_msat: u64, proportional: u32, valid_until: LSPSDateTime,
+	) -> OpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat,
+			proportional,
+			valid_until,
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+		}
+		.into_opening_fee_params(&[42; 32])
+	}
+
+	#[test]
+	fn orders_qualifying_opening_fee_params_by_fee() {
+		let valid_until = LSPSDateTime::now() + Duration::from_secs(3600);
+		let expired = LSPSDateTime::now() - Duration::from_secs(3600);
+		let menu = vec![
+			opening_fee_params(1_000, 10_000, valid_until),
+			opening_fee_params(5_000, 1_000, valid_until),
+			opening_fee_params(0, 0, expired),
+		];
+
+		// Without a payment size the lowest minimum fee comes first.
+		assert_eq!(qualifying_opening_fee_params(&menu, None), menu[..2].to_vec());
+
+		// 1% of 10_000_000 msat is 100_000 msat, while 0.1% is only 10_000 msat.
+		assert_eq!(
+			qualifying_opening_fee_params(&menu, Some(10_000_000)),
+			vec![menu[1].clone(), menu[0].clone()]
+		);
+
+		// For small payments the minimum fees dominate.
+		assert_eq!(qualifying_opening_fee_params(&menu, Some(10_000)), menu[..2].to_vec());
+
+		// Entries whose fee can't be covered by the payment don't qualify.
+		assert_eq!(qualifying_opening_fee_params(&menu, Some(5_000)), menu[..1].to_vec());
+		assert_eq!(qualifying_opening_fee_params(&menu, Some(1_000)), Vec::new());
+		assert_eq!(qualifying_opening_fee_params(&menu[2..], None), Vec::new());
+	}
+
+	#[test]
+	fn computes_channel_size() {
+		assert_eq!(compute_channel_size_sat(1_000_000, 0, 0), 1_000);
+		assert_eq!(compute_channel_size_sat(1_000_001, 0, 0), 1_001);
+		assert_eq!(compute_channel_size_sat(1_000_000, 0, 100_000), 1_100);
+		assert_eq!(compute_channel_size_sat(1_000_000, 100_000, 100_000), 100_000);
+		assert_eq!(compute_channel_size_sat(u64::MAX, 0, 100_000), u64::MAX / 1_000_000_000 + 1);
+	}
+
+	#[test]
+	fn spreads_opening_fee_across_parts() {
+		// The fee is split proportionally to the parts' amounts.
+		assert_eq!(
+			compute_amounts_to_forward_msat(&[30_000, 10_000], 4_000, 1),
+			Some(vec![27_000, 9_000])
+		);
+
+		// Rounding leftovers are taken from the first parts.
+		assert_eq!(
+			compute_amounts_to_forward_msat(&[10_000, 10_000, 10_000], 1_000, 1),
+			Some(vec![9_666, 9_667, 9_667])
+		);
+
+		// No part goes below the HTLC minimum, others bear the difference.
+		assert_eq!(
+			compute_amounts_to_forward_msat(&[1_500, 10_000], 4_600, 1_000),
+			Some(vec![1_000, 5_900])
+		);
+
+		assert_eq!(compute_amounts_to_forward_msat(&[1_500, 10_000], 9_501, 1_000), None);
+		assert_eq!(compute_amounts_to_forward_msat(&[500, 10_000], 100, 1_000), None);
+		assert_eq!(compute_amounts_to_forward_msat(&[1_000], 1_000, 0), None);
+		assert_eq!(compute_amounts_to_forward_msat(&[1_000], 999, 0), Some(vec![1]));
+		assert_eq!(compute_amounts_to_forward_msat(&[], 0, 1), Some(vec![]));
+	}
+
+	#[test]
+	fn computes_opening_fee() {
+		let valid_until = LSPSDateTime::now() + Duration::from_secs(3600);
+		let fee = |payment_size_msat: u64, min_fee_msat: u64, proportional: u32| {
+			compute_opening_fee(
+				payment_size_msat,
+				&opening_fee_params(min_fee_msat, proportional, valid_until),
+			)
+		};
+
+		// The minimum fee applies to small payments.
+		assert_eq!(fee(10_000_000, 546_000, 1_200), Some(546_000));
+		assert_eq!(fee(0, 546_000, 1_200), Some(546_000));
+
+		// Larger payments pay the proportional fee, rounded up.
+		assert_eq!(fee(1_000_000_000, 546_000, 1_200), Some(1_200_000));
+		assert_eq!(fee(1_000_000_001, 546_000, 1_200), Some(1_200_001));
+		assert_eq!(fee(1, 0, 1), Some(1));
+		assert_eq!(fee(1_000_000, 0, 0), Some(0));
+
+		// Overflows are detected, both in the multiplication and the rounding.
+		assert_eq!(fee(u64::MAX, 0, 2), None);
+		assert_eq!(fee(u64::MAX, 0, 1), None);
+		assert_eq!(fee(u64::MAX - 999_999, 0, 1), Some(u64::MAX / 1_000_000));
+		assert_eq!(fee(u64::MAX / 1_000_000, 0, 1_000_000), None);
+	}
+}
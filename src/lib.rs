@@ -18,10 +18,34 @@
 #![allow(clippy::drop_non_drop)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+mod channel_manager;
 mod channel_request;
 pub mod events;
 mod jit_channel;
 mod transport;
 mod utils;
 
-pub use transport::message_handler::{LiquidityManager, LiquidityProviderConfig};
+pub use channel_manager::ChannelManagerInterface;
+pub use channel_request::event::{LSPS1Event, OrderCreationFailureReason};
+pub use channel_request::msgs::{
+	ChannelInfo, OnchainPayment, OptionsSupported, OrderId, OrderParams, OrderReceipt, OrderState,
+	PaymentInfo, PaymentState,
+};
+pub use channel_request::pricing::{
+	ChannelOrderPricing, ChannelOrderPricingConfig, DefaultChannelOrderPricing,
+};
+pub use channel_request::protocol::{
+	ChannelRequestsClientConfig, ChannelRequestsConfig, InboundChannelOrder, OutboundChannelOrder,
+};
+pub use channel_request::signer::OrderReceiptSigner;
+pub use channel_request::wallet::ChannelOrderWallet;
+pub use jit_channel::broadcaster::JITFundingBroadcaster;
+pub use jit_channel::event::{InvoiceCreationFailureReason, JITChannelFailureReason, LSPS2Event};
+pub use jit_channel::msgs::{OpeningFeeParams, RawOpeningFeeParams};
+pub use jit_channel::pricing::JITChannelPricing;
+pub use jit_channel::protocol::{JITChannelsClientConfig, JITChannelsConfig, OutboundJITChannel};
+pub use jit_channel::utils::compute_opening_fee;
+pub use transport::message_handler::{
+	LiquidityClientConfig, LiquidityManager, LiquidityManagerReadArgs, LiquidityProviderConfig,
+};
+pub use transport::msgs::LSPSDateTime;
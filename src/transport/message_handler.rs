@@ -1,19 +1,37 @@
+use crate::channel_manager::ChannelManagerInterface;
+use crate::channel_request::msgs::{OrderId, OrderParams, OrderState};
+use crate::channel_request::pricing::ChannelOrderPricing;
+use crate::channel_request::protocol::{
+	ChannelRequestsClientConfig, ChannelRequestsConfig, LSPS1MessageHandler, OutboundChannelOrder,
+};
+use crate::channel_request::signer::OrderReceiptSigner;
+use crate::channel_request::wallet::ChannelOrderWallet;
 use crate::events::{Event, EventQueue};
-use crate::transport::msgs::{LSPSMessage, RawLSPSMessage, LSPS_MESSAGE_TYPE};
+use crate::jit_channel::msgs::OpeningFeeParams;
+use crate::jit_channel::pricing::JITChannelPricing;
+use crate::jit_channel::protocol::{
+	JITChannelsClientConfig, JITChannelsConfig, LSPS2MessageHandler, OutboundJITChannel,
+};
+use crate::transport::msgs::{LSPSDateTime, LSPSMessage, RawLSPSMessage, LSPS_MESSAGE_TYPE};
 use crate::transport::protocol::LSPS0MessageHandler;
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, BlockHash, BlockHeader, Network, OutPoint, Transaction, Txid};
+use lightning::chain::{Confirm, Listen, TransactionData};
+use lightning::ln::channelmanager::{InterceptId, Retry};
 use lightning::ln::features::{InitFeatures, NodeFeatures};
-use lightning::ln::msgs::{ErrorAction, LightningError};
+use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError};
 use lightning::ln::peer_handler::CustomMessageHandler;
 use lightning::ln::wire::CustomMessageReader;
-use lightning::sign::EntropySource;
+use lightning::ln::PaymentHash;
+use lightning::sign::{EntropySource, NodeSigner};
+use lightning::util::errors::APIError;
 use lightning::util::logger::Level;
-use lightning::util::ser::Readable;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::sync::{Arc, Mutex};
 
 const LSPS_FEATURE_BIT: usize = 729;
@@ -35,45 +53,604 @@ pub(crate) trait ProtocolMessageHandler {
 ///
 /// Allows end-user to configure options when using the [`LiquidityManager`]
 /// to provide liquidity services to clients.
-pub struct LiquidityProviderConfig;
+pub struct LiquidityProviderConfig<JP: Deref, CP: Deref, OW: Deref, RS: Deref>
+where
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	/// Optional configuration for JIT channels
+	/// should you want to support them.
+	pub jit_channels: Option<JITChannelsConfig<JP>>,
+	/// Optional configuration for selling channels clients order
+	/// should you want to support them.
+	pub channel_requests: Option<ChannelRequestsConfig<CP, OW, RS>>,
+}
+
+/// A client-side configuration for [`LiquidityManager`].
+///
+/// Allows end-user to configure options when using the [`LiquidityManager`]
+/// to buy liquidity services from LSPs.
+pub struct LiquidityClientConfig {
+	/// Optional configuration for buying JIT channels.
+	pub jit_channels: Option<JITChannelsClientConfig>,
+	/// Optional configuration for ordering channels.
+	pub channel_requests: Option<ChannelRequestsClientConfig>,
+}
 
 /// The main interface into LSP functionality.
 ///
 /// Should be used as a [`CustomMessageHandler`] for your
 /// [`lightning::ln::peer_handler::PeerManager`]'s [`lightning::ln::peer_handler::MessageHandler`].
-pub struct LiquidityManager<ES: Deref>
-where
+///
+/// To track on-chain payments for channel orders and the leases of ordered channels, it also has
+/// to be notified of blocks as a [`Listen`] or [`Confirm`], after the
+/// [`lightning::ln::channelmanager::ChannelManager`] was. On-chain payments are only found in
+/// full blocks, see [`ChannelRequestsConfig::wallet`].
+pub struct LiquidityManager<
+	ES: Deref + Clone,
+	CM: Deref + Clone,
+	NS: Deref + Clone,
+	JP: Deref,
+	CP: Deref,
+	OW: Deref,
+	RS: Deref,
+> where
 	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
 {
 	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 	pending_events: Arc<EventQueue>,
 	request_id_to_method_map: Mutex<HashMap<String, String>>,
 	lsps0_message_handler: LSPS0MessageHandler<ES>,
-	provider_config: Option<LiquidityProviderConfig>,
+	lsps1_message_handler: LSPS1MessageHandler<ES, CM, NS, CP, OW, RS>,
+	lsps2_message_handler: LSPS2MessageHandler<ES, CM, NS, JP>,
+	is_provider: bool,
 }
 
-impl<ES: Deref> LiquidityManager<ES>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
 where
 	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
 {
 	/// Constructor for the LiquidityManager
 	///
 	/// Sets up the required protocol message handlers based on the given [`LiquidityProviderConfig`].
-	pub fn new(entropy_source: ES, provider_config: Option<LiquidityProviderConfig>) -> Self {
+	/// The [`LiquidityClientConfig`] configures how we act as a client of LSPs.
+	///
+	/// The `node_signer` is used to sign the invoices we create as a client of JIT channels and
+	/// as a seller of ordered channels, and `network` determines their currency. As a client of
+	/// ordered channels, we also check the receipts the LSP signed against our node id.
+	pub fn new(
+		entropy_source: ES, channel_manager: CM, node_signer: NS, network: Network,
+		provider_config: Option<LiquidityProviderConfig<JP, CP, OW, RS>>,
+		client_config: Option<LiquidityClientConfig>,
+	) -> Self {
 		let pending_messages = Arc::new(Mutex::new(vec![]));
+		let pending_events = Arc::new(EventQueue::default());
+
+		let is_provider = provider_config.is_some();
+		let (jit_channels_config, channel_requests_config) = match provider_config {
+			Some(config) => (config.jit_channels, config.channel_requests),
+			None => (None, None),
+		};
+		let (jit_channels_client_config, channel_requests_client_config) = match client_config {
+			Some(config) => (config.jit_channels, config.channel_requests),
+			None => (None, None),
+		};
+
+		let mut protocols = vec![];
+		if channel_requests_config.is_some() {
+			protocols.extend(LSPS1MessageHandler::<ES, CM, NS, CP, OW, RS>::PROTOCOL_NUMBER);
+		}
+		if jit_channels_config.is_some() {
+			protocols.extend(LSPS2MessageHandler::<ES, CM, NS, JP>::PROTOCOL_NUMBER);
+		}
+
+		let lsps1_message_handler = LSPS1MessageHandler::new(
+			entropy_source.clone(),
+			channel_manager.clone(),
+			node_signer.clone(),
+			network,
+			channel_requests_config,
+			channel_requests_client_config,
+			Arc::clone(&pending_messages),
+			Arc::clone(&pending_events),
+		);
+
+		let lsps2_message_handler = LSPS2MessageHandler::new(
+			entropy_source.clone(),
+			channel_manager,
+			node_signer,
+			network,
+			jit_channels_config,
+			jit_channels_client_config,
+			Arc::clone(&pending_messages),
+			Arc::clone(&pending_events),
+		);
 
 		let lsps0_message_handler =
-			LSPS0MessageHandler::new(entropy_source, vec![], Arc::clone(&pending_messages));
+			LSPS0MessageHandler::new(entropy_source, protocols, Arc::clone(&pending_messages));
 
 		Self {
 			pending_messages,
-			pending_events: Arc::new(EventQueue::default()),
+			pending_events,
 			request_id_to_method_map: Mutex::new(HashMap::new()),
 			lsps0_message_handler,
-			provider_config,
+			lsps1_message_handler,
+			lsps2_message_handler,
+			is_provider,
 		}
 	}
 
+	/// Used by a client to order a channel from the given LSP.
+	///
+	/// Fetches the options the LSP supports, which are presented via an
+	/// [`LSPS1Event::SupportedOptionsReady`] event. The order is only placed once it was passed
+	/// to [`LiquidityManager::channel_order_create`]. All events carry the given
+	/// `user_channel_id`. If the LSP doesn't respond in time, or no order is placed within a few
+	/// timer ticks, an [`LSPS1Event::OrderCreationFailed`] event is emitted.
+	///
+	/// [`LSPS1Event::SupportedOptionsReady`]: crate::LSPS1Event::SupportedOptionsReady
+	/// [`LSPS1Event::OrderCreationFailed`]: crate::LSPS1Event::OrderCreationFailed
+	pub fn channel_order_request_options(
+		&self, counterparty_node_id: PublicKey, user_channel_id: u128,
+	) {
+		self.lsps1_message_handler.request_options(counterparty_node_id, user_channel_id)
+	}
+
+	/// Used by a client to place an order for a channel with the given parameters.
+	///
+	/// Should be called in response to an [`LSPS1Event::SupportedOptionsReady`] event. Errors
+	/// naming the offending property if the order doesn't match the options the LSP supports.
+	///
+	/// `token` is an optional token the LSP may e.g. grant a discount for, such as a coupon code.
+	/// If the LSP doesn't recognize it, the order fails.
+	///
+	/// `refund_onchain_address` is where the LSP refunds an on-chain payment to if the order fails
+	/// after it was paid. Errors if it isn't an address on our network.
+	///
+	/// The order the LSP created is returned via an [`LSPS1Event::OrderCreated`] event. If the
+	/// LSP rejects the order, or created one differing from the requested one, an
+	/// [`LSPS1Event::OrderCreationFailed`] event is emitted instead. The same applies if the LSP
+	/// signed a receipt for the order that doesn't match it or isn't signed by the LSP for us.
+	/// Valid receipts are kept as the order's [`InboundChannelOrder::receipt`].
+	///
+	/// [`LSPS1Event::SupportedOptionsReady`]: crate::LSPS1Event::SupportedOptionsReady
+	/// [`InboundChannelOrder::receipt`]: crate::InboundChannelOrder::receipt
+	/// [`LSPS1Event::OrderCreated`]: crate::LSPS1Event::OrderCreated
+	/// [`LSPS1Event::OrderCreationFailed`]: crate::LSPS1Event::OrderCreationFailed
+	pub fn channel_order_create(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, order: OrderParams,
+		token: Option<String>, refund_onchain_address: Option<Address>,
+	) -> Result<(), APIError> {
+		self.lsps1_message_handler.create_order(
+			counterparty_node_id,
+			user_channel_id,
+			order,
+			token,
+			refund_onchain_address,
+		)
+	}
+
+	/// Used by a client to fetch the current status of an order it placed with the given LSP.
+	///
+	/// The order is returned via an [`LSPS1Event::OrderStatusReady`] event. Once the LSP reports the
+	/// order as completed, the channel it opened is checked against our channels, and an
+	/// [`LSPS1Event::ChannelDeliveryDisputed`] event is emitted if it doesn't match the order.
+	///
+	/// [`LSPS1Event::OrderStatusReady`]: crate::LSPS1Event::OrderStatusReady
+	/// [`LSPS1Event::ChannelDeliveryDisputed`]: crate::LSPS1Event::ChannelDeliveryDisputed
+	pub fn channel_order_check_status(
+		&self, counterparty_node_id: &PublicKey, order_id: OrderId,
+	) -> Result<(), APIError> {
+		self.lsps1_message_handler.check_order_status(counterparty_node_id, order_id)
+	}
+
+	/// Used by a client to pay for an order it placed.
+	///
+	/// Decodes the order's invoice and checks that it pays the order total to the LSP, and that
+	/// it is the invoice the LSP created the order with, before paying it via the
+	/// [`ChannelManager`]. Errors if the order doesn't expect a payment, or its invoice expired.
+	///
+	/// The outcome of the payment is reported via an [`LSPS1Event::OrderPaymentSent`] or
+	/// [`LSPS1Event::OrderPaymentFailed`] event once it was passed to
+	/// [`LiquidityManager::payment_sent`] or [`LiquidityManager::payment_failed`].
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	/// [`LSPS1Event::OrderPaymentSent`]: crate::LSPS1Event::OrderPaymentSent
+	/// [`LSPS1Event::OrderPaymentFailed`]: crate::LSPS1Event::OrderPaymentFailed
+	pub fn channel_order_pay(
+		&self, order_id: &OrderId, retry_strategy: Retry,
+	) -> Result<(), APIError> {
+		self.lsps1_message_handler.pay_order(order_id, retry_strategy)
+	}
+
+	/// Used by a client to buy a JIT channel from the given LSP and get an invoice for it.
+	///
+	/// Negotiates the protocol version with the LSP and fetches its opening fee parameters. The
+	/// ones valid for the given `payment_size_msat` are presented via an
+	/// [`LSPS2Event::OpeningFeeParamsMenuReady`] event, and the channel is only bought once one of
+	/// them was selected via [`LiquidityManager::jit_channel_select_opening_fee_params`].
+	///
+	/// The invoice, which has a route hint through the LSP's intercept scid, is then returned via
+	/// an [`LSPS2Event::InvoiceReady`] event. All events carry the given `user_channel_id`. If
+	/// buying the channel fails, including if the LSP doesn't respond in time, an
+	/// [`LSPS2Event::InvoiceCreationFailed`] event is emitted instead.
+	///
+	/// `token` is an optional token the LSP may require, e.g., to authenticate the client.
+	///
+	/// [`LSPS2Event::OpeningFeeParamsMenuReady`]: crate::LSPS2Event::OpeningFeeParamsMenuReady
+	/// [`LSPS2Event::InvoiceReady`]: crate::LSPS2Event::InvoiceReady
+	/// [`LSPS2Event::InvoiceCreationFailed`]: crate::LSPS2Event::InvoiceCreationFailed
+	pub fn jit_channel_create_invoice(
+		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
+		token: Option<String>, description: String, expiry_secs: u32, user_channel_id: u128,
+	) {
+		self.lsps2_message_handler.create_invoice(
+			counterparty_node_id,
+			payment_size_msat,
+			token,
+			description,
+			expiry_secs,
+			user_channel_id,
+		)
+	}
+
+	/// Used by a client to buy a JIT channel with the given opening fee parameters.
+	///
+	/// Should be called in response to an [`LSPS2Event::OpeningFeeParamsMenuReady`] event, passing
+	/// one of the offered parameters. Errors if they weren't offered for the given
+	/// `user_channel_id` or have expired meanwhile.
+	///
+	/// If the selection takes longer than a few timer ticks, buying the channel is given up.
+	///
+	/// [`LSPS2Event::OpeningFeeParamsMenuReady`]: crate::LSPS2Event::OpeningFeeParamsMenuReady
+	pub fn jit_channel_select_opening_fee_params(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+		opening_fee_params: OpeningFeeParams,
+	) -> Result<(), APIError> {
+		self.lsps2_message_handler.select_opening_fee_params(
+			counterparty_node_id,
+			user_channel_id,
+			opening_fee_params,
+		)
+	}
+
+	/// Used by a client to verify the opening fee an LSP deducted from a payment before claiming
+	/// it.
+	///
+	/// Should be called in response to every [`Event::PaymentClaimable`], passing its
+	/// `counterparty_skimmed_fee_msat`. Returns whether the payment may be claimed. Payments to
+	/// invoices not created via [`LiquidityManager::jit_channel_create_invoice`] are always
+	/// claimable.
+	///
+	/// If the LSP deducted more than the opening fee we agreed on, the payment is failed back and
+	/// an [`LSPS2Event::OpeningFeeOvercharged`] event is emitted.
+	///
+	/// Note that LSPs can only deduct a fee if [`ChannelConfig::accept_underpaying_htlcs`] is set.
+	///
+	/// Also used by LSP to hold the payment of a channel order, which is never claimable right
+	/// away. Instead the ordered channel is opened, and the payment is claimed once it is ready.
+	/// Payments not covering the order total, or arriving after the order expired, are failed
+	/// back.
+	///
+	/// [`Event::PaymentClaimable`]: lightning::events::Event::PaymentClaimable
+	/// [`LSPS2Event::OpeningFeeOvercharged`]: crate::LSPS2Event::OpeningFeeOvercharged
+	/// [`ChannelConfig::accept_underpaying_htlcs`]: lightning::util::config::ChannelConfig::accept_underpaying_htlcs
+	pub fn payment_claimable(
+		&self, payment_hash: PaymentHash, amount_msat: u64, counterparty_skimmed_fee_msat: u64,
+	) -> bool {
+		self.lsps1_message_handler.payment_claimable(payment_hash, amount_msat)
+			&& self.lsps2_message_handler.payment_claimable(
+				payment_hash,
+				amount_msat,
+				counterparty_skimmed_fee_msat,
+			)
+	}
+
+	/// Used by a client to learn that the payment for an order succeeded.
+	///
+	/// Should be called in response to every [`Event::PaymentSent`]. Emits an
+	/// [`LSPS1Event::OrderPaymentSent`] event if the payment was made via
+	/// [`LiquidityManager::channel_order_pay`].
+	///
+	/// [`Event::PaymentSent`]: lightning::events::Event::PaymentSent
+	/// [`LSPS1Event::OrderPaymentSent`]: crate::LSPS1Event::OrderPaymentSent
+	pub fn payment_sent(&self, payment_hash: PaymentHash) {
+		self.lsps1_message_handler.payment_sent(payment_hash)
+	}
+
+	/// Used by a client to learn that the payment for an order failed.
+	///
+	/// Should be called in response to every [`Event::PaymentFailed`]. Emits an
+	/// [`LSPS1Event::OrderPaymentFailed`] event if the payment was made via
+	/// [`LiquidityManager::channel_order_pay`].
+	///
+	/// [`Event::PaymentFailed`]: lightning::events::Event::PaymentFailed
+	/// [`LSPS1Event::OrderPaymentFailed`]: crate::LSPS1Event::OrderPaymentFailed
+	pub fn payment_failed(&self, payment_hash: PaymentHash) {
+		self.lsps1_message_handler.payment_failed(payment_hash)
+	}
+
+	/// Used by a client to accept the zero-conf channels LSPs open to it.
+	///
+	/// Should be called in response to every [`Event::OpenChannelRequest`], which requires
+	/// [`UserConfig::manually_accept_inbound_channels`] to be set. A channel from one of the LSPs
+	/// configured in [`JITChannelsClientConfig::trusted_lsp_node_ids`] is accepted as zero-conf
+	/// if we have an unpaid invoice for a JIT channel bought from it which no zero-conf channel
+	/// was accepted for yet, in which case `true` is returned.
+	///
+	/// All other channels are left alone and `false` is returned, so they may be accepted or
+	/// rejected as usual.
+	///
+	/// [`Event::OpenChannelRequest`]: lightning::events::Event::OpenChannelRequest
+	/// [`UserConfig::manually_accept_inbound_channels`]: lightning::util::config::UserConfig::manually_accept_inbound_channels
+	/// [`JITChannelsClientConfig::trusted_lsp_node_ids`]: crate::JITChannelsClientConfig::trusted_lsp_node_ids
+	pub fn open_channel_requested(
+		&self, temporary_channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+		user_channel_id: u128,
+	) -> Result<bool, APIError> {
+		self.lsps2_message_handler.open_channel_requested(
+			temporary_channel_id,
+			counterparty_node_id,
+			user_channel_id,
+		)
+	}
+
+	/// Returns the order a client placed with us with the given id, if any.
+	pub fn channel_order_by_id(&self, order_id: &OrderId) -> Option<OutboundChannelOrder> {
+		self.lsps1_message_handler.outbound_order_by_id(order_id)
+	}
+
+	/// Returns the orders clients placed with us, oldest first.
+	///
+	/// Only orders in the given `order_state`, placed by the given client, and created within the
+	/// given range are returned, for each filter that is set.
+	pub fn channel_orders(
+		&self, order_state: Option<OrderState>, counterparty_node_id: Option<&PublicKey>,
+		created_at: Option<Range<LSPSDateTime>>,
+	) -> Vec<OutboundChannelOrder> {
+		self.lsps1_message_handler.list_outbound_orders(
+			order_state,
+			counterparty_node_id,
+			created_at,
+		)
+	}
+
+	/// Used by LSP to cancel an order a client placed with us that wasn't paid for yet.
+	///
+	/// The order fails, which the client learns the next time it checks its status, and payments
+	/// to its invoice are failed back. Errors if the order isn't awaiting payment anymore.
+	pub fn channel_order_cancel(&self, order_id: &OrderId) -> Result<(), APIError> {
+		self.lsps1_message_handler.cancel_outbound_order(order_id)
+	}
+
+	/// Returns the JIT channel a client bought from us with the given intercept scid, if any.
+	///
+	/// Useful to match an intercepted HTLC to the client it is destined for.
+	pub fn jit_channel_by_scid(&self, intercept_scid: u64) -> Option<OutboundJITChannel> {
+		self.lsps2_message_handler.outbound_channel_by_scid(intercept_scid)
+	}
+
+	/// Returns all JIT channels the given client bought from us.
+	pub fn jit_channels_for_client(
+		&self, counterparty_node_id: &PublicKey,
+	) -> Vec<OutboundJITChannel> {
+		self.lsps2_message_handler.outbound_channels_for_counterparty(counterparty_node_id)
+	}
+
+	/// Used by LSP to hold an intercepted HTLC and open the JIT channel it is destined for.
+	///
+	/// Should be called in response to every [`Event::HTLCIntercepted`], which requires
+	/// [`UserConfig::accept_intercept_htlcs`] to be set. HTLCs for scids we didn't issue to a
+	/// client are ignored.
+	///
+	/// If the client bought the channel for a fixed `payment_size_msat`, the parts of the payment
+	/// are held until they add up to it, and the opening fee is spread across them. Otherwise the
+	/// first HTLC has to cover the fee on its own.
+	///
+	/// Once the channel is ready the HTLCs are forwarded with the opening fee deducted. If that
+	/// isn't possible, the HTLCs are failed back and an [`LSPS2Event::HTLCsFailed`] event is
+	/// emitted. Note that HTLCs are always failed back with `unknown_next_peer`, as
+	/// [`ChannelManager::fail_intercepted_htlc`] doesn't allow to pick the failure code.
+	///
+	/// [`LSPS2Event::HTLCsFailed`]: crate::LSPS2Event::HTLCsFailed
+	/// [`ChannelManager::fail_intercepted_htlc`]: lightning::ln::channelmanager::ChannelManager::fail_intercepted_htlc
+	///
+	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
+	/// [`UserConfig::accept_intercept_htlcs`]: lightning::util::config::UserConfig::accept_intercept_htlcs
+	pub fn htlc_intercepted(
+		&self, intercept_scid: u64, intercept_id: InterceptId, expected_outbound_amount_msat: u64,
+		payment_hash: PaymentHash,
+	) -> Result<(), APIError> {
+		self.lsps2_message_handler.htlc_intercepted(
+			intercept_scid,
+			intercept_id,
+			expected_outbound_amount_msat,
+			payment_hash,
+		)
+	}
+
+	/// Used by LSP to accept on-chain payments for orders before they confirmed.
+	///
+	/// Should be called for every unconfirmed transaction our wallet sees paying to one of the
+	/// addresses handed out by the [`ChannelOrderWallet`], along with the fee it pays. Payments
+	/// paying at least the [`ChannelRequestsConfig::min_fee_for_0conf`] feerate are accepted right
+	/// away, all others once they have the confirmations the order requires, as learned via
+	/// [`Listen`] or [`Confirm`].
+	///
+	/// [`ChannelOrderWallet`]: crate::ChannelOrderWallet
+	/// [`ChannelRequestsConfig::min_fee_for_0conf`]: crate::ChannelRequestsConfig::min_fee_for_0conf
+	pub fn unconfirmed_transaction_seen(&self, tx: &Transaction, fee_sat: u64) {
+		self.lsps1_message_handler.unconfirmed_transaction_seen(tx, fee_sat)
+	}
+
+	/// Used by LSP to record the channel it opened for an order.
+	///
+	/// Should be called in response to every [`Event::ChannelPending`]. Sets the funding outpoint
+	/// and expiry of the channel reported for the order. Channels we didn't open for orders are
+	/// ignored.
+	///
+	/// [`Event::ChannelPending`]: lightning::events::Event::ChannelPending
+	pub fn channel_pending(
+		&self, user_channel_id: u128, counterparty_node_id: &PublicKey, funding_txo: OutPoint,
+	) -> Result<(), APIError> {
+		self.lsps1_message_handler.channel_pending(
+			user_channel_id,
+			counterparty_node_id,
+			funding_txo,
+		)
+	}
+
+	/// Used by LSP to forward the HTLCs held for a JIT channel once it is ready.
+	///
+	/// Should be called in response to every [`Event::ChannelReady`]. Channels we didn't open
+	/// via [`LiquidityManager::htlc_intercepted`] are ignored.
+	///
+	/// Also claims the held payment of the order the channel was opened for, if any.
+	///
+	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+	pub fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &[u8; 32], counterparty_node_id: &PublicKey,
+	) -> Result<(), APIError> {
+		// The channel was opened for either protocol, so both get to see it.
+		let lsps1_result =
+			self.lsps1_message_handler.channel_ready(user_channel_id, counterparty_node_id);
+		let lsps2_result = self.lsps2_message_handler.channel_ready(
+			user_channel_id,
+			channel_id,
+			counterparty_node_id,
+		);
+		match (lsps1_result, lsps2_result) {
+			(Ok(()), Ok(())) => Ok(()),
+			(Err(e), Ok(())) | (Ok(()), Err(e)) => Err(e),
+			(Err(lsps1_error), Err(lsps2_error)) => Err(APIError::APIMisuseError {
+				err: format!("{:?}, {:?}", lsps1_error, lsps2_error),
+			}),
+		}
+	}
+
+	/// Used by LSP to learn whether the funding transaction of a channel has to be held back.
+	///
+	/// Should be called in response to every [`Event::FundingGenerationReady`], before passing
+	/// the funding transaction to the [`ChannelManager`]. Returns true for JIT channels sold in
+	/// `client_trusts_lsp` mode, in which case the transaction has to be held via
+	/// [`JITFundingBroadcaster::hold_transaction`] until an [`LSPS2Event::FundingBroadcastSafe`]
+	/// event is emitted.
+	///
+	/// [`Event::FundingGenerationReady`]: lightning::events::Event::FundingGenerationReady
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	/// [`JITFundingBroadcaster::hold_transaction`]: crate::JITFundingBroadcaster::hold_transaction
+	/// [`LSPS2Event::FundingBroadcastSafe`]: crate::LSPS2Event::FundingBroadcastSafe
+	pub fn funding_transaction_generated(&self, user_channel_id: u128, funding_txid: Txid) -> bool {
+		self.lsps2_message_handler.funding_transaction_generated(user_channel_id, funding_txid)
+	}
+
+	/// Used by LSP to learn that a client claimed a payment over a JIT channel.
+	///
+	/// Should be called in response to every [`Event::PaymentForwarded`] with its
+	/// `next_channel_id`. Emits an [`LSPS2Event::FundingBroadcastSafe`] event if the channel's
+	/// funding transaction was held back.
+	///
+	/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
+	/// [`LSPS2Event::FundingBroadcastSafe`]: crate::LSPS2Event::FundingBroadcastSafe
+	pub fn payment_forwarded(&self, next_channel_id: &[u8; 32]) {
+		self.lsps2_message_handler.payment_forwarded(next_channel_id)
+	}
+
+	/// Used by LSP to fail back the HTLCs held for a JIT channel whose open failed.
+	///
+	/// Should be called in response to every [`Event::ChannelClosed`]. Channels we didn't open
+	/// via [`LiquidityManager::htlc_intercepted`] are ignored. If the closed channel was ready,
+	/// its intercept scid is forgotten, otherwise the next payment to the scid may open a new
+	/// channel.
+	///
+	/// If the channel was opened for an order and never got ready, the order's held payment is
+	/// failed back and the order fails. If the order was paid for on-chain, an
+	/// [`LSPS1Event::RefundRequired`] event is emitted instead.
+	///
+	/// [`Event::ChannelClosed`]: lightning::events::Event::ChannelClosed
+	/// [`LSPS1Event::RefundRequired`]: crate::LSPS1Event::RefundRequired
+	pub fn channel_closed(&self, user_channel_id: u128) {
+		self.lsps1_message_handler.channel_closed(user_channel_id);
+		self.lsps2_message_handler.channel_closed(user_channel_id)
+	}
+
+	/// Used by LSP to record the refund of an order's on-chain payment.
+	///
+	/// Should be called once the refund requested via an [`LSPS1Event::RefundRequired`] event was
+	/// sent, passing the id of its transaction. The order's payment moves to `REFUNDED`. Errors if
+	/// the order doesn't await a refund.
+	///
+	/// [`LSPS1Event::RefundRequired`]: crate::LSPS1Event::RefundRequired
+	pub fn channel_order_refunded(
+		&self, order_id: &OrderId, refund_txid: Txid,
+	) -> Result<(), APIError> {
+		self.lsps1_message_handler.order_refunded(order_id, refund_txid)
+	}
+
+	/// Fails back the HTLCs we gave up waiting on.
+	///
+	/// This fails the parts of initial payments which didn't add up to the expected payment size
+	/// in time, as well as HTLCs held for channels whose opening fee parameters expired. The
+	/// intercept scids of such channels are forgotten, as are those of opened channels once their
+	/// opening fee parameters expired.
+	///
+	/// Clients forget the opening fees agreed for JIT channel invoices which expired unpaid, and
+	/// give up on buying JIT channels from LSPs which didn't respond, or whose opening fee
+	/// parameters weren't selected, within a few ticks. The same goes for channel orders.
+	/// LSPs retire previous promise secrets once all of their promises expired.
+	///
+	/// Channel orders which, or whose invoices, expired unpaid fail, which is reported via an
+	/// [`LSPS1Event::OrderStateChanged`] event.
+	///
+	/// Should be called roughly once per minute.
+	///
+	/// [`LSPS1Event::OrderStateChanged`]: crate::LSPS1Event::OrderStateChanged
+	pub fn timer_tick_occurred(&self) {
+		self.lsps1_message_handler.timer_tick_occurred();
+		self.lsps2_message_handler.timer_tick_occurred()
+	}
+
+	/// Used by LSP to rotate the secret it promises opening fee parameters with.
+	///
+	/// If no `promise_secret` is given, a random one is drawn from the node's [`EntropySource`].
+	/// Promises made with the previous secret are still accepted until they expire, after which
+	/// the secret is retired on the next [`LiquidityManager::timer_tick_occurred`].
+	pub fn rotate_promise_secret(&self, promise_secret: Option<[u8; 32]>) {
+		self.lsps2_message_handler.rotate_promise_secret(promise_secret)
+	}
+
+	/// Returns whether state was updated since the last call, and clears the flag.
+	///
+	/// If this returns true the [`LiquidityManager`] should be persisted, so that the intercept
+	/// scids we issued to clients, the opening fees we agreed on with LSPs, as well as channel
+	/// orders, survive a restart. It can be read back via [`LiquidityManagerReadArgs`].
+	pub fn get_and_clear_needs_persistence(&self) -> bool {
+		self.lsps1_message_handler.get_and_clear_needs_persistence()
+			| self.lsps2_message_handler.get_and_clear_needs_persistence()
+	}
+
 	/// Blocks until next event is ready and returns it
 	///
 	/// Typically you would spawn a thread or task that calls this in a loop
@@ -98,6 +675,12 @@ where
 			LSPSMessage::LSPS0(msg) => {
 				self.lsps0_message_handler.handle_message(msg, sender_node_id)?;
 			}
+			LSPSMessage::LSPS1(msg) => {
+				self.lsps1_message_handler.handle_message(msg, sender_node_id)?;
+			}
+			LSPSMessage::LSPS2(msg) => {
+				self.lsps2_message_handler.handle_message(msg, sender_node_id)?;
+			}
 		}
 		Ok(())
 	}
@@ -108,9 +691,118 @@ where
 	}
 }
 
-impl<ES: Deref> CustomMessageReader for LiquidityManager<ES>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> Writeable for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.lsps2_message_handler.write(writer)?;
+		self.lsps1_message_handler.write(writer)
+	}
+}
+
+/// Arguments for reading a previously persisted [`LiquidityManager`].
+///
+/// The arguments match those of [`LiquidityManager::new`].
+pub struct LiquidityManagerReadArgs<
+	ES: Deref + Clone,
+	CM: Deref + Clone,
+	NS: Deref + Clone,
+	JP: Deref,
+	CP: Deref,
+	OW: Deref,
+	RS: Deref,
+> where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	/// The entropy source to use.
+	pub entropy_source: ES,
+	/// The channel manager to use.
+	pub channel_manager: CM,
+	/// The node signer used to sign our invoices.
+	pub node_signer: NS,
+	/// The network we operate on.
+	pub network: Network,
+	/// The configuration to use, which may differ from the one the [`LiquidityManager`] was
+	/// persisted with.
+	pub provider_config: Option<LiquidityProviderConfig<JP, CP, OW, RS>>,
+	/// The client configuration to use.
+	pub client_config: Option<LiquidityClientConfig>,
+}
+
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> ReadableArgs<LiquidityManagerReadArgs<ES, CM, NS, JP, CP, OW, RS>>
+	for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	fn read<R: io::Read>(
+		reader: &mut R, args: LiquidityManagerReadArgs<ES, CM, NS, JP, CP, OW, RS>,
+	) -> Result<Self, DecodeError> {
+		let liquidity_manager = Self::new(
+			args.entropy_source,
+			args.channel_manager,
+			args.node_signer,
+			args.network,
+			args.provider_config,
+			args.client_config,
+		);
+		liquidity_manager.lsps2_message_handler.read_state(reader)?;
+		liquidity_manager.lsps1_message_handler.read_state(reader)?;
+		Ok(liquidity_manager)
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> CustomMessageReader for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
 where
 	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
 {
 	type CustomMessage = RawLSPSMessage;
 
@@ -124,9 +816,23 @@ where
 	}
 }
 
-impl<ES: Deref> CustomMessageHandler for LiquidityManager<ES>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> CustomMessageHandler for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
 where
 	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
 {
 	fn handle_custom_message(
 		&self, msg: Self::CustomMessage, sender_node_id: &PublicKey,
@@ -163,7 +869,7 @@ where
 	fn provided_node_features(&self) -> NodeFeatures {
 		let mut features = NodeFeatures::empty();
 
-		if self.provider_config.is_some() {
+		if self.is_provider {
 			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
 		}
 
@@ -173,10 +879,75 @@ where
 	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
 		let mut features = InitFeatures::empty();
 
-		if self.provider_config.is_some() {
+		if self.is_provider {
 			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
 		}
 
 		features
 	}
 }
+
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> Listen for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	fn filtered_block_connected(
+		&self, header: &BlockHeader, txdata: &TransactionData, height: u32,
+	) {
+		self.lsps1_message_handler.transactions_confirmed(header, txdata, height);
+		self.lsps1_message_handler.best_block_updated(header, height);
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader, height: u32) {
+		self.lsps1_message_handler.block_disconnected(header, height);
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		NS: Deref + Clone,
+		JP: Deref,
+		CP: Deref,
+		OW: Deref,
+		RS: Deref,
+	> Confirm for LiquidityManager<ES, CM, NS, JP, CP, OW, RS>
+where
+	ES::Target: EntropySource,
+	CM::Target: ChannelManagerInterface,
+	NS::Target: NodeSigner,
+	JP::Target: JITChannelPricing,
+	CP::Target: ChannelOrderPricing,
+	OW::Target: ChannelOrderWallet,
+	RS::Target: OrderReceiptSigner,
+{
+	fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		self.lsps1_message_handler.transactions_confirmed(header, txdata, height);
+	}
+
+	fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.lsps1_message_handler.transaction_unconfirmed(txid);
+	}
+
+	fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		self.lsps1_message_handler.best_block_updated(header, height);
+	}
+
+	fn get_relevant_txids(&self) -> Vec<(Txid, Option<BlockHash>)> {
+		self.lsps1_message_handler.get_relevant_txids()
+	}
+}
@@ -1,12 +1,27 @@
+use crate::channel_request::msgs::{
+	LSPS1Message, LSPS1Request, LSPS1Response, LSPS1_CREATE_ORDER_METHOD_NAME,
+	LSPS1_GET_INFO_METHOD_NAME, LSPS1_GET_ORDER_METHOD_NAME,
+};
+use crate::jit_channel::msgs::{
+	LSPS2Message, LSPS2Request, LSPS2Response, LSPS2_BUY_METHOD_NAME, LSPS2_GET_INFO_METHOD_NAME,
+	LSPS2_GET_VERSIONS_METHOD_NAME,
+};
+
 use lightning::impl_writeable_msg;
+use lightning::ln::msgs::DecodeError;
 use lightning::ln::wire;
+use lightning::util::ser::{Readable, Writeable, Writer};
 use serde::de;
 use serde::de::{MapAccess, Visitor};
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
 
 const LSPS_MESSAGE_SERIALIZED_STRUCT_NAME: &str = "LSPSMessage";
 const JSONRPC_FIELD_KEY: &str = "jsonrpc";
@@ -17,6 +32,7 @@ const JSONRPC_PARAMS_FIELD_KEY: &str = "params";
 const JSONRPC_RESULT_FIELD_KEY: &str = "result";
 const JSONRPC_ERROR_FIELD_KEY: &str = "error";
 const JSONRPC_INVALID_MESSAGE_ERROR_CODE: i32 = -32700;
+pub(crate) const JSONRPC_INTERNAL_ERROR_ERROR_CODE: i32 = -32603;
 const JSONRPC_INVALID_MESSAGE_ERROR_MESSAGE: &str = "parse error";
 const LSPS0_LISTPROTOCOLS_METHOD_NAME: &str = "lsps0.listprotocols";
 
@@ -35,14 +51,15 @@ impl wire::Type for RawLSPSMessage {
 	}
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// The identifier of a JSON-RPC request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct RequestId(pub String);
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ResponseError {
 	pub code: i32,
 	pub message: String,
-	pub data: Option<String>,
+	pub data: Option<serde_json::Value>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -84,8 +101,8 @@ impl TryFrom<LSPSMessage> for LSPS0Message {
 
 	fn try_from(message: LSPSMessage) -> Result<Self, Self::Error> {
 		match message {
-			LSPSMessage::Invalid => Err(()),
 			LSPSMessage::LSPS0(message) => Ok(message),
+			_ => Err(()),
 		}
 	}
 }
@@ -100,6 +117,8 @@ impl From<LSPS0Message> for LSPSMessage {
 pub enum LSPSMessage {
 	Invalid,
 	LSPS0(LSPS0Message),
+	LSPS1(LSPS1Message),
+	LSPS2(LSPS2Message),
 }
 
 impl LSPSMessage {
@@ -116,6 +135,12 @@ impl LSPSMessage {
 			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, request)) => {
 				Some((request_id.0.clone(), request.method().to_string()))
 			}
+			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
+				Some((request_id.0.clone(), request.method().to_string()))
+			}
+			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
+				Some((request_id.0.clone(), request.method().to_string()))
+			}
 			_ => None,
 		}
 	}
@@ -154,6 +179,86 @@ impl Serialize for LSPSMessage {
 					}
 				}
 			}
+			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
+				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, request.method())?;
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
+
+				match request {
+					LSPS1Request::GetInfo(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+					LSPS1Request::CreateOrder(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+					LSPS1Request::GetOrder(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+				}
+			}
+			LSPSMessage::LSPS1(LSPS1Message::Response(request_id, response)) => {
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
+
+				match response {
+					LSPS1Response::GetInfo(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS1Response::GetInfoError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+					LSPS1Response::CreateOrder(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS1Response::CreateOrderError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+					LSPS1Response::GetOrder(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS1Response::GetOrderError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+				}
+			}
+			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
+				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, request.method())?;
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
+
+				match request {
+					LSPS2Request::GetVersions(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+					LSPS2Request::GetInfo(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+					LSPS2Request::Buy(params) => {
+						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+					}
+				}
+			}
+			LSPSMessage::LSPS2(LSPS2Message::Response(request_id, response)) => {
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
+
+				match response {
+					LSPS2Response::GetVersions(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS2Response::GetVersionsError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+					LSPS2Response::GetInfo(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS2Response::GetInfoError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+					LSPS2Response::Buy(result) => {
+						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
+					}
+					LSPS2Response::BuyError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
+				}
+			}
 			LSPSMessage::Invalid => {
 				let error = ResponseError {
 					code: JSONRPC_INVALID_MESSAGE_ERROR_CODE,
@@ -187,8 +292,8 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 	{
 		let mut id: Option<String> = None;
 		let mut method: Option<&str> = None;
-		let mut params = None;
-		let mut result = None;
+		let mut params: Option<serde_json::Value> = None;
+		let mut result: Option<serde_json::Value> = None;
 		let mut error: Option<ResponseError> = None;
 
 		while let Some(key) = map.next_key()? {
@@ -224,6 +329,72 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 						LSPS0Request::ListProtocols(ListProtocolsRequest {}),
 					)))
 				}
+				LSPS1_GET_INFO_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS1(LSPS1Message::Request(
+						RequestId(id),
+						LSPS1Request::GetInfo(request),
+					)))
+				}
+				LSPS1_CREATE_ORDER_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS1(LSPS1Message::Request(
+						RequestId(id),
+						LSPS1Request::CreateOrder(request),
+					)))
+				}
+				LSPS1_GET_ORDER_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS1(LSPS1Message::Request(
+						RequestId(id),
+						LSPS1Request::GetOrder(request),
+					)))
+				}
+				LSPS2_GET_VERSIONS_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
+						RequestId(id),
+						LSPS2Request::GetVersions(request),
+					)))
+				}
+				LSPS2_GET_INFO_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
+						RequestId(id),
+						LSPS2Request::GetInfo(request),
+					)))
+				}
+				LSPS2_BUY_METHOD_NAME => {
+					let request = serde_json::from_value(params.unwrap_or(json!({})))
+						.map_err(de::Error::custom)?;
+
+					self.request_id_to_method.insert(id.clone(), method.to_string());
+
+					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
+						RequestId(id),
+						LSPS2Request::Buy(request),
+					)))
+				}
 				_ => Err(de::Error::custom(format!(
 					"Received request with unknown method: {}",
 					method
@@ -248,6 +419,108 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
 						}
 					}
+					LSPS1_GET_INFO_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetInfoError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetInfo(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					LSPS1_CREATE_ORDER_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::CreateOrderError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::CreateOrder(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					LSPS1_GET_ORDER_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetOrderError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetOrder(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					LSPS2_GET_VERSIONS_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::GetVersionsError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::GetVersions(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					LSPS2_GET_INFO_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::GetInfoError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::GetInfo(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					LSPS2_BUY_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::BuyError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::Buy(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
 					_ => Err(de::Error::custom(format!(
 						"Received response for an unknown request method: {}",
 						method
@@ -268,6 +541,286 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 	}
 }
 
+/// Serializes msat amounts as strings, as required by LSPS0.
+pub(crate) mod string_amount {
+	use serde::de::Unexpected;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub(crate) fn serialize<S>(x: &u64, s: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		s.serialize_str(&x.to_string())
+	}
+
+	pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let buf = String::deserialize(deserializer)?;
+
+		buf.parse::<u64>().map_err(|_| {
+			serde::de::Error::invalid_value(Unexpected::Str(&buf), &"invalid u64 amount string")
+		})
+	}
+}
+
+/// Serializes optional msat amounts as strings, as required by LSPS0.
+pub(crate) mod string_amount_option {
+	use serde::de::Unexpected;
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+	pub(crate) fn serialize<S>(x: &Option<u64>, s: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		let v = x.as_ref().map(|v| v.to_string());
+		Option::<String>::serialize(&v, s)
+	}
+
+	pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		if let Some(buf) = Option::<String>::deserialize(deserializer)? {
+			let val = buf.parse::<u64>().map_err(|_| {
+				serde::de::Error::invalid_value(Unexpected::Str(&buf), &"invalid u64 amount string")
+			})?;
+			Ok(Some(val))
+		} else {
+			Ok(None)
+		}
+	}
+}
+
+/// A point in time, formatted as an [RFC 3339](https://www.rfc-editor.org/rfc/rfc3339) UTC
+/// timestamp on the wire as required by LSPS0, e.g. `2023-02-23T08:47:30.511Z`.
+///
+/// Timestamps are kept with millisecond precision and can't predate the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LSPSDateTime {
+	millis_since_epoch: u64,
+}
+
+impl LSPSDateTime {
+	/// Returns the current time.
+	pub fn now() -> Self {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time must be > 1970");
+		Self::from_duration_since_epoch(now)
+	}
+
+	/// Returns the time the given duration after the Unix epoch.
+	pub fn from_duration_since_epoch(duration: Duration) -> Self {
+		let millis_since_epoch = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+		Self { millis_since_epoch }
+	}
+
+	/// Returns the duration since the Unix epoch.
+	pub fn duration_since_epoch(&self) -> Duration {
+		Duration::from_millis(self.millis_since_epoch)
+	}
+
+	/// Returns whether this point in time lies in the past.
+	pub fn is_past(&self) -> bool {
+		*self < Self::now()
+	}
+
+	/// Formats the timestamp as an RFC 3339 string with millisecond precision.
+	pub fn to_rfc3339(&self) -> String {
+		let secs = self.millis_since_epoch / 1000;
+		let (year, month, day) = civil_from_days(secs / 86400);
+		let secs_of_day = secs % 86400;
+		format!(
+			"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+			year,
+			month,
+			day,
+			secs_of_day / 3600,
+			secs_of_day % 3600 / 60,
+			secs_of_day % 60,
+			self.millis_since_epoch % 1000
+		)
+	}
+
+	/// Parses an RFC 3339 timestamp, e.g. `2023-02-23T08:47:30.511Z` or
+	/// `2023-02-23T09:47:30+01:00`.
+	///
+	/// Fractional seconds beyond millisecond precision are truncated.
+	pub fn from_rfc3339(s: &str) -> Option<Self> {
+		fn number(s: &[u8]) -> Option<u64> {
+			if s.is_empty() || !s.iter().all(u8::is_ascii_digit) {
+				return None;
+			}
+			Some(s.iter().fold(0, |acc, d| acc * 10 + u64::from(d - b'0')))
+		}
+
+		let b = s.as_bytes();
+		if b.len() < 20
+			|| b[4] != b'-'
+			|| b[7] != b'-'
+			|| !(b[10] == b'T' || b[10] == b't')
+			|| b[13] != b':'
+			|| b[16] != b':'
+		{
+			return None;
+		}
+		let (year, month, day) = (number(&b[0..4])?, number(&b[5..7])?, number(&b[8..10])?);
+		let (hour, minute, second) =
+			(number(&b[11..13])?, number(&b[14..16])?, number(&b[17..19])?);
+		if year < 1970
+			|| month == 0
+			|| month > 12
+			|| day == 0
+			|| day > days_in_month(year, month)
+			|| hour > 23
+			|| minute > 59
+			|| second > 59
+		{
+			return None;
+		}
+
+		let mut rest = &b[19..];
+		let mut millis = 0;
+		if rest[0] == b'.' {
+			let digits = rest[1..].iter().take_while(|c| c.is_ascii_digit()).count();
+			if digits == 0 {
+				return None;
+			}
+			let mut fraction = rest[1..1 + digits.min(3)].to_vec();
+			fraction.resize(3, b'0');
+			millis = number(&fraction)?;
+			rest = &rest[1 + digits..];
+		}
+
+		let offset_secs: i64 = match rest {
+			[b'Z'] | [b'z'] => 0,
+			[sign @ b'+', h1, h2, b':', m1, m2] | [sign @ b'-', h1, h2, b':', m1, m2] => {
+				let (hours, minutes) = (number(&[*h1, *h2])?, number(&[*m1, *m2])?);
+				if hours > 23 || minutes > 59 {
+					return None;
+				}
+				let offset = (hours * 3600 + minutes * 60) as i64;
+				if *sign == b'+' {
+					offset
+				} else {
+					-offset
+				}
+			}
+			_ => return None,
+		};
+
+		let local_secs =
+			days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+		let secs = u64::try_from(local_secs as i64 - offset_secs).ok()?;
+		Some(Self { millis_since_epoch: secs * 1000 + millis })
+	}
+}
+
+impl Add<Duration> for LSPSDateTime {
+	type Output = Self;
+
+	fn add(self, duration: Duration) -> Self {
+		let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+		Self { millis_since_epoch: self.millis_since_epoch.saturating_add(millis) }
+	}
+}
+
+impl Sub<Duration> for LSPSDateTime {
+	type Output = Self;
+
+	fn sub(self, duration: Duration) -> Self {
+		let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+		Self { millis_since_epoch: self.millis_since_epoch.saturating_sub(millis) }
+	}
+}
+
+impl fmt::Display for LSPSDateTime {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.to_rfc3339())
+	}
+}
+
+impl FromStr for LSPSDateTime {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::from_rfc3339(s).ok_or(())
+	}
+}
+
+impl Serialize for LSPSDateTime {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		serializer.serialize_str(&self.to_rfc3339())
+	}
+}
+
+impl<'de> Deserialize<'de> for LSPSDateTime {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let buf = String::deserialize(deserializer)?;
+		Self::from_rfc3339(&buf).ok_or_else(|| {
+			de::Error::invalid_value(de::Unexpected::Str(&buf), &"an RFC 3339 timestamp")
+		})
+	}
+}
+
+impl Writeable for LSPSDateTime {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.millis_since_epoch.write(writer)
+	}
+}
+
+impl Readable for LSPSDateTime {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self { millis_since_epoch: Readable::read(reader)? })
+	}
+}
+
+fn is_leap_year(year: u64) -> bool {
+	year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+	match month {
+		2 if is_leap_year(year) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+/// Returns the number of days since the Unix epoch of the given date, which must not predate it.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+	let days_before_year =
+		(1970..year).map(|y| if is_leap_year(y) { 366 } else { 365 }).sum::<u64>();
+	let days_before_month = (1..month).map(|m| days_in_month(year, m)).sum::<u64>();
+	days_before_year + days_before_month + day - 1
+}
+
+/// Returns the date of the given number of days since the Unix epoch.
+fn civil_from_days(mut days: u64) -> (u64, u64, u64) {
+	let mut year = 1970;
+	loop {
+		let days_in_year = if is_leap_year(year) { 366 } else { 365 };
+		if days < days_in_year {
+			break;
+		}
+		days -= days_in_year;
+		year += 1;
+	}
+	let mut month = 1;
+	while days >= days_in_month(year, month) {
+		days -= days_in_month(year, month);
+		month += 1;
+	}
+	(year, month, days + 1)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -391,4 +944,38 @@ mod tests {
 			r#"{"jsonrpc":"2.0","id":"request:id:xyz123","result":{"protocols":[1,2,3]}}"#
 		);
 	}
+
+	#[test]
+	fn parses_and_formats_datetimes() {
+		let datetime = LSPSDateTime::from_rfc3339("2023-02-23T08:47:30.511Z").unwrap();
+		assert_eq!(datetime.duration_since_epoch(), Duration::from_millis(1_677_142_050_511));
+		assert_eq!(datetime.to_rfc3339(), "2023-02-23T08:47:30.511Z");
+
+		let with_offset = LSPSDateTime::from_rfc3339("2023-02-23T09:47:30.511123+01:00").unwrap();
+		assert_eq!(with_offset, datetime);
+
+		let leap_day = LSPSDateTime::from_rfc3339("2024-02-29T23:59:59Z").unwrap();
+		assert_eq!(leap_day.to_rfc3339(), "2024-02-29T23:59:59.000Z");
+		assert_eq!(LSPSDateTime::from_str(&leap_day.to_string()), Ok(leap_day));
+
+		for invalid in &[
+			"2023-02-29T08:47:30Z",
+			"2023-02-23 08:47:30Z",
+			"2023-02-23T24:00:00Z",
+			"2023-02-23T08:47:30.Z",
+			"2023-02-23T08:47:30",
+			"1969-12-31T23:59:59Z",
+		] {
+			assert!(LSPSDateTime::from_rfc3339(invalid).is_none(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn serializes_datetimes_as_strings() {
+		let datetime = LSPSDateTime::from_rfc3339("2023-02-23T08:47:30.511Z").unwrap();
+		let json = serde_json::to_string(&datetime).unwrap();
+		assert_eq!(json, r#""2023-02-23T08:47:30.511Z""#);
+		assert_eq!(serde_json::from_str::<LSPSDateTime>(&json).unwrap(), datetime);
+		assert!(serde_json::from_str::<LSPSDateTime>(r#""yesterday""#).is_err());
+	}
 }